@@ -4,14 +4,33 @@
 //! See the top-level README.md for licence details.
 //!
 //! This crate is a build script which generates version information and
-//! places it in `src/version/data.rs`.
+//! places it in `src/version/data.rs`. It also generates the resource table
+//! and the linker script's MEMORY block from `resources.toml`.
 
+#[macro_use]
+extern crate serde_derive;
+extern crate resource_table;
+extern crate toml;
+
+use std::path::Path;
 use std::process::Command;
 
+#[path = "build/resource_config.rs"]
+mod resource_config;
+
 fn main() {
     // Put the linker script somewhere the linker can find it
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=build/resource_config.rs");
     println!("cargo:rerun-if-changed=am5728_ipu.ld");
+    println!("cargo:rerun-if-changed=resources.toml");
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    if let Err(e) = resource_config::generate(Path::new("resources.toml"), Path::new(&out_dir)) {
+        panic!("resources.toml: {}", e);
+    }
+    // So the linker script can `INCLUDE memory.x`
+    println!("cargo:rustc-link-search={}", out_dir);
+
     let git_desc = Command::new("git")
         .args(&["describe", "--all", "--dirty", "--tags", "--long"])
         .output()
@@ -0,0 +1,534 @@
+//! # Resource Table Configuration
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Reads `resources.toml` and generates two files from it: the Rust source
+//! for our resource table, and the MEMORY block for the linker script. This
+//! is shared between the firmware's `build.rs` and `host-tests/build.rs`, so
+//! that both see the same table.
+//!
+//! Anything wrong with the configuration is reported as an error here, so
+//! that it stops the build rather than confusing the kernel.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use resource_table::validate::vring_size;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use toml;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The name of the generated Rust source, in the output directory.
+pub const TABLE_FILE: &str = "resource_table.rs";
+
+/// The name of the generated MEMORY block, in the output directory.
+pub const MEMORY_FILE: &str = "memory.x";
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+/// The contents of `resources.toml`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    vdev: Option<VdevConfig>,
+    #[serde(default)]
+    carveout: Vec<MemoryConfig>,
+    trace: Option<TraceConfig>,
+    #[serde(default)]
+    devmem: Vec<MemoryConfig>,
+    #[serde(default)]
+    region: Vec<RegionConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VdevConfig {
+    field: String,
+    id: String,
+    #[serde(default)]
+    dfeatures: Option<String>,
+    #[serde(default)]
+    vring: Vec<VringConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VringConfig {
+    field: String,
+    da: String,
+    align: String,
+    num: String,
+}
+
+/// A carveout or a devmem.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemoryConfig {
+    field: String,
+    name: String,
+    da: String,
+    /// Carveouts are allocated by the kernel, so don't have one
+    pa: Option<String>,
+    len: String,
+    /// The linker script region for a carveout
+    region: Option<String>,
+    attributes: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TraceConfig {
+    field: String,
+    name: String,
+    carveout: String,
+    len: String,
+}
+
+/// A linker script region which isn't in the resource table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegionConfig {
+    name: String,
+    attributes: String,
+    origin: String,
+    len: String,
+}
+
+/// A range of device addresses, for checking overlaps.
+struct Span<'a> {
+    what: &'a str,
+    start: u64,
+    end: u64,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// Names in the resource table are NUL terminated, in 32 bytes.
+const MAX_NAME_LEN: usize = 31;
+
+/// Device addresses are 32-bit.
+const DEVICE_ADDRESS_LIMIT: u64 = 1 << 32;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Read the given config file and write `TABLE_FILE` and `MEMORY_FILE` to
+/// `out_dir`.
+pub fn generate(config_path: &Path, out_dir: &Path) -> Result<(), String> {
+    let text = fs::read_to_string(config_path).map_err(|e| e.to_string())?;
+    let config: Config = toml::from_str(&text).map_err(|e| e.to_string())?;
+    check(&config)?;
+    let table = table_source(&config)?;
+    let memory = memory_block(&config)?;
+    fs::write(out_dir.join(TABLE_FILE), table).map_err(|e| e.to_string())?;
+    fs::write(out_dir.join(MEMORY_FILE), memory).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Check everything we can about the configuration before generating
+/// anything from it.
+fn check(config: &Config) -> Result<(), String> {
+    let mut fields = HashSet::new();
+    let mut field = |name: &str| {
+        if fields.insert(name.to_owned()) {
+            Ok(())
+        } else {
+            Err(format!("field `{}` used twice", name))
+        }
+    };
+
+    let mut spans = Vec::new();
+    for mem in config.carveout.iter().chain(config.devmem.iter()) {
+        field(&mem.field)?;
+        check_name(&mem.name)?;
+        let start = number(&mem.da)?;
+        let end = start + number(&mem.len)?;
+        if start == end {
+            return Err(format!("{} has zero length", mem.name));
+        }
+        if end > DEVICE_ADDRESS_LIMIT {
+            return Err(format!(
+                "{} runs off the end of the address space",
+                mem.name
+            ));
+        }
+        spans.push(Span {
+            what: &mem.name,
+            start,
+            end,
+        });
+    }
+    for carveout in &config.carveout {
+        if carveout.pa.is_some() {
+            return Err(format!("carveout {} can't have a pa", carveout.name));
+        }
+    }
+    for devmem in &config.devmem {
+        if devmem.pa.is_none() {
+            return Err(format!("devmem {} needs a pa", devmem.name));
+        }
+        if devmem.region.is_some() {
+            return Err(format!("devmem {} can't be a linker region", devmem.name));
+        }
+    }
+    check_overlaps(&spans)?;
+
+    if let Some(ref vdev) = config.vdev {
+        field(&vdev.field)?;
+        let mut vring_spans = Vec::new();
+        for vring in &vdev.vring {
+            field(&vring.field)?;
+            let align = number(&vring.align)?;
+            if !align.is_power_of_two() {
+                return Err(format!("{} align must be a power of two", vring.field));
+            }
+            let start = number(&vring.da)?;
+            let end = start + vring_size(number(&vring.num)? as usize, align as usize) as u64;
+            if !spans
+                .iter()
+                .any(|s| start >= s.start && end <= s.end && is_devmem(config, s.what))
+            {
+                return Err(format!("{} isn't inside a devmem", vring.field));
+            }
+            vring_spans.push(Span {
+                what: &vring.field,
+                start,
+                end,
+            });
+        }
+        check_overlaps(&vring_spans)?;
+    }
+
+    if let Some(ref trace) = config.trace {
+        field(&trace.field)?;
+        check_name(&trace.name)?;
+        let carveout = config
+            .carveout
+            .iter()
+            .find(|c| c.name == trace.carveout)
+            .ok_or_else(|| format!("trace carveout {} not found", trace.carveout))?;
+        if number(&trace.len)? > number(&carveout.len)? {
+            return Err(format!("trace doesn't fit in {}", carveout.name));
+        }
+    }
+
+    let mut regions = HashSet::new();
+    for name in config
+        .carveout
+        .iter()
+        .filter_map(|c| c.region.as_ref())
+        .chain(config.region.iter().map(|r| &r.name))
+    {
+        if !regions.insert(name) {
+            return Err(format!("linker region {} used twice", name));
+        }
+    }
+    Ok(())
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    if name.len() > MAX_NAME_LEN || !name.is_ascii() {
+        Err(format!(
+            "name {:?} must be ASCII and at most {} bytes",
+            name, MAX_NAME_LEN
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_overlaps(spans: &[Span]) -> Result<(), String> {
+    for (idx, a) in spans.iter().enumerate() {
+        for b in &spans[idx + 1..] {
+            if a.start < b.end && b.start < a.end {
+                return Err(format!("{} overlaps {}", a.what, b.what));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_devmem(config: &Config, name: &str) -> bool {
+    config.devmem.iter().any(|d| d.name == name)
+}
+
+/// Parse `0x1234`, `1234`, `64K` or `5M`.
+fn number(value: &str) -> Result<u64, String> {
+    let value = value.replace('_', "");
+    let (digits, scale) = match value.chars().last() {
+        Some('K') => (value.trim_end_matches('K'), 1024),
+        Some('M') => (value.trim_end_matches('M'), 1024 * 1024),
+        _ => (value.as_str(), 1),
+    };
+    let parsed = if digits.starts_with("0x") {
+        u64::from_str_radix(digits.trim_start_matches("0x"), 16)
+    } else {
+        digits.parse()
+    };
+    parsed
+        .map(|n| n * scale)
+        .map_err(|_| format!("{:?} isn't a number", value))
+}
+
+/// A number, or the name of a constant in `memory_map`.
+fn address(value: &str) -> Result<String, String> {
+    if value.starts_with(|c: char| c.is_ascii_digit()) {
+        Ok(format!("0x{:08X}", number(value)?))
+    } else if value
+        .chars()
+        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+    {
+        Ok(format!("memory_map::{} as u32", value))
+    } else {
+        Err(format!(
+            "{:?} isn't a number or a memory_map constant",
+            value
+        ))
+    }
+}
+
+/// A `String32` literal.
+fn name_literal(name: &str) -> String {
+    let mut literal = String::from("rt::String32 { buffer: *b\"");
+    literal.push_str(name);
+    for _ in name.len()..32 {
+        literal.push_str("\\0");
+    }
+    literal.push_str("\" }");
+    literal
+}
+
+fn table_source(config: &Config) -> Result<String, String> {
+    let mut s = String::new();
+    let mut entries = Vec::new();
+
+    if let Some(ref vdev) = config.vdev {
+        let mut entry = format!(
+            "{}: rt::Vdev = rt::Vdev {{
+            rtype: rt::ResourceType::VDEV,
+            id: {},
+            notifyid: 0,
+            dfeatures: {},
+            gfeatures: 0,
+            config_len: 0,
+            status: 0,
+            num_of_vrings: {},
+            reserved: [0, 0],
+        }}",
+            vdev.field,
+            number(&vdev.id)?,
+            number(vdev.dfeatures.as_ref().map_or("0", |d| d.as_str()))?,
+            vdev.vring.len()
+        );
+        for (idx, vring) in vdev.vring.iter().enumerate() {
+            write!(
+                entry,
+                "
+            => {}: rt::VdevVring = rt::VdevVring {{
+                da: 0x{:08X},
+                align: {},
+                num: {},
+                notifyid: {},
+                reserved: 0,
+            }}",
+                vring.field,
+                number(&vring.da)?,
+                number(&vring.align)?,
+                number(&vring.num)?,
+                idx + 1
+            )
+            .unwrap();
+        }
+        entries.push(entry);
+    }
+
+    for carveout in &config.carveout {
+        entries.push(format!(
+            "{}: rt::Carveout = rt::Carveout {{
+            rtype: rt::ResourceType::CARVEOUT,
+            da: 0x{:08X},
+            pa: 0, // Filled in by the kernel
+            len: 0x{:08X},
+            flags: 0,
+            reserved: 0,
+            name: {},
+        }}",
+            carveout.field,
+            number(&carveout.da)?,
+            number(&carveout.len)?,
+            name_literal(&carveout.name)
+        ));
+    }
+
+    let mut trace_len = None;
+    if let Some(ref trace) = config.trace {
+        let carveout = config
+            .carveout
+            .iter()
+            .find(|c| c.name == trace.carveout)
+            .unwrap();
+        entries.push(format!(
+            "{}: rt::Trace = rt::Trace {{
+            rtype: rt::ResourceType::TRACE,
+            da: 0x{:08X},
+            len: 0x{:08X},
+            reserved: 0,
+            name: {},
+        }}",
+            trace.field,
+            number(&carveout.da)?,
+            number(&trace.len)?,
+            name_literal(&trace.name)
+        ));
+        trace_len = Some(number(&trace.len)?);
+    }
+
+    for devmem in &config.devmem {
+        entries.push(format!(
+            "{}: rt::Devmem = rt::Devmem {{
+            rtype: rt::ResourceType::DEVMEM,
+            da: 0x{:08X},
+            pa: {},
+            len: 0x{:08X},
+            flags: 0,
+            reserved: 0,
+            name: {},
+        }}",
+            devmem.field,
+            number(&devmem.da)?,
+            address(devmem.pa.as_ref().unwrap())?,
+            number(&devmem.len)?,
+            name_literal(&devmem.name)
+        ));
+    }
+
+    writeln!(
+        s,
+        "// Generated by build.rs from resources.toml. Do not edit."
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "
+resource_table! {{
+    /// This resource table structure is processed by the kernel.
+    pub struct ResourceTable;
+
+    #[link_section = \".resource_table\"]
+    #[no_mangle]
+    pub static RESOURCE_TABLE = {{"
+    )
+    .unwrap();
+    for entry in entries {
+        writeln!(s, "        {};", entry).unwrap();
+    }
+    writeln!(s, "    }};\n}}").unwrap();
+
+    if let Some(len) = trace_len {
+        writeln!(
+            s,
+            "
+/// The size of the trace buffer in the resource table.
+pub const TRACE_BUFFER_LEN: usize = {};",
+            len
+        )
+        .unwrap();
+    }
+
+    Ok(s)
+}
+
+fn memory_block(config: &Config) -> Result<String, String> {
+    let mut s = String::new();
+    writeln!(
+        s,
+        "/* Generated by build.rs from resources.toml. Do not edit. */"
+    )
+    .unwrap();
+    writeln!(s, "MEMORY\n{{").unwrap();
+    for carveout in &config.carveout {
+        if let Some(ref region) = carveout.region {
+            writeln!(
+                s,
+                "  {} ({}) : ORIGIN = 0x{:08X}, LENGTH = 0x{:08X}",
+                region,
+                carveout.attributes.as_ref().map_or("RW", |a| a.as_str()),
+                number(&carveout.da)?,
+                number(&carveout.len)?
+            )
+            .unwrap();
+        }
+    }
+    for region in &config.region {
+        writeln!(
+            s,
+            "  {} ({}) : ORIGIN = 0x{:08X}, LENGTH = 0x{:08X}",
+            region.name,
+            region.attributes,
+            number(&region.origin)?,
+            number(&region.len)?
+        )
+        .unwrap();
+    }
+    writeln!(s, "}}").unwrap();
+    Ok(s)
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
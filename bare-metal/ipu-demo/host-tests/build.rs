@@ -0,0 +1,27 @@
+//! # host-tests build script
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Generates the resource table and MEMORY block from the firmware's
+//! `resources.toml`, exactly as the firmware's own build script does.
+
+#[macro_use]
+extern crate serde_derive;
+extern crate resource_table;
+extern crate toml;
+
+use std::path::Path;
+
+#[path = "../build/resource_config.rs"]
+mod resource_config;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=../build/resource_config.rs");
+    println!("cargo:rerun-if-changed=../resources.toml");
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    if let Err(e) = resource_config::generate(Path::new("../resources.toml"), Path::new(&out_dir)) {
+        panic!("resources.toml: {}", e);
+    }
+}
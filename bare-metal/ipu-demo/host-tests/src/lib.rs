@@ -0,0 +1,518 @@
+//! # IPU Demo Host Tests
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! The firmware itself can only be built for the Cortex-M4, so it can't run
+//! any unit tests. This crate pulls in the parts of the firmware which don't
+//! touch the hardware and checks them on the host. Run it with `cargo test
+//! --target x86_64-unknown-linux-gnu`.
+//!
+//! That means the resource table, the rpmsg transmit backlog, the control
+//! message state machine and the RPC handlers, which run here against
+//! stand-ins for the firmware's `trace` and `version` modules. The state
+//! machine's tests live in `control.rs` itself. As well as the generic
+//! checks in `resource_table::validate`, we check the table agrees with the
+//! linker script, because the kernel will load our ELF sections into the
+//! carveouts the table describes. Our build script generates the table and
+//! the linker script's MEMORY block from `resources.toml`, just like the
+//! firmware's does.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+// The firmware modules refer to `::core`
+extern crate core;
+extern crate crash_report;
+extern crate demo_messages;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate resource_table;
+extern crate rpmsg_fragment;
+extern crate rpmsg_rpc;
+extern crate vring;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// Before the other modules, so they can use its macros
+#[macro_use]
+#[allow(dead_code)]
+mod trace;
+#[path = "../../src/control.rs"]
+#[allow(dead_code)]
+mod control;
+#[path = "../../src/memory_map.rs"]
+#[allow(dead_code)]
+mod memory_map;
+#[path = "../../src/resources.rs"]
+#[allow(dead_code)]
+mod resources;
+#[path = "../../src/rpc.rs"]
+#[allow(dead_code)]
+mod rpc;
+#[path = "../../src/rpmsg.rs"]
+mod rpmsg;
+#[allow(dead_code)]
+mod version;
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod test {
+    use demo_messages;
+    use log::LevelFilter;
+    use memory_map;
+    use resource_table::{validate, AddressMapper, RegionMap};
+    use resources::{self, RESOURCE_TABLE};
+    use rpc;
+    use rpmsg::{self, BacklogPolicy, SendMessage, Transport};
+    use rpmsg_rpc::{CallError, Client, Loopback, Status};
+    use std::time::Duration;
+    use trace;
+    use version;
+    use vring;
+
+    const LINKER_SCRIPT: &str = include_str!("../../am5728_ipu.ld");
+
+    const MEMORY_BLOCK: &str = include_str!(concat!(env!("OUT_DIR"), "/memory.x"));
+
+    /// Find `ORIGIN` and `LENGTH` for the named region in the linker
+    /// script's MEMORY block.
+    fn memory_region(name: &str) -> (u32, u32) {
+        let line = MEMORY_BLOCK
+            .lines()
+            .map(|l| l.trim())
+            .find(|l| l.split_whitespace().next() == Some(name))
+            .unwrap_or_else(|| panic!("No {} in linker script", name));
+        let mut origin = None;
+        let mut length = None;
+        for field in line.split(&[':', ','][..]) {
+            let mut parts = field.splitn(2, '=').map(|p| p.trim());
+            match (parts.next(), parts.next()) {
+                (Some("ORIGIN"), Some(v)) => origin = Some(parse_number(v)),
+                (Some("LENGTH"), Some(v)) => length = Some(parse_number(v)),
+                _ => {}
+            }
+        }
+        (origin.unwrap(), length.unwrap())
+    }
+
+    /// The host's side of a vring we transmit on. It hands us buffers and
+    /// reads back what we put in them.
+    struct HostRing {
+        base: usize,
+        available: usize,
+        used: usize,
+        given: u16,
+        taken: u16,
+    }
+
+    const RING_ENTRIES: usize = 32;
+    const RING_ALIGN: usize = 64;
+
+    fn identity(address: u64) -> u64 {
+        address
+    }
+
+    impl HostRing {
+        /// Make an empty ring, and the firmware's view of it.
+        fn new() -> (HostRing, vring::GuestVring) {
+            let size = validate::vring_size(RING_ENTRIES, RING_ALIGN) + RING_ALIGN;
+            let memory: &'static mut [u64] = Box::leak(vec![0u64; size / 8].into_boxed_slice());
+            let base = memory.as_ptr() as usize;
+            let guest =
+                unsafe { vring::GuestVring::new(base, RING_ENTRIES, RING_ALIGN, &identity) };
+            let available = base + 16 * RING_ENTRIES;
+            let used = (available + 6 + 2 * RING_ENTRIES + RING_ALIGN - 1) & !(RING_ALIGN - 1);
+            let host = HostRing {
+                base,
+                available,
+                used,
+                given: 0,
+                taken: 0,
+            };
+            (host, guest)
+        }
+
+        /// Make `count` buffers of `len` bytes available to the firmware.
+        fn give(&mut self, count: usize, len: usize) {
+            for _ in 0..count {
+                let idx = self.given as usize % RING_ENTRIES;
+                let buffer: &'static mut [u8] = Box::leak(vec![0u8; len].into_boxed_slice());
+                unsafe {
+                    let descriptor = (self.base + 16 * idx) as *mut u8;
+                    *(descriptor as *mut u64) = buffer.as_ptr() as u64;
+                    *(descriptor.offset(8) as *mut u32) = len as u32;
+                    *(descriptor.offset(12) as *mut u32) = 0;
+                    *((self.available + 4 + 2 * idx) as *mut u16) = idx as u16;
+                    self.given = self.given.wrapping_add(1);
+                    *((self.available + 2) as *mut u16) = self.given;
+                }
+            }
+        }
+
+        /// The payloads of the messages the firmware has sent since we last
+        /// looked.
+        fn take(&mut self) -> Vec<Vec<u8>> {
+            let mut messages = Vec::new();
+            let used_idx = unsafe { *((self.used + 2) as *const u16) };
+            while self.taken != used_idx {
+                let slot = self.taken as usize % RING_ENTRIES;
+                unsafe {
+                    let entry = (self.used + 4 + 8 * slot) as *const u32;
+                    let descriptor = self.base + 16 * (*entry as usize);
+                    let addr = *(descriptor as *const u64) as usize;
+                    let len = *entry.offset(1) as usize;
+                    let bytes = ::std::slice::from_raw_parts(addr as *const u8, len);
+                    messages.push(bytes[::std::mem::size_of::<rpmsg::Header>()..].to_vec());
+                }
+                self.taken = self.taken.wrapping_add(1);
+            }
+            messages
+        }
+    }
+
+    /// A transport whose to-host ring starts off with no buffers.
+    fn transport(policy: BacklogPolicy) -> (HostRing, Transport) {
+        let (host, tx) = HostRing::new();
+        let (_, rx) = HostRing::new();
+        let backlog = Box::leak(Box::new(rpmsg::Backlog::new()));
+        let mut transport = Transport::new(tx, rx, backlog);
+        transport.set_backlog_policy(policy);
+        (host, transport)
+    }
+
+    /// Fill the backlog, then send one more.
+    fn overfill(transport: &mut Transport) -> Result<(), rpmsg::Error> {
+        for i in 0..rpmsg::BACKLOG_LEN {
+            transport.send_bytes(1, 2, &[i as u8]).unwrap();
+        }
+        assert_eq!(transport.backlog_len(), rpmsg::BACKLOG_LEN);
+        transport.send_bytes(1, 2, &[rpmsg::BACKLOG_LEN as u8])
+    }
+
+    /// Parse `0x1234`, `64K` or `5M`.
+    fn parse_number(value: &str) -> u32 {
+        if value.starts_with("0x") {
+            return u32::from_str_radix(value.trim_start_matches("0x"), 16).unwrap();
+        }
+        let (digits, scale) = match value.chars().last() {
+            Some('K') => (value.trim_end_matches('K'), 1024),
+            Some('M') => (value.trim_end_matches('M'), 1024 * 1024),
+            _ => (value, 1),
+        };
+        digits.parse::<u32>().unwrap() * scale
+    }
+
+    #[test]
+    fn table_is_valid() {
+        let count = validate::validate(&RESOURCE_TABLE, |v| println!("{:?}", v));
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn address_map() {
+        let map = RegionMap::from_table(&RESOURCE_TABLE).unwrap();
+        let t = &RESOURCE_TABLE;
+        // The mailboxes, which the firmware looks up on every access
+        assert_eq!(
+            map.pa_to_da(memory_map::L4_PERIPHERAL_L4CFG + 0xF_4000),
+            Some(t.devmem5.da as usize + 0xF_4000)
+        );
+        // GPTimer 7, the watchdog
+        assert!(map
+            .pa_to_da(memory_map::L4_PERIPHERAL_L4PER3 + 0x2_4000)
+            .is_some());
+        assert_eq!(
+            map.da_to_pa(t.devmem0.da as usize),
+            Some(t.devmem0.pa as usize)
+        );
+        // The kernel allocates this one, so it has no translation yet
+        assert_eq!(map.da_to_pa(t.ipcdata_cout.da as usize), None);
+        assert!(map
+            .unallocated()
+            .iter()
+            .any(|m| m.da == t.ipcdata_cout.da as usize && m.len == t.ipcdata_cout.len as usize));
+    }
+
+    #[test]
+    fn vrings_fit() {
+        let vring0 = &RESOURCE_TABLE.rpmsg_vring0;
+        let size = validate::vring_size(vring0.num as usize, vring0.align as usize);
+        assert!(vring0.da + size as u32 <= RESOURCE_TABLE.rpmsg_vring1.da);
+    }
+
+    #[test]
+    fn carveouts_match_linker_script() {
+        let t = &RESOURCE_TABLE;
+        assert_eq!(memory_region("FLASH"), (t.text_cout.da, t.text_cout.len));
+        assert_eq!(memory_region("RAM"), (t.data_cout.da, t.data_cout.len));
+        assert_eq!(
+            memory_region("IPC_DATA"),
+            (t.ipcdata_cout.da, t.ipcdata_cout.len)
+        );
+    }
+
+    #[test]
+    fn memory_block_included() {
+        assert!(LINKER_SCRIPT.contains("INCLUDE memory.x"));
+        assert!(!LINKER_SCRIPT.contains("MEMORY\n"));
+    }
+
+    #[test]
+    fn trace_at_start_of_ipc_data() {
+        let (origin, _) = memory_region("IPC_DATA");
+        assert_eq!(RESOURCE_TABLE.trace.da, origin);
+        assert_eq!(
+            RESOURCE_TABLE.trace.len as usize,
+            resources::TRACE_BUFFER_LEN
+        );
+        // The trace buffer is only at ORIGIN(IPC_DATA) if it's the first
+        // thing in the output section.
+        let first = LINKER_SCRIPT
+            .lines()
+            .skip_while(|l| !l.trim().starts_with(".ipc_data"))
+            .nth(1)
+            .unwrap();
+        assert!(first.contains("*(.tracebuffer"));
+    }
+
+    #[test]
+    fn crash_report_after_trace_buffer() {
+        let t = &RESOURCE_TABLE;
+        assert!(t.trace.len as usize + crash_report::ENCODED_LEN <= t.ipcdata_cout.len as usize);
+        let second = LINKER_SCRIPT
+            .lines()
+            .skip_while(|l| !l.trim().starts_with(".ipc_data"))
+            .nth(2)
+            .unwrap();
+        assert!(second.contains("*(.crash_report"));
+    }
+
+    #[test]
+    fn backlog_drop_oldest() {
+        let (mut host, mut transport) = transport(BacklogPolicy::DropOldest);
+        overfill(&mut transport).unwrap();
+        assert_eq!(transport.backlog_stats().dropped_oldest, 1);
+        host.give(RING_ENTRIES, rpmsg::BUFFER_SIZE);
+        assert_eq!(transport.flush(), rpmsg::BACKLOG_LEN);
+        let sent = host.take();
+        assert_eq!(sent.len(), rpmsg::BACKLOG_LEN);
+        assert_eq!(sent[0], [1]);
+        assert_eq!(sent[rpmsg::BACKLOG_LEN - 1], [rpmsg::BACKLOG_LEN as u8]);
+    }
+
+    #[test]
+    fn backlog_drop_newest() {
+        let (mut host, mut transport) = transport(BacklogPolicy::DropNewest);
+        match overfill(&mut transport) {
+            Err(rpmsg::Error::Dropped) => {}
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(transport.backlog_stats().dropped_newest, 1);
+        host.give(RING_ENTRIES, rpmsg::BUFFER_SIZE);
+        // Sending flushes the backlog first, to keep messages in order
+        transport.send_bytes(1, 2, &[0xFF]).unwrap();
+        let sent = host.take();
+        assert_eq!(sent.len(), rpmsg::BACKLOG_LEN + 1);
+        assert_eq!(sent[0], [0]);
+        assert_eq!(sent[rpmsg::BACKLOG_LEN], [0xFF]);
+        assert_eq!(transport.backlog_len(), 0);
+    }
+
+    #[test]
+    fn backlog_block_gives_up() {
+        let (_host, mut transport) = transport(BacklogPolicy::Block);
+        match overfill(&mut transport) {
+            Err(rpmsg::Error::Dropped) => {}
+            r => panic!("{:?}", r),
+        }
+        let stats = transport.backlog_stats();
+        assert_eq!(stats.blocked, 1);
+        assert_eq!(stats.block_timeouts, 1);
+        assert_eq!(transport.backlog_len(), rpmsg::BACKLOG_LEN);
+    }
+
+    #[test]
+    fn backlog_skips_failed_message() {
+        let (mut host, mut transport) = transport(BacklogPolicy::DropOldest);
+        transport.send_bytes(1, 2, &[0xAA; 64]).unwrap();
+        transport.send_bytes(1, 2, &[0xBB]).unwrap();
+        // Too small for the first message, which can never be sent
+        host.give(2, 32);
+        assert_eq!(transport.flush(), 1);
+        assert_eq!(transport.backlog_stats().failed, 1);
+        assert_eq!(host.take(), vec![vec![0xBB]]);
+        assert_eq!(transport.backlog_len(), 0);
+    }
+
+    fn rpc_client() -> Client<Loopback<()>> {
+        Client::new(Loopback::new(rpc::dispatcher(), ()))
+    }
+
+    fn rpc_call(client: &mut Client<Loopback<()>>, method_id: u16, request: &[u8]) -> Vec<u8> {
+        client
+            .call(method_id, request, Duration::from_millis(100))
+            .unwrap()
+    }
+
+    fn rpc_status(client: &mut Client<Loopback<()>>, method_id: u16, request: &[u8]) -> Status {
+        match client.call(method_id, request, Duration::from_millis(100)) {
+            Err(CallError::Status(status)) => status,
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn rpc_echo() {
+        let mut client = rpc_client();
+        assert_eq!(
+            rpc_call(&mut client, demo_messages::METHOD_ECHO, b"hello"),
+            b"hello"
+        );
+        assert_eq!(rpc_call(&mut client, demo_messages::METHOD_ECHO, &[]), b"");
+        let largest = [0x55; rpmsg_rpc::MAX_MESSAGE_LEN - rpmsg_rpc::HEADER_LEN];
+        assert_eq!(
+            rpc_call(&mut client, demo_messages::METHOD_ECHO, &largest),
+            &largest[..]
+        );
+    }
+
+    #[test]
+    fn rpc_add() {
+        let mut client = rpc_client();
+        let request = [1, 0, 0, 0, 2, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF];
+        // 1 + 2 - 1, wrapping
+        assert_eq!(
+            rpc_call(&mut client, demo_messages::METHOD_ADD, &request),
+            [2, 0, 0, 0]
+        );
+        assert_eq!(
+            rpc_call(&mut client, demo_messages::METHOD_ADD, &[]),
+            [0, 0, 0, 0]
+        );
+        assert_eq!(
+            rpc_status(&mut client, demo_messages::METHOD_ADD, &[1, 2, 3]),
+            Status::BadRequest
+        );
+    }
+
+    #[test]
+    fn rpc_version() {
+        let mut client = rpc_client();
+        assert_eq!(
+            rpc_call(&mut client, demo_messages::METHOD_VERSION, &[]),
+            version::VERSION.as_bytes()
+        );
+    }
+
+    #[test]
+    fn rpc_set_log_level() {
+        let mut client = rpc_client();
+        assert_eq!(
+            rpc_call(&mut client, demo_messages::METHOD_SET_LOG_LEVEL, &[2]),
+            b""
+        );
+        assert_eq!(trace::default_level(), Some(LevelFilter::Warn));
+        let mut request = vec![5];
+        request.extend_from_slice(b"ipu_demo::rpc");
+        rpc_call(&mut client, demo_messages::METHOD_SET_LOG_LEVEL, &request);
+        assert_eq!(
+            trace::module_level("ipu_demo::rpc"),
+            Some(LevelFilter::Trace)
+        );
+        for bad in &[&[][..], &[6][..], &[1, 0xFF][..]] {
+            assert_eq!(
+                rpc_status(&mut client, demo_messages::METHOD_SET_LOG_LEVEL, bad),
+                Status::BadRequest
+            );
+        }
+        let mut request = vec![1];
+        request.extend_from_slice(&[b'x'; trace::MAX_MODULE_LEN + 1]);
+        assert_eq!(
+            rpc_status(&mut client, demo_messages::METHOD_SET_LOG_LEVEL, &request),
+            Status::Failed
+        );
+        assert_eq!(trace::default_level(), Some(LevelFilter::Warn));
+    }
+
+    #[test]
+    fn rpc_unknown_method() {
+        let mut client = rpc_client();
+        assert_eq!(rpc_status(&mut client, 0xFFFF, &[]), Status::UnknownMethod);
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,95 @@
+//! # Trace Stand-in
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! The firmware's `trace` module logs to the trace buffer with interrupts
+//! disabled, which we can't do on the host. This has the same functions
+//! `rpc` uses, and remembers the levels it was given so the tests can check
+//! them. `dwarn!` and friends log straight away, as on the host there's
+//! nothing to gain by deferring the formatting.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use log::LevelFilter;
+use std::cell::RefCell;
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+#[allow(unused_macros)]
+macro_rules! dwarn {
+    ($($arg:tt)*) => { warn!($($arg)*) };
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The same limits as the firmware's.
+pub const MAX_MODULE_LEVELS: usize = 8;
+pub const MAX_MODULE_LEN: usize = 48;
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+thread_local! {
+    static DEFAULT_LEVEL: RefCell<Option<LevelFilter>> = const { RefCell::new(None) };
+    static MODULE_LEVELS: RefCell<Vec<(String, LevelFilter)>> = const { RefCell::new(Vec::new()) };
+}
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+pub fn set_default_level(level: LevelFilter) {
+    DEFAULT_LEVEL.with(|l| *l.borrow_mut() = Some(level));
+}
+
+pub fn set_module_level(module: &str, level: LevelFilter) -> bool {
+    MODULE_LEVELS.with(|levels| {
+        let mut levels = levels.borrow_mut();
+        levels.retain(|(m, _)| m != module);
+        if (module.len() > MAX_MODULE_LEN) || (levels.len() == MAX_MODULE_LEVELS) {
+            return false;
+        }
+        levels.push((module.to_owned(), level));
+        true
+    })
+}
+
+/// The level last given to `set_default_level` on this thread.
+pub fn default_level() -> Option<LevelFilter> {
+    DEFAULT_LEVEL.with(|l| *l.borrow())
+}
+
+/// The level set for `module` on this thread.
+pub fn module_level(module: &str) -> Option<LevelFilter> {
+    MODULE_LEVELS.with(|levels| {
+        levels
+            .borrow()
+            .iter()
+            .find(|&(m, _)| m == module)
+            .map(|&(_, level)| level)
+    })
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,31 @@
+//! # Version Stand-in
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! The firmware's version string is generated by its build script. This
+//! gives `rpc` a fixed one instead.
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+pub const VERSION: &str = "ipu-demo (host-tests)";
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+pub fn version() -> &'static str {
+    VERSION
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
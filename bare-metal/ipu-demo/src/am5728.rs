@@ -169,6 +169,21 @@ where
     mapper: &'a T,
 }
 
+/// Everything the IPU forgets if its power domain is switched off while we
+/// hibernate. See `Am5728::hibernate`.
+#[derive(Clone, Copy)]
+pub struct SavedContext {
+    nvic_enabled: [u32; NVIC_ENABLE_WORDS],
+    nvic_priority: [u8; NUM_INTERRUPTS],
+    cache_config: u32,
+    cache_ocp: u32,
+    mmu_large: [MmuPage; 4],
+    mmu_medium: [MmuPage; 2],
+    mmu_small: [MmuPage; 10],
+    mmu_config: u32,
+    wugen_evt: [u32; 2],
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum MailboxUser {
     User0,
@@ -217,6 +232,14 @@ pub struct MailboxLocation {
     pub slot: MailboxSlot,
 }
 
+/// The general purpose timers the kernel gives IPU1 as watchdogs, one per
+/// core. See `get_timer`.
+#[derive(Debug, Copy, Clone)]
+pub enum TimerId {
+    Timer7,
+    Timer8,
+}
+
 #[derive(Debug, Copy, Clone)]
 #[repr(u32)]
 pub enum CacheFlushMode {
@@ -397,20 +420,11 @@ pub enum InterruptPriority {
 //
 // ****************************************************************************
 
-pub const L3_OCMC_RAM: usize = 0x4030_0000;
-pub const L4_PERIPHERAL_L4PER1: usize = 0x4800_0000;
-pub const L4_PERIPHERAL_L4PER2: usize = 0x4840_0000;
-pub const L4_PERIPHERAL_L4PER3: usize = 0x4880_0000;
-pub const L4_PERIPHERAL_L4CFG: usize = 0x4A00_0000;
-pub const L3_PERIPHERAL_PRUSS: usize = 0x4B20_0000;
-pub const L3_PERIPHERAL_DMM: usize = 0x4E00_0000;
-pub const L4_PERIPHERAL_L4EMU: usize = 0x5400_0000;
-pub const L3_IVAHD_CONFIG: usize = 0x5A00_0000;
-pub const L3_IVAHD_SL2: usize = 0x5B00_0000;
-pub const L3_TILER_MODE_0_1: usize = 0x6000_0000;
-pub const L3_TILER_MODE_2: usize = 0x7000_0000;
-pub const L3_TILER_MODE_3: usize = 0x7800_0000;
-pub const L3_EMIF_SDRAM: usize = 0xA000_0000;
+/// The IPU's clock rate, and so how fast the cycle counter counts.
+pub const CPU_HZ: u32 = 212_800_000;
+
+/// The rate `SyncCounter` counts at. It wraps every 36 hours or so.
+pub const SYNC_COUNTER_HZ: u32 = 32_768;
 
 // Default IRQ mappings
 
@@ -574,6 +588,42 @@ pub struct Mailbox {
     irq_eoi: RW<u32>,
 }
 
+/// A general purpose timer (DMTimer). See the Timers chapter in the TRM.
+#[repr(C)]
+pub struct Timer {
+    /// Address Offset 0x0000_0000
+    tidr: RO<u32>,
+    _padding: [u32; 3],
+    /// Address Offset 0x0000_0010
+    tiocp_cfg: RW<u32>,
+    _padding2: [u32; 3],
+    /// Address Offset 0x0000_0020
+    irq_eoi: RW<u32>,
+    irqstatus_raw: RW<u32>,
+    irqstatus: RW<u32>,
+    irqenable_set: RW<u32>,
+    irqenable_clr: RW<u32>,
+    irqwakeen: RW<u32>,
+    /// Address Offset 0x0000_0038
+    tclr: RW<u32>,
+    tcrr: RW<u32>,
+    tldr: RW<u32>,
+    ttgr: RW<u32>,
+    /// Address Offset 0x0000_0048
+    twps: RO<u32>,
+}
+
+/// The 32 kHz synchronised timer (COUNTER_32K). It counts up from power-on
+/// at `SYNC_COUNTER_HZ`, whatever the IPU is doing, so it keeps going while
+/// we hibernate. See the Timers chapter in the TRM.
+#[repr(C)]
+pub struct SyncCounter {
+    /// Address Offset 0x0000_0000
+    _padding: [u32; 12],
+    /// Address Offset 0x0000_0030
+    cr: RO<u32>,
+}
+
 /// The Wake-Up Generator. Allows our core to come out of idle.
 /// Corresponds to IPUx_WUGEN in section 7.4.7
 #[repr(C)]
@@ -591,6 +641,14 @@ struct WuGen {
     _reserved: RW<u32>,
 }
 
+/// The saved translation and policy for one Unicache MMU page.
+#[derive(Debug, Clone, Copy, Default)]
+struct MmuPage {
+    addr: usize,
+    xlte: usize,
+    policy: u32,
+}
+
 /// Stores two 9-bit values with 16-bit alignment
 #[repr(C)]
 struct IrqRegister {
@@ -699,6 +757,27 @@ const MMU_MAINT_PRELOAD: u32 = 1 << 0;
 const MMU_CONFIG_PRIVILEGE: u32 = 1 << 1;
 const MMU_CONFIG_MMU_LOCK: u32 = 1 << 0;
 
+/// Trace enable bit in the Debug Exception and Monitor Control Register. The
+/// DWT doesn't count anything without it.
+const DEMCR_TRCENA: u32 = 1 << 24;
+
+const DWT_CTRL_CYCCNTENA: u32 = 1 << 0;
+
+/// Deep sleep bit in the System Control Register. With this set, WFI lets the
+/// PRCM put the IPU into standby.
+const SCR_SLEEPDEEP: u32 = 1 << 2;
+
+const TIMER_TCLR_ST: u32 = 1 << 0;
+const TIMER_TCLR_AR: u32 = 1 << 1;
+
+const TIMER_IRQ_OVF: u32 = 1 << 1;
+
+/// The number of interrupt lines after the 16 system exceptions.
+const NUM_INTERRUPTS: usize = 64;
+
+/// The number of 32-bit NVIC enable registers needed for `NUM_INTERRUPTS`.
+const NVIC_ENABLE_WORDS: usize = NUM_INTERRUPTS / 32;
+
 // ****************************************************************************
 //
 // Public Functions
@@ -742,6 +821,7 @@ where
         }
         self.cache_flush_all(CacheFlushAllMode::Flush);
         self.cache_enable();
+        self.cycle_counter_enable();
 
         // Need to enable interrupt for non-empty and not for non-full!
 
@@ -815,6 +895,151 @@ where
         self.interrupt_disable(Interrupt::Ipu1Irq44);
     }
 
+    /// Start the Cortex-M4 cycle counter. See `cycle_count`.
+    fn cycle_counter_enable(&mut self) {
+        unsafe {
+            let dcb = &*cortex_m::peripheral::DCB::ptr();
+            dcb.demcr.modify(|w| w | DEMCR_TRCENA);
+            let dwt = &*cortex_m::peripheral::DWT::ptr();
+            dwt.cyccnt.write(0);
+            dwt.ctrl.modify(|w| w | DWT_CTRL_CYCCNTENA);
+        }
+    }
+
+    /// The number of CPU cycles since boot. See `cycle_counter`.
+    pub fn cycle_count(&self) -> u32 {
+        cycle_counter()
+    }
+
+    /// Put the IPU in to deep sleep until `wake` fires. The NVIC, Unicache
+    /// and Unicache MMU are saved beforehand and restored afterwards, in case
+    /// the PRCM let their registers lose context while we were asleep. If
+    /// the power domain is switched right off we never return from here:
+    /// the core boots again from its reset vector. Only `wake` is left
+    /// enabled in the WuGen while we sleep. Nothing calls this yet: the
+    /// saved context is a local, and nothing on the reset path looks for
+    /// it, so we can't agree to the host's hibernation requests.
+    pub fn hibernate(&mut self, wake: Interrupt) {
+        cortex_m::interrupt::disable();
+        let context = self.save_context();
+        self.cache_flush_all(CacheFlushAllMode::WriteBack);
+        let wugen = get_wugen();
+        wugen.wake_none();
+        wugen.wake_on_interrupt(wake);
+        unsafe {
+            let scb = &*cortex_m::peripheral::SCB::ptr();
+            scb.scr.modify(|w| w | SCR_SLEEPDEEP);
+            cortex_m::asm::dsb();
+            // An interrupt pending at this point still wakes us, even
+            // though they are masked.
+            cortex_m::asm::wfi();
+            scb.scr.modify(|w| w & !SCR_SLEEPDEEP);
+        }
+        self.restore_context(&context);
+        unsafe {
+            cortex_m::interrupt::enable();
+        }
+    }
+
+    /// Take a copy of the NVIC, Unicache, Unicache MMU and WuGen registers.
+    pub fn save_context(&mut self) -> SavedContext {
+        let mut context = SavedContext {
+            nvic_enabled: [0; NVIC_ENABLE_WORDS],
+            nvic_priority: [0; NUM_INTERRUPTS],
+            cache_config: 0,
+            cache_ocp: 0,
+            mmu_large: [MmuPage::default(); 4],
+            mmu_medium: [MmuPage::default(); 2],
+            mmu_small: [MmuPage::default(); 10],
+            mmu_config: 0,
+            wugen_evt: [0; 2],
+        };
+        let nvic = unsafe { &*cortex_m::peripheral::NVIC::ptr() };
+        for (idx, word) in context.nvic_enabled.iter_mut().enumerate() {
+            *word = nvic.iser[idx].read();
+        }
+        for (idx, priority) in context.nvic_priority.iter_mut().enumerate() {
+            *priority = nvic.ipr[idx].read();
+        }
+        let unicache_cfg = get_unicache_config();
+        context.cache_config = unicache_cfg.config.read();
+        context.cache_ocp = unicache_cfg.ocp.read();
+        let unicache_mmu = get_unicache_mmu();
+        for (idx, page) in context.mmu_large.iter_mut().enumerate() {
+            *page = MmuPage {
+                addr: unicache_mmu.large_addr[idx].read(),
+                xlte: unicache_mmu.large_xlte[idx].read(),
+                policy: unicache_mmu.large_policy[idx].read(),
+            };
+        }
+        for (idx, page) in context.mmu_medium.iter_mut().enumerate() {
+            *page = MmuPage {
+                addr: unicache_mmu.medium_addr[idx].read(),
+                xlte: unicache_mmu.medium_xlte[idx].read(),
+                policy: unicache_mmu.medium_policy[idx].read(),
+            };
+        }
+        for (idx, page) in context.mmu_small.iter_mut().enumerate() {
+            *page = MmuPage {
+                addr: unicache_mmu.small_addr[idx].read(),
+                xlte: unicache_mmu.small_xlte[idx].read(),
+                policy: unicache_mmu.small_policy[idx].read(),
+            };
+        }
+        context.mmu_config = unicache_mmu.mmu_config.read();
+        let wugen = get_wugen();
+        context.wugen_evt = [wugen.wugen_evt0.read(), wugen.wugen_evt1.read()];
+        context
+    }
+
+    /// Put back everything `save_context` took a copy of. The MMU goes back
+    /// first so that the cache comes back up with the right policies, and
+    /// the cache is invalidated as its contents may be stale.
+    pub fn restore_context(&mut self, context: &SavedContext) {
+        let unicache_mmu = get_unicache_mmu();
+        unsafe {
+            for (idx, page) in context.mmu_large.iter().enumerate() {
+                unicache_mmu.large_addr[idx].write(page.addr);
+                unicache_mmu.large_xlte[idx].write(page.xlte);
+                unicache_mmu.large_policy[idx].write(page.policy);
+            }
+            for (idx, page) in context.mmu_medium.iter().enumerate() {
+                unicache_mmu.medium_addr[idx].write(page.addr);
+                unicache_mmu.medium_xlte[idx].write(page.xlte);
+                unicache_mmu.medium_policy[idx].write(page.policy);
+            }
+            for (idx, page) in context.mmu_small.iter().enumerate() {
+                unicache_mmu.small_addr[idx].write(page.addr);
+                unicache_mmu.small_xlte[idx].write(page.xlte);
+                unicache_mmu.small_policy[idx].write(page.policy);
+            }
+            unicache_mmu.mmu_config.write(context.mmu_config);
+
+            let unicache_cfg = get_unicache_config();
+            unicache_cfg.ocp.write(context.cache_ocp);
+            unicache_cfg.config.write(context.cache_config);
+            // Ensure write is complete
+            let _ = unicache_cfg.config.read();
+        }
+        self.cache_flush_all(CacheFlushAllMode::Invalidate);
+
+        let wugen = get_wugen();
+        unsafe {
+            wugen.wugen_evt0.write(context.wugen_evt[0]);
+            wugen.wugen_evt1.write(context.wugen_evt[1]);
+        }
+
+        let nvic = unsafe { &*cortex_m::peripheral::NVIC::ptr() };
+        for (idx, priority) in context.nvic_priority.iter().enumerate() {
+            unsafe { nvic.ipr[idx].write(*priority) };
+        }
+        for (idx, word) in context.nvic_enabled.iter().enumerate() {
+            unsafe { nvic.iser[idx].write(*word) };
+        }
+        // The counter stops if we lose power, so make sure it's running.
+        self.cycle_counter_enable();
+    }
+
     /// Enable the L1 'Unicache'
     pub fn cache_enable(&mut self) {
         unsafe {
@@ -871,14 +1096,7 @@ where
     /// Tell the unicache to invalidate/writeback a specific address range
     /// from the L1 cache.
     pub unsafe fn cache_flush_address(&mut self, address: usize, len: usize, mode: CacheFlushMode) {
-        let unicache_cfg = get_unicache_config();
-        unicache_cfg.mt_start.write(address);
-        unicache_cfg.mt_end.write(address + len - 1);
-        let mode: u32 = mode as u32;
-        unicache_cfg.maint.modify(|v| v | mode);
-        while (unicache_cfg.maint.read() & 0x1f) != 0 {
-            cortex_m::asm::nop();
-        }
+        cache_flush_range(address, len, mode);
     }
 
     /// Send a message to the host.
@@ -894,6 +1112,15 @@ where
         }
     }
 
+    /// Send a message to the host without waiting for it to make room.
+    /// Returns false if the mailbox was full and the message was dropped.
+    pub fn try_send_message(&mut self, id: u32, location: MailboxLocation) -> bool {
+        match get_mailbox(location.id, self.mapper) {
+            Some(mailbox) => mailbox.try_put_message(location.slot, id),
+            None => false,
+        }
+    }
+
     /// Get any message the host may have for us.
     ///
     /// This invoves processor 8 talking to processor 6.
@@ -957,6 +1184,19 @@ impl Mailbox {
         }
     }
 
+    /// Send a message without waiting for the other end to empty the FIFO.
+    /// Returns false if the FIFO was full and the message was dropped.
+    pub fn try_put_message(&mut self, slot: MailboxSlot, msg: u32) -> bool {
+        if self.fifo_status[slot as usize].read() != 0 {
+            false
+        } else {
+            unsafe {
+                self.message[slot as usize].write(msg);
+            }
+            true
+        }
+    }
+
     /// Mark an interrupt as handled
     pub fn clear_interrupts(&mut self, user: MailboxUser) {
         unsafe {
@@ -1137,6 +1377,26 @@ fn get_wugen() -> &'static mut WuGen {
     unsafe { &mut *(WUGEN_ADDR as *mut WuGen) }
 }
 
+/// Tell the unicache to invalidate/writeback a specific address range
+/// from the L1 cache, without claiming the chip. For the fault handlers;
+/// everything else should use `Am5728::cache_flush_address`.
+pub unsafe fn cache_flush_range(address: usize, len: usize, mode: CacheFlushMode) {
+    let unicache_cfg = get_unicache_config();
+    unicache_cfg.mt_start.write(address);
+    unicache_cfg.mt_end.write(address + len - 1);
+    let mode: u32 = mode as u32;
+    unicache_cfg.maint.modify(|v| v | mode);
+    while (unicache_cfg.maint.read() & 0x1f) != 0 {
+        cortex_m::asm::nop();
+    }
+}
+
+/// The number of CPU cycles since boot. This only counts once the chip has
+/// been claimed. At `CPU_HZ`, it wraps every 20 seconds or so.
+pub fn cycle_counter() -> u32 {
+    unsafe { (*cortex_m::peripheral::DWT::ptr()).cyccnt.read() }
+}
+
 /// Get a reference to a specific mailbox instance. The mailboxes are remote
 /// to the M4 so we need something that can map the fixed physical address of
 /// the peripheral to the device address we need as mapped in the IOMMU.
@@ -1167,22 +1427,107 @@ where
     }
 }
 
+/// Get a reference to a general purpose timer. Like the mailboxes, the
+/// timers are remote to the M4, so their addresses need mapping.
+pub fn get_timer<T>(id: TimerId, mapper: &T) -> Option<&'static mut Timer>
+where
+    T: rt::AddressMapper,
+{
+    // Both are in L4_PERIPHERAL_L4PER3.
+    let pa = match id {
+        TimerId::Timer7 => 0x4882_4000,
+        TimerId::Timer8 => 0x4882_6000,
+    };
+    match mapper.pa_to_da(pa) {
+        Some(pa) => unsafe { Some(&mut *(pa as *mut Timer)) },
+        None => None,
+    }
+}
+
+/// Get a reference to the 32 kHz synchronised timer. It's in L4_WKUP,
+/// which is inside the L4_PERIPHERAL_L4CFG devmem.
+pub fn get_sync_counter<T>(mapper: &T) -> Option<&'static SyncCounter>
+where
+    T: rt::AddressMapper,
+{
+    match mapper.pa_to_da(0x4AE0_4000) {
+        Some(da) => unsafe { Some(&*(da as *const SyncCounter)) },
+        None => None,
+    }
+}
+
+impl SyncCounter {
+    /// The current count.
+    pub fn read(&self) -> u32 {
+        self.cr.read()
+    }
+}
+
+impl Timer {
+    /// Overflow after `ticks` of the timer's functional clock, raising the
+    /// overflow interrupt, then start again from the same value.
+    pub fn start_overflow(&mut self, ticks: u32) {
+        let load = 0u32.wrapping_sub(ticks);
+        unsafe {
+            self.wait_for_writes();
+            self.tclr.modify(|w| w & !TIMER_TCLR_ST);
+            self.wait_for_writes();
+            self.tldr.write(load);
+            self.wait_for_writes();
+            self.tcrr.write(load);
+            self.irqstatus.write(TIMER_IRQ_OVF);
+            self.irqenable_set.write(TIMER_IRQ_OVF);
+            self.wait_for_writes();
+            self.tclr.write(TIMER_TCLR_AR | TIMER_TCLR_ST);
+        }
+    }
+
+    /// Go back to the start value, putting off the overflow.
+    pub fn reload(&mut self) {
+        self.wait_for_writes();
+        // Any value will do
+        unsafe { self.ttgr.write(1) };
+    }
+
+    /// Stop counting.
+    pub fn stop(&mut self) {
+        self.wait_for_writes();
+        unsafe { self.tclr.modify(|w| w & !TIMER_TCLR_ST) };
+    }
+
+    /// In posted mode, a write to a timer register takes a few cycles of the
+    /// timer's clock to land. Another write to the same register before then
+    /// is lost.
+    fn wait_for_writes(&self) {
+        while self.twps.read() != 0 {
+            cortex_m::asm::nop();
+        }
+    }
+}
+
 impl WuGen {
     /// Enable a wake-up trigger on IPU1 when this interrupt fires.
     pub fn wake_on_interrupt(&mut self, interrupt: Interrupt) {
+        // This is zero for IRQ 16, which is bit 0 of the first register.
         let num = interrupt.nr() as u32;
-        if num >= 48 {
-            // Interrupt bit is in second register of 32..63
+        if num >= 32 {
+            // IRQs 48..79 are in the second register
             unsafe {
-                self.wugen_evt1.modify(|m| m | num);
+                self.wugen_evt1.modify(|m| m | (1 << (num - 32)));
             }
-        } else if num >= 16 {
-            // Interrupt bit is in first register of 0..31
+        } else {
+            // IRQs 16..47 are in the first register
             unsafe {
-                self.wugen_evt0.modify(|m| m | num);
+                self.wugen_evt0.modify(|m| m | (1 << num));
             }
-        } else {
-            panic!("Can't enable WuGen interrupt when < 16")
+        }
+    }
+
+    /// Disable all wake-up triggers on IPU1.
+    pub fn wake_none(&mut self) {
+        unsafe {
+            self.wugen_evt0.write(0);
+            self.wugen_evt1.write(0);
         }
     }
 }
@@ -0,0 +1,406 @@
+//! # Control Messages
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Besides the vring notifications (0 and 1), the host sends us the
+//! `MBOX_*` control messages defined in `rpmsg`. This module tracks what
+//! state the firmware is in, decides what each control message means in
+//! that state, and logs every change of state. The main loop carries out
+//! the resulting `Action`.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use rpmsg;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Waiting for the host to prime the vrings
+    Booting,
+    /// Handling messages
+    Running,
+    /// Emptying the host-to-IPU ring, after `MBOX_PENDING_MSG`
+    Draining,
+    /// Shutting down, after `MBOX_ABORT_REQUEST`
+    Stopping,
+    /// Shut down cleanly. We never leave this state.
+    Stopped,
+    /// Something went badly wrong and we've told the host with
+    /// `MBOX_CRASH`. We never leave this state.
+    Crashed,
+}
+
+/// What the main loop should do about a control message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Nothing
+    None,
+    /// Send this message to the host's mailbox
+    Reply(u32),
+    /// Write back the cache
+    FlushCache,
+    /// Process everything in the host-to-IPU ring, then call `drained`
+    DrainRx,
+    /// Withdraw our services and stop, calling `stopped` on the way
+    Shutdown,
+}
+
+/// The control message state machine.
+pub struct Control {
+    state: State,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// Every `MBOX_*` message. Anything else is a vring notification.
+const CONTROL_MESSAGES: [u32; 12] = [
+    rpmsg::MBOX_READY,
+    rpmsg::MBOX_PENDING_MSG,
+    rpmsg::MBOX_CRASH,
+    rpmsg::MBOX_ECHO_REQUEST,
+    rpmsg::MBOX_ECHO_REPLY,
+    rpmsg::MBOX_ABORT_REQUEST,
+    rpmsg::MBOX_FLUSH_CACHE,
+    rpmsg::MBOX_BOOTINIT_DONE,
+    rpmsg::MBOX_HIBERNATION,
+    rpmsg::MBOX_HIBERNATION_FORCE,
+    rpmsg::MBOX_HIBERNATION_ACK,
+    rpmsg::MBOX_HIBERNATION_CANCEL,
+];
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl Control {
+    pub fn new() -> Control {
+        Control {
+            state: State::Booting,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Call once we've told the host we've booted.
+    pub fn booted(&mut self) {
+        self.transition(State::Running, "boot complete");
+    }
+
+    /// Decide what to do with a message from the host. Returns `None` if
+    /// it isn't a control message, i.e. it's a vring notification.
+    pub fn handle(&mut self, msg: u32) -> Option<Action> {
+        if !CONTROL_MESSAGES.contains(&msg) {
+            return None;
+        }
+        match self.state {
+            // Once we've stopped, we don't do anything the host asks.
+            State::Stopped | State::Crashed => return Some(Action::None),
+            _ => {}
+        }
+        let action = match msg {
+            rpmsg::MBOX_READY => {
+                debug!("Host ready");
+                Action::None
+            }
+            rpmsg::MBOX_ECHO_REQUEST => Action::Reply(rpmsg::MBOX_ECHO_REPLY),
+            rpmsg::MBOX_FLUSH_CACHE => Action::FlushCache,
+            rpmsg::MBOX_PENDING_MSG => {
+                if self.state == State::Running {
+                    self.transition(State::Draining, "pending message");
+                    Action::DrainRx
+                } else {
+                    Action::None
+                }
+            }
+            rpmsg::MBOX_ABORT_REQUEST => {
+                self.transition(State::Stopping, "abort request");
+                Action::Shutdown
+            }
+            rpmsg::MBOX_HIBERNATION | rpmsg::MBOX_HIBERNATION_FORCE => {
+                // Once we ACK, the host may switch our power domain off.
+                // When it powers us up again it only releases reset, and
+                // we can't yet restore our context from there, so we'd
+                // boot from scratch against vrings the host is still
+                // using. Refuse until we can.
+                Action::Reply(rpmsg::MBOX_HIBERNATION_CANCEL)
+            }
+            rpmsg::MBOX_CRASH
+            | rpmsg::MBOX_ECHO_REPLY
+            | rpmsg::MBOX_BOOTINIT_DONE
+            | rpmsg::MBOX_HIBERNATION_ACK
+            | rpmsg::MBOX_HIBERNATION_CANCEL => {
+                // These only go from us to the host
+                dwarn!("Ignoring 0x{:08x} from host", msg);
+                Action::None
+            }
+            _ => return None,
+        };
+        Some(action)
+    }
+
+    /// Call once `Action::DrainRx` is complete.
+    pub fn drained(&mut self) {
+        if self.state == State::Draining {
+            self.transition(State::Running, "ring empty");
+        }
+    }
+
+    /// Call just before halting for `Action::Shutdown`.
+    pub fn stopped(&mut self) {
+        self.transition(State::Stopped, "services withdrawn");
+    }
+
+    /// Something has gone wrong that we can't recover from. Returns the
+    /// message to send to the host, so it can recover us.
+    pub fn fatal(&mut self, reason: &str) -> u32 {
+        self.transition(State::Crashed, reason);
+        rpmsg::MBOX_CRASH
+    }
+
+    fn transition(&mut self, new_state: State, reason: &str) {
+        info!("{:?} -> {:?} ({})", self.state, new_state, reason);
+        self.state = new_state;
+    }
+}
+
+impl Default for Control {
+    fn default() -> Control {
+        Control::new()
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+// None
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Every state we answer the host in.
+    const LIVE: [State; 4] = [
+        State::Booting,
+        State::Running,
+        State::Draining,
+        State::Stopping,
+    ];
+
+    /// Messages which only go from us to the host.
+    const TO_HOST: [u32; 5] = [
+        rpmsg::MBOX_CRASH,
+        rpmsg::MBOX_ECHO_REPLY,
+        rpmsg::MBOX_BOOTINIT_DONE,
+        rpmsg::MBOX_HIBERNATION_ACK,
+        rpmsg::MBOX_HIBERNATION_CANCEL,
+    ];
+
+    /// Send `msg` to a `Control` in each of `states`, and check what it
+    /// asks for and the state it ends up in (`None` meaning unchanged).
+    fn check(states: &[State], msg: u32, action: Option<Action>, new_state: Option<State>) {
+        for &state in states {
+            let mut control = Control { state };
+            assert_eq!(control.handle(msg), action, "0x{:08x} in {:?}", msg, state);
+            assert_eq!(
+                control.state(),
+                new_state.unwrap_or(state),
+                "0x{:08x} in {:?}",
+                msg,
+                state
+            );
+        }
+    }
+
+    #[test]
+    fn vring_notifications() {
+        let all = [
+            State::Booting,
+            State::Running,
+            State::Draining,
+            State::Stopping,
+            State::Stopped,
+            State::Crashed,
+        ];
+        check(&all, 0, None, None);
+        check(&all, 1, None, None);
+    }
+
+    #[test]
+    fn ready() {
+        check(&LIVE, rpmsg::MBOX_READY, Some(Action::None), None);
+    }
+
+    #[test]
+    fn echo_request() {
+        check(
+            &LIVE,
+            rpmsg::MBOX_ECHO_REQUEST,
+            Some(Action::Reply(rpmsg::MBOX_ECHO_REPLY)),
+            None,
+        );
+    }
+
+    #[test]
+    fn flush_cache() {
+        check(
+            &LIVE,
+            rpmsg::MBOX_FLUSH_CACHE,
+            Some(Action::FlushCache),
+            None,
+        );
+    }
+
+    #[test]
+    fn pending_msg() {
+        check(
+            &[State::Running],
+            rpmsg::MBOX_PENDING_MSG,
+            Some(Action::DrainRx),
+            Some(State::Draining),
+        );
+        check(
+            &[State::Booting, State::Draining, State::Stopping],
+            rpmsg::MBOX_PENDING_MSG,
+            Some(Action::None),
+            None,
+        );
+    }
+
+    #[test]
+    fn abort_request() {
+        check(
+            &LIVE,
+            rpmsg::MBOX_ABORT_REQUEST,
+            Some(Action::Shutdown),
+            Some(State::Stopping),
+        );
+    }
+
+    #[test]
+    fn hibernation() {
+        for &msg in &[rpmsg::MBOX_HIBERNATION, rpmsg::MBOX_HIBERNATION_FORCE] {
+            check(
+                &LIVE,
+                msg,
+                Some(Action::Reply(rpmsg::MBOX_HIBERNATION_CANCEL)),
+                None,
+            );
+        }
+    }
+
+    #[test]
+    fn to_host() {
+        for &msg in &TO_HOST {
+            check(&LIVE, msg, Some(Action::None), None);
+        }
+    }
+
+    #[test]
+    fn stopped() {
+        let from_host = [
+            rpmsg::MBOX_READY,
+            rpmsg::MBOX_PENDING_MSG,
+            rpmsg::MBOX_ECHO_REQUEST,
+            rpmsg::MBOX_ABORT_REQUEST,
+            rpmsg::MBOX_FLUSH_CACHE,
+            rpmsg::MBOX_HIBERNATION,
+            rpmsg::MBOX_HIBERNATION_FORCE,
+        ];
+        for &msg in from_host.iter().chain(TO_HOST.iter()) {
+            check(
+                &[State::Stopped, State::Crashed],
+                msg,
+                Some(Action::None),
+                None,
+            );
+        }
+    }
+
+    #[test]
+    fn lifecycle() {
+        let mut control = Control::new();
+        assert_eq!(control.state(), State::Booting);
+        control.booted();
+        assert_eq!(control.state(), State::Running);
+        // Only a drain we started ends in `Running`
+        control.drained();
+        assert_eq!(control.state(), State::Running);
+        control.handle(rpmsg::MBOX_PENDING_MSG);
+        control.drained();
+        assert_eq!(control.state(), State::Running);
+        control.handle(rpmsg::MBOX_ABORT_REQUEST);
+        control.stopped();
+        assert_eq!(control.state(), State::Stopped);
+
+        let mut control = Control::new();
+        assert_eq!(control.fatal("test"), rpmsg::MBOX_CRASH);
+        assert_eq!(control.state(), State::Crashed);
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
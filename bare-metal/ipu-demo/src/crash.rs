@@ -0,0 +1,255 @@
+//! # Crash Reports
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! When we panic or take a HardFault, we fill in a `CrashReport` (see the
+//! `crash-report` crate) with the exception frame, the fault status
+//! registers, the exception which was running, the top of the stack and
+//! our version. The report goes to the trace buffer as text, and a binary
+//! copy goes in the `.crash_report` section, just after the trace buffer in
+//! the `IPU_MEM_IPC_DATA` carveout. That copy stays put until the firmware
+//! is loaded again, so the host can read it even if the trace buffer has
+//! been lost.
+//!
+//! Only the first crash is recorded. If recording it causes another one,
+//! we don't try again.
+//!
+//! What happens next is `ACTION`, chosen with the `crash-halt` and
+//! `crash-reset` features. By default we send `MBOX_CRASH` to the host and
+//! halt, and the omap remoteproc driver restarts us if its recovery is
+//! enabled. With `crash-reset` we also reset ourselves, but only once the
+//! host has had `RECOVERY_WINDOW_SECS` to stop us, so we don't race its
+//! recovery.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use am5728;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use cortex_m;
+use cortex_m_rt;
+use crash_report::{self, CrashReport, Kind, ENCODED_LEN};
+use resource_table as rt;
+use resources::RESOURCE_TABLE;
+use trace;
+use version;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// What to do once a crash has been recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Halt without telling the host, so it leaves us alone to be
+    /// inspected with a debugger
+    Halt,
+    /// Tell the host with `MBOX_CRASH`, then halt
+    Notify,
+    /// Tell the host, then reset ourselves if the host hasn't stopped us
+    /// within `RECOVERY_WINDOW_SECS`
+    NotifyAndReset,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+#[cfg(all(feature = "crash-halt", feature = "crash-reset"))]
+compile_error!("Choose at most one of the crash-halt and crash-reset features");
+
+#[cfg(feature = "crash-halt")]
+pub const ACTION: Action = Action::Halt;
+
+#[cfg(feature = "crash-reset")]
+pub const ACTION: Action = Action::NotifyAndReset;
+
+#[cfg(not(any(feature = "crash-halt", feature = "crash-reset")))]
+pub const ACTION: Action = Action::Notify;
+
+/// How long `Action::NotifyAndReset` gives the host to stop us. When its
+/// recovery is enabled, the omap remoteproc driver puts us in reset within
+/// milliseconds of `MBOX_CRASH`, so if we're still running after this, the
+/// host isn't going to restart us.
+pub const RECOVERY_WINDOW_SECS: u32 = 5;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+/// Keeps the binary report on a word boundary, where the host looks for it.
+#[repr(C, align(4))]
+struct ReportBuffer([u8; ENCODED_LEN]);
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// The active exception number, in both the xPSR and the ICSR.
+const EXCEPTION_MASK: u32 = 0x1FF;
+
+const AIRCR_VECTKEY: u32 = 0x05FA << 16;
+const AIRCR_PRIGROUP_MASK: u32 = 0x7 << 8;
+const AIRCR_SYSRESETREQ: u32 = 1 << 2;
+
+#[link_section = ".crash_report"]
+static mut CRASH_REPORT: ReportBuffer = ReportBuffer([0; ENCODED_LEN]);
+
+/// Set once we've started recording a crash.
+static mut CRASHED: bool = false;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Record a HardFault. `frame` is what the CPU stacked when it took the
+/// fault.
+pub fn hard_fault(frame: &cortex_m_rt::ExceptionFrame) {
+    let mut report = CrashReport::new(Kind::HardFault);
+    report.frame = crash_report::ExceptionFrame {
+        r0: frame.r0,
+        r1: frame.r1,
+        r2: frame.r2,
+        r3: frame.r3,
+        r12: frame.r12,
+        lr: frame.lr,
+        pc: frame.pc,
+        xpsr: frame.xpsr,
+    };
+    // We're in the HardFault handler now, but the stacked xPSR says what
+    // was running when the fault hit.
+    report.exception = frame.xpsr & EXCEPTION_MASK;
+    let sp = frame as *const _ as usize + ::core::mem::size_of::<cortex_m_rt::ExceptionFrame>();
+    record(report, sp);
+}
+
+/// Record a panic.
+pub fn panic(info: &PanicInfo) {
+    let mut report = CrashReport::new(Kind::Panic);
+    // Never fails, just stops when the report is full
+    let _ = write!(report, "{}", info);
+    report.exception = unsafe { (*cortex_m::peripheral::SCB::ptr()).icsr.read() } & EXCEPTION_MASK;
+    record(report, cortex_m::register::msp::read() as usize);
+}
+
+/// Wait out the host's recovery window, then reset the IPU. We boot again
+/// from the image which is already loaded. The window is timed with the
+/// sync timer, as the cycle counter may not be running yet; if that isn't
+/// mapped, we can't tell how long we've waited, so we halt instead.
+pub fn reset<T>(mapper: &T) -> !
+where
+    T: rt::AddressMapper,
+{
+    cortex_m::interrupt::disable();
+    if let Some(counter) = am5728::get_sync_counter(mapper) {
+        let window = RECOVERY_WINDOW_SECS * am5728::SYNC_COUNTER_HZ;
+        let start = counter.read();
+        while counter.read().wrapping_sub(start) < window {
+            cortex_m::asm::nop();
+        }
+        info!("Host hasn't stopped us. Resetting after crash.");
+        unsafe {
+            let scb = &*cortex_m::peripheral::SCB::ptr();
+            let prigroup = scb.aircr.read() & AIRCR_PRIGROUP_MASK;
+            scb.aircr
+                .write(AIRCR_VECTKEY | prigroup | AIRCR_SYSRESETREQ);
+        }
+        cortex_m::asm::dsb();
+    }
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Fill in the rest of `report`, given the stack pointer, and write it out.
+fn record(mut report: CrashReport, sp: usize) {
+    cortex_m::interrupt::disable();
+    unsafe {
+        if CRASHED {
+            return;
+        }
+        CRASHED = true;
+    }
+    let (seconds, micros) = trace::uptime();
+    report.seconds = seconds;
+    report.micros = micros;
+    unsafe {
+        let scb = &*cortex_m::peripheral::SCB::ptr();
+        report.cfsr = scb.cfsr.read();
+        report.hfsr = scb.hfsr.read();
+        report.mmfar = scb.mmfar.read();
+        report.bfar = scb.bfar.read();
+    }
+    report.sp = sp as u32;
+    // The stack is at the top of our RAM carveout. Don't follow a stack
+    // pointer which points anywhere else, or we'll fault again.
+    let ram = &RESOURCE_TABLE.data_cout;
+    let (bottom, top) = (ram.da as usize, ram.da as usize + ram.len as usize);
+    if sp >= bottom && sp < top && sp % 4 == 0 {
+        let words = (top - sp) / 4;
+        report.set_stack(unsafe { ::core::slice::from_raw_parts(sp as *const u32, words) });
+    }
+    report.set_version(version::version());
+
+    trace::write_line_unbuffered(format_args!("{}", report));
+    unsafe {
+        report.encode(&mut CRASH_REPORT.0);
+        // The host reads this from DDR, not through our cache
+        am5728::cache_flush_range(
+            &CRASH_REPORT as *const _ as usize,
+            ENCODED_LEN,
+            am5728::CacheFlushMode::WriteBack,
+        );
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -25,6 +25,16 @@
 extern crate cortex_m;
 #[macro_use]
 extern crate cortex_m_rt;
+extern crate crash_report;
+extern crate demo_messages;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate resource_table;
+extern crate rpmsg_fragment;
+extern crate rpmsg_rpc;
+extern crate ti_messageq;
+extern crate trace_ring;
 extern crate volatile_register;
 extern crate vring;
 
@@ -34,10 +44,10 @@ extern crate vring;
 //
 // ****************************************************************************
 
-use core::fmt::Write;
 use core::panic::PanicInfo;
 use resource_table as rt;
-use rt::{AddressMapper, Region};
+use resources::RESOURCE_TABLE;
+use rt::AddressMapper;
 
 use rpmsg::SendMessage;
 
@@ -51,11 +61,20 @@ pub use am5728::__INTERRUPTS;
 
 #[macro_use]
 mod am5728;
-mod resource_table;
-mod rpmsg;
-mod string;
+// Before the other modules, so they can use its macros
+#[macro_use]
 mod trace;
+mod control;
+mod crash;
+mod memory_map;
+mod messageq;
+mod resources;
+mod rpc;
+mod rpmsg;
+mod services;
 mod version;
+#[cfg(feature = "watchdog")]
+mod watchdog;
 
 // ****************************************************************************
 //
@@ -71,354 +90,14 @@ mod version;
 //
 // ****************************************************************************
 
-/// This resource table structure is processed by the kernel. We can map as
-/// many resources as we require, but ensure that the offsets array is
-/// calculated correctly. Resource tables are specific to each application,
-/// but in this case it closely matches the TI example.
-#[repr(C)]
-#[derive(Debug)]
-pub struct ResourceTable {
-    base: rt::Header,
-    offsets: [usize; NUM_ENTRIES],
-    rpmsg_vdev: rt::Vdev,
-    rpmsg_vring0: rt::VdevVring,
-    rpmsg_vring1: rt::VdevVring,
-    text_cout: rt::Carveout,
-    data_cout: rt::Carveout,
-    ipcdata_cout: rt::Carveout,
-    trace: rt::Trace,
-    devmem0: rt::Devmem,
-    devmem1: rt::Devmem,
-    devmem2: rt::Devmem,
-    devmem3: rt::Devmem,
-    devmem4: rt::Devmem,
-    devmem5: rt::Devmem,
-    devmem6: rt::Devmem,
-    devmem7: rt::Devmem,
-    devmem8: rt::Devmem,
-    devmem9: rt::Devmem,
-    devmem10: rt::Devmem,
-    devmem11: rt::Devmem,
-    devmem12: rt::Devmem,
-    devmem13: rt::Devmem,
-    devmem14: rt::Devmem,
-    devmem15: rt::Devmem,
-}
+// None
 
 // ****************************************************************************
-
 //
 // Public Data
 //
 // ****************************************************************************
 
-#[link_section = ".resource_table"]
-#[no_mangle]
-pub static RESOURCE_TABLE: ResourceTable = ResourceTable {
-    base: rt::Header {
-        ver: 1,
-        num: NUM_ENTRIES,
-        reserved: [0, 0],
-    },
-    // We don't have an offsetof macro so we have to calculate these by hand
-    offsets: [
-        SZ_RT_HEADER,
-        SZ_RT_HEADER + 68,
-        SZ_RT_HEADER + 124,
-        SZ_RT_HEADER + 180,
-        SZ_RT_HEADER + 236,
-        SZ_RT_HEADER + 284,
-        SZ_RT_HEADER + 340,
-        SZ_RT_HEADER + 396,
-        SZ_RT_HEADER + 452,
-        SZ_RT_HEADER + 508,
-        SZ_RT_HEADER + 564,
-        SZ_RT_HEADER + 620,
-        SZ_RT_HEADER + 676,
-        SZ_RT_HEADER + 732,
-        SZ_RT_HEADER + 788,
-        SZ_RT_HEADER + 844,
-        SZ_RT_HEADER + 900,
-        SZ_RT_HEADER + 956,
-        SZ_RT_HEADER + 1012,
-        SZ_RT_HEADER + 1068,
-        SZ_RT_HEADER + 1124,
-    ],
-
-    rpmsg_vdev: rt::Vdev {
-        rtype: rt::ResourceType::VDEV,
-        id: vring::VIRTIO_ID_RPMSG,
-        notifyid: 0,
-        dfeatures: 1,
-        gfeatures: 0,
-        config_len: 0,
-        status: 0,
-        num_of_vrings: 2,
-        reserved: [0, 0],
-    },
-
-    /// vring0 is for rproc-to-Linux comms
-    rpmsg_vring0: rt::VdevVring {
-        da: 0x60000000,
-        align: 4096,
-        num: 256,
-        notifyid: 1,
-        reserved: 0,
-    },
-
-    /// vring1 is for Linux-to-rproc comms
-    rpmsg_vring1: rt::VdevVring {
-        da: 0x60004000,
-        align: 4096,
-        num: 256,
-        notifyid: 2,
-        reserved: 0,
-    },
-
-    text_cout: rt::Carveout {
-        rtype: rt::ResourceType::CARVEOUT,
-        da: 0x00000000,
-        pa: 0, // Auto allocated by kernel
-        len: rt::SZ_1M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_MEM_TEXT\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    data_cout: rt::Carveout {
-        rtype: rt::ResourceType::CARVEOUT,
-        da: 0x80000000,
-        pa: 0, // Auto allocated by kernel
-        len: rt::SZ_1M * 5,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_MEM_DATA\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    ipcdata_cout: rt::Carveout {
-        rtype: rt::ResourceType::CARVEOUT,
-        da: 0x9F000000,
-        pa: 0, // Auto allocated by kernel
-        len: rt::SZ_1M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_MEM_IPC_DATA\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    trace: rt::Trace {
-        rtype: rt::ResourceType::TRACE,
-        /// We must ensure that the tracebuffer structure is linked at this
-        /// address. We do this by placing it at the start of the ".tracebuffer"
-        /// section. Ideally we'd just take the address of our buffer
-        /// but that's now allowed in a static variable definition.
-        da: 0x9F000000,
-        len: 16384,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"trace:sysm3\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem0: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x60000000,
-        /// This has to match the CMA for IPU1 in the Device Tree
-        pa: 0x9D000000,
-        len: rt::SZ_1M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_MEM_IPC_VRING\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem1: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x90000000,
-        pa: 0xBA300000,
-        len: rt::SZ_1M * 90,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_MEM_IOBUFS\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem2: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0xA0000000,
-        pa: am5728::L3_TILER_MODE_0_1,
-        len: rt::SZ_256M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_TILER_MODE_0_1\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem3: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0xB0000000,
-        pa: am5728::L3_TILER_MODE_2,
-        len: rt::SZ_128M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_TILER_MODE_2\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem4: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0xB8000000,
-        pa: am5728::L3_TILER_MODE_3,
-        len: rt::SZ_128M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_TILER_MODE_3\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem5: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x6A000000,
-        pa: am5728::L4_PERIPHERAL_L4CFG,
-        len: rt::SZ_16M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_PERIPHERAL_L4CFG\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem6: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x68000000,
-        pa: am5728::L4_PERIPHERAL_L4PER1,
-        len: rt::SZ_2M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_PERIPHERAL_L4PER1\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem7: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x68400000,
-        pa: am5728::L4_PERIPHERAL_L4PER2,
-        len: rt::SZ_4M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_PERIPHERAL_L4PER2\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem8: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x68800000,
-        pa: am5728::L4_PERIPHERAL_L4PER3,
-        len: rt::SZ_8M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_PERIPHERAL_L4PER3\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem9: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x74000000,
-        pa: am5728::L4_PERIPHERAL_L4EMU,
-        len: rt::SZ_16M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_PERIPHERAL_L4EMU\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem10: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x6B200000,
-        pa: am5728::L3_PERIPHERAL_PRUSS,
-        len: rt::SZ_1M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_PERIPHERAL_PRUSS\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem11: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x7A000000,
-        pa: am5728::L3_IVAHD_CONFIG,
-        len: rt::SZ_16M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_IVAHD_CONFIG\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem12: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x7B000000,
-        pa: am5728::L3_IVAHD_SL2,
-        len: rt::SZ_16M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_IVAHD_SL2\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem13: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x6E000000,
-        pa: am5728::L3_PERIPHERAL_DMM,
-        len: rt::SZ_1M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_PERIPHERAL_DMM\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem14: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x60300000,
-        pa: am5728::L3_OCMC_RAM,
-        len: rt::SZ_4M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_OCMC_RAM\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-
-    devmem15: rt::Devmem {
-        rtype: rt::ResourceType::DEVMEM,
-        da: 0x10000000,
-        pa: am5728::L3_EMIF_SDRAM,
-        len: rt::SZ_256M,
-        flags: 0,
-        reserved: 0,
-        name: rt::String32 {
-            buffer: *b"IPU_EMIF_SDRAM\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
-        },
-    },
-};
-
 #[doc(hidden)]
 #[link_section = ".vector_table.reset_vector_am5728"]
 #[no_mangle]
@@ -449,11 +128,6 @@ pub unsafe extern "C" fn ResetAM5728() -> ! {
 //
 // ****************************************************************************
 
-struct BufferWriter<'a> {
-    buf: &'a mut [u8],
-    offset: usize,
-}
-
 struct Fifo<T>
 where
     T: Copy,
@@ -463,6 +137,11 @@ where
     read: u8,
 }
 
+/// Looks addresses up in `ADDRESS_MAP`. The chip driver keeps hold of its
+/// mapper, so we give it one of these rather than a reference to the map
+/// itself, leaving us free to reload the map once the cache is running.
+struct LiveMap;
+
 // ****************************************************************************
 //
 // Private Data
@@ -471,12 +150,38 @@ where
 
 entry!(main);
 
-const NUM_ENTRIES: usize = 21;
-const SZ_RT_HEADER: usize = core::mem::size_of::<rt::Header>() + (NUM_ENTRIES * 4);
+/// The most verbose level to log at start-up. Change it at run time with
+/// the `METHOD_SET_LOG_LEVEL` RPC call.
+const LOG_LEVEL: log::LevelFilter = log::LevelFilter::Info;
 
+/// Where `demo_messages::Service::Demo` responses go.
 const HOST_ID: u32 = 100;
-const REMOTE_ID: u32 = 61;
-const NAMESERVER_ID: u32 = 53;
+/// TI's IPC stack expects "rpmsg-proto" at the MessageQ port. Linux can only
+/// send to this one endpoint, so messages for the demo's own services carry
+/// a `demo_messages::Service` tag. Fragmented messages are reassembled and
+/// echoed back, fragmented again, and RPC replies go back to whoever made
+/// the call.
+const REMOTE_ID: u32 = ti_messageq::MESSAGEQ_PORT;
+
+/// Give up on a partially reassembled message if the next fragment takes
+/// longer than this. One second.
+const FRAGMENT_TIMEOUT_CYCLES: u32 = am5728::CPU_HZ;
+
+/// Our MultiProc ID. This must match the host's MultiProc configuration
+/// (HOST, IPU2, IPU1, DSP2, DSP1 on the DRA7xx).
+const MESSAGEQ_PROC_ID: u16 = 2;
+
+/// The MessageQ we create. MessageQ applications on Linux can open this by
+/// name. Every message put on it is echoed back to its reply queue.
+const MESSAGEQ_NAME: &str = "IPU1_DEMO";
+
+/// What to do with replies when the host isn't taking them fast enough.
+const TX_BACKLOG_POLICY: rpmsg::BacklogPolicy = rpmsg::BacklogPolicy::DropOldest;
+
+/// The vdev status the host sets once it has primed the vrings with buffers.
+const BUFS_PRIMED: u8 = vring::VIRTIO_CONFIG_S_ACKNOWLEDGE
+    | vring::VIRTIO_CONFIG_S_DRIVER
+    | vring::VIRTIO_CONFIG_S_DRIVER_OK;
 
 const RX_MAILBOX: am5728::MailboxLocation = am5728::MailboxLocation {
     id: am5728::MailboxId::Mailbox5,
@@ -496,6 +201,17 @@ static mut MAILBOX_FIFO: Fifo<u32> = Fifo {
     write: 0,
 };
 
+/// Replies waiting for space on the to-host ring.
+static mut TX_BACKLOG: rpmsg::Backlog = rpmsg::Backlog::new();
+
+/// Somewhere to put fragmented messages back together.
+static mut REASSEMBLY_BUFFER: [u8; 4096] = [0; 4096];
+
+/// Translates between physical and device addresses. Filled in from the
+/// resource table at the start of `main`, and again by `load_address_map`.
+/// Only use it through `LiveMap`.
+static mut ADDRESS_MAP: rt::RegionMap = rt::RegionMap::new();
+
 // ****************************************************************************
 //
 // Public Functions
@@ -503,17 +219,25 @@ static mut MAILBOX_FIFO: Fifo<u32> = Fifo {
 // ****************************************************************************
 
 fn main() -> ! {
-    let mut chip = am5728::Am5728::claim(&RESOURCE_TABLE).unwrap();
-    let t = trace::get_trace().unwrap();
-    writeln!(t, "Setup complete. Booting {:?}", version::version()).unwrap();
+    trace::init(LOG_LEVEL);
+    // The peripherals are all devmems, which is all `claim` needs.
+    unsafe {
+        ADDRESS_MAP
+            .add_table(&RESOURCE_TABLE)
+            .expect("Bad resource_table");
+    }
+    trace::start_clock(&LiveMap);
+    let mut chip = am5728::Am5728::claim(&LiveMap).unwrap();
+    load_address_map(&mut chip);
+    info!("Setup complete. Booting {:?}", version::version());
 
     // This vring is full of available buffers we can use to send
     // data back to the host.
     let ipu_to_host = unsafe {
         vring::GuestVring::new(
-            RESOURCE_TABLE.rpmsg_vring0.da,
-            RESOURCE_TABLE.rpmsg_vring0.num,
-            RESOURCE_TABLE.rpmsg_vring0.align,
+            RESOURCE_TABLE.rpmsg_vring0.da as usize,
+            RESOURCE_TABLE.rpmsg_vring0.num as usize,
+            RESOURCE_TABLE.rpmsg_vring0.align as usize,
             &address_map,
         )
     };
@@ -522,48 +246,44 @@ fn main() -> ! {
     // something with.
     let host_to_ipu = unsafe {
         vring::GuestVring::new(
-            RESOURCE_TABLE.rpmsg_vring1.da,
-            RESOURCE_TABLE.rpmsg_vring1.num,
-            RESOURCE_TABLE.rpmsg_vring1.align,
+            RESOURCE_TABLE.rpmsg_vring1.da as usize,
+            RESOURCE_TABLE.rpmsg_vring1.num as usize,
+            RESOURCE_TABLE.rpmsg_vring1.align as usize,
             &address_map,
         )
     };
 
     // Spin until status is OK
-    {
-        const BUFS_PRIMED: u8 = vring::VIRTIO_CONFIG_S_ACKNOWLEDGE
-            | vring::VIRTIO_CONFIG_S_DRIVER
-            | vring::VIRTIO_CONFIG_S_DRIVER_OK;
-        let status_ptr = &RESOURCE_TABLE.rpmsg_vdev.status as *const u8;
-        loop {
-            chip.cache_flush(
-                &RESOURCE_TABLE.rpmsg_vdev,
-                ::core::mem::size_of::<rt::Vdev>(),
-                am5728::CacheFlushMode::Invalidate,
-            );
-            // Volatile read as we're in a loop
-            let status = unsafe { ::core::ptr::read_volatile(status_ptr) };
-            writeln!(t, "Buffer status is {}", status).unwrap();
-            if status == BUFS_PRIMED {
-                break;
-            } else {
-                for _ in 0..100_000 {
-                    cortex_m::asm::nop();
-                }
+    loop {
+        let status = vdev_status(&mut chip);
+        debug!("Buffer status is {}", status);
+        if status == BUFS_PRIMED {
+            break;
+        } else {
+            for _ in 0..100_000 {
+                cortex_m::asm::nop();
             }
         }
     }
 
     chip.send_message(rpmsg::MBOX_BOOTINIT_DONE, TX_MAILBOX);
 
-    writeln!(t, "Send boot init.").unwrap();
+    info!("Send boot init.");
 
-    let mut transport = rpmsg::Transport::new(ipu_to_host, host_to_ipu);
+    // Only main uses the backlog, and main is only entered once.
+    let mut transport = rpmsg::Transport::new(ipu_to_host, host_to_ipu, unsafe { &mut TX_BACKLOG });
+    transport.set_backlog_policy(TX_BACKLOG_POLICY);
     let res = register_proto(&mut chip, &mut transport);
 
-    writeln!(t, "Registered proto {:?}", res).unwrap();
+    info!("Registered proto {:?}", res);
+
+    let mut services = services::Services::new();
+    let res = services.announce(&mut transport);
+    chip.send_message(0, TX_MAILBOX);
+
+    info!("Announced services {:?}", res);
 
-    writeln!(t, "Transport is now: {:#?}", transport).unwrap();
+    debug!("Transport is now: {:#?}", transport);
 
     chip.disable_mailbox_interrupts(RX_MAILBOX.id, RX_MAILBOX.user);
     chip.disable_mailbox_interrupts(TX_MAILBOX.id, TX_MAILBOX.user);
@@ -573,56 +293,182 @@ fn main() -> ! {
         cortex_m::interrupt::enable();
     }
 
+    let mut control = control::Control::new();
+
+    // Only main uses this buffer, and main is only entered once.
+    let mut reassembler = rpmsg_fragment::Reassembler::new(
+        unsafe { &mut REASSEMBLY_BUFFER },
+        FRAGMENT_TIMEOUT_CYCLES,
+    );
+    let mut fragment_message_id: u16 = 0;
+    let dispatcher = rpc::dispatcher();
+    let mut messageq = messageq::MessageQ::new(MESSAGEQ_PROC_ID);
+    // It's our only queue, so this can't fail
+    let demo_queue = messageq.create(MESSAGEQ_NAME).unwrap();
+    debug!("MessageQ is now: {:?}", messageq);
+
+    control.booted();
+    #[cfg(feature = "watchdog")]
+    watchdog::start(&LiveMap);
+
     let mut loops: u32 = 0;
     loop {
         loops = loops.wrapping_add(1);
+        #[cfg(feature = "watchdog")]
+        watchdog::pet(&LiveMap);
         let popped = cortex_m::interrupt::free(|_cs| unsafe { MAILBOX_FIFO.pop() });
         if let Some(msg) = popped {
             if (loops % 1000) == 0 {
-                writeln!(t, "Rx message {}", loops).unwrap();
+                ddebug!("Rx message {}", loops);
+                debug!("Backlog: {:?}", transport.backlog_stats());
+                debug!("Reassembly: {:?}", reassembler.stats());
             }
-            match msg {
-                rpmsg::MBOX_READY => {
-                    writeln!(t, "{}: Ready received.", loops).unwrap();
-                }
-                rpmsg::MBOX_ECHO_REQUEST => {
-                    writeln!(t, "{}: Echo request received, sending reply.", loops).unwrap();
-                    chip.send_message(rpmsg::MBOX_ECHO_REPLY, TX_MAILBOX);
+            let receive = match control.handle(msg) {
+                Some(control::Action::None) => false,
+                Some(control::Action::Reply(reply)) => {
+                    chip.send_message(reply, TX_MAILBOX);
+                    false
                 }
-                rpmsg::MBOX_FLUSH_CACHE => {
-                    writeln!(t, "{}: Cache flush request received.", loops).unwrap();
+                Some(control::Action::FlushCache) => {
                     chip.cache_flush_all(am5728::CacheFlushAllMode::WriteBack);
+                    false
                 }
-                1 => {
-                    let res_rx = transport.receive(|mut tx, _header, _payload| {
-                        // writeln!(t, "Got: {:?}, {:x?}", _header, _payload).unwrap();
-                        let mut buffer = [0u8; 64];
-                        {
-                            let mut writer = BufferWriter::new(&mut buffer);
-                            write!(writer, "Response to message {}", loops).unwrap();
-                        }
-                        tx.send(REMOTE_ID, HOST_ID, &buffer)
-                            .expect("Failed to send");
-                        chip.send_message(0, TX_MAILBOX);
-                    });
-                    match res_rx {
-                        Ok(()) => {
-                            // writeln!(t, "{}: Message processed", loops).unwrap();
-                        }
-                        Err(rpmsg::Error::Empty) => {
-                            writeln!(t, "{}: Queue empty??", loops).unwrap();
-                        }
-                        Err(e) => {
-                            writeln!(t, "{}: Transport error: {:?}", loops, e).unwrap();
+                Some(control::Action::DrainRx) => true,
+                Some(control::Action::Shutdown) => {
+                    shutdown(&mut chip, &mut transport, &mut control);
+                }
+                None => match msg {
+                    1 => true,
+                    0 => {
+                        // Letting us know about space on the to-host ring.
+                        // This is also the first thing we hear after the
+                        // host restarts its virtio bus, at which point it
+                        // has forgotten our services.
+                        if transport.is_reinitialised() && vdev_status(&mut chip) == BUFS_PRIMED {
+                            dinfo!("{}: Host vrings re-initialised.", loops);
+                            transport.reset();
+                            let res = transport.reannounce();
+                            chip.send_message(0, TX_MAILBOX);
+                            info!("{}: Re-announced services {:?}", loops, res);
+                        } else if transport.flush() != 0 {
+                            chip.send_message(0, TX_MAILBOX);
                         }
+                        false
+                    }
+                    m => {
+                        dwarn!("{}: Unexpected message ID 0x{:08x}.", loops, m);
+                        false
+                    }
+                },
+            };
+            // A new-message notification means one message; a drain means
+            // keep going until the ring is empty.
+            while receive {
+                let mut res_tx = Ok(());
+                let mut res_mq = Ok(());
+                let now = chip.cycle_count();
+                let res_rx = transport.receive(|mut tx, header, payload| {
+                    trace!("Got: {:?}, {:x?}", header, payload);
+                    match header.destination {
+                        REMOTE_ID => match demo_messages::Service::untag(payload) {
+                            Some((demo_messages::Service::Fragment, body)) => {
+                                res_tx = match reassembler.push(body, now) {
+                                    Ok(Some(message)) => {
+                                        fragment_message_id = fragment_message_id.wrapping_add(1);
+                                        tx.send_fragmented(
+                                            REMOTE_ID,
+                                            header.source,
+                                            fragment_message_id,
+                                            message,
+                                        )
+                                    }
+                                    Ok(None) => Ok(()),
+                                    Err(e) => Err(e.into()),
+                                };
+                            }
+                            Some((demo_messages::Service::Rpc, body)) => {
+                                let mut buffer = [0u8; rpmsg_rpc::MAX_MESSAGE_LEN];
+                                match dispatcher.dispatch(&mut (), body, &mut buffer) {
+                                    Ok(len) => {
+                                        res_tx = tx.send_bytes(
+                                            REMOTE_ID,
+                                            header.source,
+                                            &buffer[0..len],
+                                        );
+                                    }
+                                    Err(e) => {
+                                        warn!("{}: Bad RPC call: {:?}", loops, e);
+                                    }
+                                }
+                            }
+                            Some((demo_messages::Service::Demo, body)) => {
+                                match demo_messages::Message::decode(body) {
+                                    Ok(demo_messages::Message::TestRequest(request)) => {
+                                        let response = demo_messages::Message::TestResponse(
+                                            demo_messages::TestResponse {
+                                                sequence: loops,
+                                                request,
+                                            },
+                                        );
+                                        let mut buffer = [0u8; demo_messages::MAX_MESSAGE_LEN];
+                                        // Our buffer is always big enough
+                                        let len = response.encode(&mut buffer).unwrap();
+                                        res_tx = tx.send_bytes(REMOTE_ID, HOST_ID, &buffer[0..len]);
+                                    }
+                                    Ok(m) => {
+                                        warn!("{}: Unexpected message {:?}", loops, m.kind());
+                                    }
+                                    Err(e) => {
+                                        warn!("{}: Bad message: {:?}", loops, e);
+                                    }
+                                }
+                            }
+                            None if ti_messageq::is_name_server_msg(payload) => {
+                                res_mq = messageq.handle_name_server(&mut tx, payload);
+                            }
+                            None => {
+                                res_mq = messageq.handle_message(payload).map(|_| ());
+                            }
+                        },
+                        d => match services.handle(&mut tx, header, payload) {
+                            Some(res) => res_tx = res,
+                            None => {
+                                dwarn!("{}: Message for unknown address {}", loops, d);
+                            }
+                        },
                     }
+                    chip.send_message(0, TX_MAILBOX);
+                });
+                if let Err(e) = res_tx {
+                    warn!("{}: Failed to send reply: {:?}", loops, e);
                 }
-                0 => {
-                    // Ignore - letting us know about space on the to-host ring
-                    // writeln!(t, "{}: Ignoring space indication.", loops).unwrap();
+                if let Err(e) = res_mq {
+                    warn!("{}: MessageQ error: {:?}", loops, e);
                 }
-                m => {
-                    writeln!(t, "{}: Unexpected message ID 0x{:08x}.", loops, m).unwrap();
+                while let Some(msg) = messageq.get(demo_queue) {
+                    let res = messageq.reply(&mut transport, &msg, msg.header.msg_id, msg.body());
+                    if let Err(e) = res {
+                        warn!("{}: MessageQ reply failed: {:?}", loops, e);
+                    }
+                    chip.send_message(0, TX_MAILBOX);
+                }
+                match res_rx {
+                    Ok(()) if control.state() == control::State::Draining => {}
+                    Ok(()) => break,
+                    Err(rpmsg::Error::Empty) => {
+                        if control.state() == control::State::Draining {
+                            control.drained();
+                        } else {
+                            dwarn!("{}: Queue empty??", loops);
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        // The rings are corrupt, so only the host can fix
+                        // things now.
+                        error!("{}: Transport error: {:?}", loops, e);
+                        crash(&mut chip, &mut control, "transport error");
+                    }
                 }
             }
         } else {
@@ -646,20 +492,102 @@ fn register_proto<T>(
 where
     T: rt::AddressMapper,
 {
-    let msg = rpmsg::NameServiceAnnounce::new(
-        "rpmsg-proto",
-        "rpmsg-proto",
-        REMOTE_ID,
-        rpmsg::NameServiceAnnounceFlags::Create,
-    );
-    let res = transport.send(REMOTE_ID, NAMESERVER_ID, &msg);
+    let res = transport.announce("rpmsg-proto", "rpmsg-proto", REMOTE_ID);
     chip.send_message(0, TX_MAILBOX);
     res
 }
 
+/// The kernel writes the physical address of each carveout it allocates
+/// into the copy of the resource table it loaded for us. Throw away any
+/// cached copy of the table and rebuild the address map from it, so that
+/// we can translate addresses in our own carveouts.
+fn load_address_map<T>(chip: &mut am5728::Am5728<T>)
+where
+    T: rt::AddressMapper,
+{
+    chip.cache_flush(
+        &RESOURCE_TABLE,
+        ::core::mem::size_of::<resources::ResourceTable>(),
+        am5728::CacheFlushMode::Invalidate,
+    );
+    // The mailbox interrupt uses the map too
+    cortex_m::interrupt::free(|_cs| unsafe {
+        ADDRESS_MAP.clear();
+        ADDRESS_MAP
+            .add_table(&RESOURCE_TABLE)
+            .expect("Bad resource_table");
+    });
+}
+
+/// Read the status byte the host writes in to our vdev resource.
+fn vdev_status<T>(chip: &mut am5728::Am5728<T>) -> u8
+where
+    T: rt::AddressMapper,
+{
+    chip.cache_flush(
+        &RESOURCE_TABLE.rpmsg_vdev,
+        ::core::mem::size_of::<rt::Vdev>(),
+        am5728::CacheFlushMode::Invalidate,
+    );
+    // Volatile read as the host changes this behind our back
+    unsafe { ::core::ptr::read_volatile(&RESOURCE_TABLE.rpmsg_vdev.status) }
+}
+
+/// The host has asked us to stop. Withdraw our services so the host's
+/// channel list is accurate, then park the CPU.
+fn shutdown<T>(
+    chip: &mut am5728::Am5728<T>,
+    transport: &mut rpmsg::Transport,
+    control: &mut control::Control,
+) -> !
+where
+    T: rt::AddressMapper,
+{
+    let res = transport.withdraw_all();
+    chip.send_message(0, TX_MAILBOX);
+    info!("Withdrew services {:?}. Halting.", res);
+    control.stopped();
+    #[cfg(feature = "watchdog")]
+    watchdog::stop(&LiveMap);
+    halt();
+}
+
+/// Something has gone wrong that we can't recover from. Tell the host, so
+/// it can restart us, unless `crash::ACTION` says not to.
+fn crash<T>(chip: &mut am5728::Am5728<T>, control: &mut control::Control, reason: &str) -> !
+where
+    T: rt::AddressMapper,
+{
+    let msg = control.fatal(reason);
+    if crash::ACTION != crash::Action::Halt {
+        // The host may have stopped reading the mailbox, so don't wait
+        chip.try_send_message(msg, TX_MAILBOX);
+    }
+    after_crash();
+}
+
+/// The host knows, if it's going to, so halt or reset as `crash::ACTION`
+/// says. The watchdog would only restart us again.
+fn after_crash() -> ! {
+    #[cfg(feature = "watchdog")]
+    watchdog::stop(&LiveMap);
+    if crash::ACTION == crash::Action::NotifyAndReset {
+        crash::reset(&LiveMap);
+    }
+    halt();
+}
+
+/// Park the CPU for good.
+fn halt() -> ! {
+    cortex_m::interrupt::disable();
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
 // Convert the addresses in the vring to addresses we can actually read
 fn address_map(physical_address: u64) -> u64 {
-    RESOURCE_TABLE.pa_to_da(physical_address as usize).unwrap() as u64
+    LiveMap.pa_to_da(physical_address as usize).unwrap() as u64
 }
 
 // define the hard fault handler
@@ -667,7 +595,8 @@ exception!(HardFault, hard_fault);
 
 /// Our default hard fault handler
 fn hard_fault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
-    panic!("HardFault at {:#?}", ef);
+    crash::hard_fault(ef);
+    notify_crash();
 }
 
 // define the default exception handler
@@ -683,8 +612,11 @@ fn mailbox_isr() {
     unsafe {
         // We have to do the read in the interrupt otherwise we'll bounce straight back in to this ISR
         let mailbox =
-            am5728::get_mailbox(RX_MAILBOX.id, &RESOURCE_TABLE).expect("Bad resource_table in IRQ");
+            am5728::get_mailbox(RX_MAILBOX.id, &LiveMap).expect("Bad resource_table in IRQ");
         if let Some(id) = mailbox.get_message(RX_MAILBOX.slot) {
+            if MAILBOX_FIFO.is_full() {
+                dwarn!("Mailbox FIFO full, overwriting with 0x{:08x}", id);
+            }
             MAILBOX_FIFO.push(id);
             // Clear the interrupt flag
             mailbox.clear_interrupts(RX_MAILBOX.user);
@@ -695,97 +627,32 @@ fn mailbox_isr() {
     };
 }
 
-impl rt::AddressMapper for ResourceTable {
-    /// Convert a physical address (e.g. an L3/L4 address) to a device address the Cortex-M4 can use.
-    fn pa_to_da(&self, given_pa: usize) -> Option<usize> {
-        let regions: &[&Region] = &[
-            &self.text_cout,
-            &self.data_cout,
-            &self.devmem0,
-            &self.devmem1,
-            &self.devmem2,
-            &self.devmem3,
-            &self.devmem4,
-            &self.devmem5,
-            &self.devmem6,
-            &self.devmem7,
-            &self.devmem8,
-            &self.devmem9,
-            &self.devmem10,
-            &self.devmem11,
-            &self.devmem12,
-            &self.devmem13,
-            &self.devmem14,
-            &self.devmem15,
-        ];
-        for region in regions {
-            if let Some(da) = region.pa_to_da(given_pa) {
-                return Some(da);
-            }
-        }
-        None
-    }
-
-    /// Convert a device address the Cortex-M4 can use to a physical address (e.g. an L3/L4 address).
-    fn da_to_pa(&self, given_da: usize) -> Option<usize> {
-        let regions: &[&Region] = &[
-            &self.text_cout,
-            &self.data_cout,
-            &self.devmem0,
-            &self.devmem1,
-            &self.devmem2,
-            &self.devmem3,
-            &self.devmem4,
-            &self.devmem5,
-            &self.devmem6,
-            &self.devmem7,
-            &self.devmem8,
-            &self.devmem9,
-            &self.devmem10,
-            &self.devmem11,
-            &self.devmem12,
-            &self.devmem13,
-            &self.devmem14,
-            &self.devmem15,
-        ];
-        for region in regions {
-            if let Some(pa) = region.da_to_pa(given_da) {
-                return Some(pa);
-            }
-        }
-        None
-    }
-}
-
 #[panic_handler]
 #[inline(never)]
 pub fn panic(info: &PanicInfo) -> ! {
-    let mut t = unsafe { trace::steal_trace() };
-    let _ = writeln!(t, "*** SYSTEM PANIC!: {:?}", info);
-    loop {}
+    crash::panic(info);
+    notify_crash();
 }
 
-impl<'a> BufferWriter<'a> {
-    fn new(buf: &'a mut [u8]) -> Self {
-        BufferWriter {
-            buf: buf,
-            offset: 0,
+/// Tell the host we've crashed, unless `crash::ACTION` says not to. We may
+/// have crashed inside `send_message`, so don't wait for the host to make
+/// room.
+fn notify_crash() -> ! {
+    if crash::ACTION != crash::Action::Halt {
+        if let Some(mailbox) = am5728::get_mailbox(TX_MAILBOX.id, &LiveMap) {
+            mailbox.try_put_message(TX_MAILBOX.slot, rpmsg::MBOX_CRASH);
         }
     }
+    after_crash();
 }
 
-/// From https://stackoverflow.com/questions/39488327/how-to-write-an-integer-as-a-string-to-a-byte-array-with-no-std
-impl<'a> ::core::fmt::Write for BufferWriter<'a> {
-    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
-        let bytes = s.as_bytes();
-        let buffer_len = self.buf.len();
-        let space = &mut self.buf[self.offset..];
-        let to_fill = &mut space[..bytes.len()];
-        to_fill.copy_from_slice(bytes);
-
-        self.offset += bytes.len().min(buffer_len);
+impl rt::AddressMapper for LiveMap {
+    fn pa_to_da(&self, pa: usize) -> Option<usize> {
+        unsafe { ADDRESS_MAP.pa_to_da(pa) }
+    }
 
-        Ok(())
+    fn da_to_pa(&self, da: usize) -> Option<usize> {
+        unsafe { ADDRESS_MAP.da_to_pa(da) }
     }
 }
 
@@ -799,6 +666,10 @@ where
         self.write = self.write.wrapping_add(1);
     }
 
+    pub fn is_full(&self) -> bool {
+        self.write.wrapping_sub(self.read) as usize >= self.storage.len()
+    }
+
     pub fn pop(&mut self) -> Option<T> {
         if self.read == self.write {
             None
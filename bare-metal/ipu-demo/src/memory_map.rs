@@ -0,0 +1,99 @@
+//! # AM5728 Memory Map
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! The physical (L3/L4) addresses of the memories and peripheral regions
+//! we ask the host to map for us. These live apart from the drivers in
+//! `am5728` so that the resource table can be checked on the host.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+pub const L3_OCMC_RAM: usize = 0x4030_0000;
+pub const L4_PERIPHERAL_L4PER1: usize = 0x4800_0000;
+pub const L4_PERIPHERAL_L4PER2: usize = 0x4840_0000;
+pub const L4_PERIPHERAL_L4PER3: usize = 0x4880_0000;
+pub const L4_PERIPHERAL_L4CFG: usize = 0x4A00_0000;
+pub const L3_PERIPHERAL_PRUSS: usize = 0x4B20_0000;
+pub const L3_PERIPHERAL_DMM: usize = 0x4E00_0000;
+pub const L4_PERIPHERAL_L4EMU: usize = 0x5400_0000;
+pub const L3_IVAHD_CONFIG: usize = 0x5A00_0000;
+pub const L3_IVAHD_SL2: usize = 0x5B00_0000;
+pub const L3_TILER_MODE_0_1: usize = 0x6000_0000;
+pub const L3_TILER_MODE_2: usize = 0x7000_0000;
+pub const L3_TILER_MODE_3: usize = 0x7800_0000;
+pub const L3_EMIF_SDRAM: usize = 0xA000_0000;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
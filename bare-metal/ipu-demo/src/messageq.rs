@@ -0,0 +1,394 @@
+//! # MessageQ
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! A subset of TI's MessageQ, compatible with IPC 3.x on Linux. We own a
+//! small table of named queues. Messages from Linux arrive on the
+//! `rpmsg-proto` endpoint and are placed in the inbox of the queue named in
+//! their header. Linux finds our queues by sending NameServer requests to
+//! the same endpoint, which we answer from the same table.
+//!
+//! Each queue holds a few messages. If the application doesn't `get` them
+//! fast enough, new messages are dropped.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::rpmsg::{self, SendMessage, String32};
+use resource_table::string;
+use ti_messageq::{self, NameServerKind, NameServerMsg};
+pub use ti_messageq::{MsgHeader, QueueId};
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// Our queues.
+pub struct MessageQ {
+    /// Our MultiProc ID, which must match the host's MultiProc configuration
+    proc_id: u16,
+    queues: [Option<Queue>; MAX_QUEUES],
+    seq_num: u16,
+}
+
+/// A received message.
+#[derive(Clone, Copy)]
+pub struct Message {
+    pub header: MsgHeader,
+    len: usize,
+    data: [u8; MAX_BODY_LEN],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error {
+    /// There are already `MAX_QUEUES` queues
+    TooManyQueues,
+    /// There is already a queue with that name
+    NameInUse,
+    /// Queue names must fit in a `String32`
+    NameTooLong,
+    /// The queue doesn't exist
+    NoSuchQueue,
+    /// The queue's inbox was full, so the message was dropped
+    QueueFull,
+    /// The message has no reply queue
+    NoReplyQueue,
+    /// The body won't fit in an rpmsg buffer
+    TooLarge,
+    /// The message was malformed
+    Codec(ti_messageq::Error),
+    /// The message couldn't be sent
+    Transport(rpmsg::Error),
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// How many queues we can have.
+pub const MAX_QUEUES: usize = 4;
+
+/// How many messages each queue can hold.
+pub const INBOX_LEN: usize = 4;
+
+/// The largest message body, given the rpmsg buffer size.
+pub const MAX_BODY_LEN: usize = rpmsg::MAX_PAYLOAD_LEN - ti_messageq::HEADER_LEN;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+#[derive(Clone, Copy)]
+struct Queue {
+    name: String32,
+    inbox: [Message; INBOX_LEN],
+    read: usize,
+    count: usize,
+    dropped: u32,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl MessageQ {
+    pub fn new(proc_id: u16) -> MessageQ {
+        MessageQ {
+            proc_id,
+            queues: [None; MAX_QUEUES],
+            seq_num: 0,
+        }
+    }
+
+    /// Create a named queue, as per `MessageQ_create`.
+    pub fn create(&mut self, name: &str) -> Result<QueueId, Error> {
+        if name.len() > string::BUFFER_LEN {
+            return Err(Error::NameTooLong);
+        }
+        if self.open(name).is_some() {
+            return Err(Error::NameInUse);
+        }
+        let idx = self
+            .queues
+            .iter()
+            .position(|q| q.is_none())
+            .ok_or(Error::TooManyQueues)?;
+        self.queues[idx] = Some(Queue {
+            name: String32::from(name),
+            inbox: [Message::empty(); INBOX_LEN],
+            read: 0,
+            count: 0,
+            dropped: 0,
+        });
+        Ok(QueueId::new(self.proc_id, idx as u16))
+    }
+
+    /// Delete a queue, and any messages in it.
+    pub fn delete(&mut self, queue: QueueId) -> Result<(), Error> {
+        let idx = self.local_index(queue).ok_or(Error::NoSuchQueue)?;
+        self.queues[idx] = None;
+        Ok(())
+    }
+
+    /// Find one of our queues by name, as per `MessageQ_open`.
+    pub fn open(&self, name: &str) -> Option<QueueId> {
+        if name.len() > string::BUFFER_LEN {
+            return None;
+        }
+        let wanted = String32::from(name);
+        self.queues
+            .iter()
+            .position(|q| match *q {
+                Some(ref q) => q.name.buffer == wanted.buffer,
+                None => false,
+            })
+            .map(|idx| QueueId::new(self.proc_id, idx as u16))
+    }
+
+    /// Take the oldest message from one of our queues, as per
+    /// `MessageQ_get`, except we don't block.
+    pub fn get(&mut self, queue: QueueId) -> Option<Message> {
+        let idx = self.local_index(queue)?;
+        match self.queues[idx] {
+            Some(ref mut q) if q.count != 0 => {
+                let msg = q.inbox[q.read];
+                q.read = (q.read + 1) % INBOX_LEN;
+                q.count -= 1;
+                Some(msg)
+            }
+            _ => None,
+        }
+    }
+
+    /// How many messages a queue has dropped because its inbox was full.
+    pub fn dropped(&self, queue: QueueId) -> u32 {
+        match self.local_index(queue) {
+            Some(idx) => self.queues[idx].as_ref().map(|q| q.dropped).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    /// Send a message to any queue, as per `MessageQ_put`. Messages for our
+    /// own queues don't go near rpmsg.
+    pub fn put<S>(
+        &mut self,
+        tx: &mut S,
+        dst: QueueId,
+        msg_id: u16,
+        reply: Option<QueueId>,
+        body: &[u8],
+    ) -> Result<(), Error>
+    where
+        S: SendMessage,
+    {
+        if body.len() > MAX_BODY_LEN {
+            return Err(Error::TooLarge);
+        }
+        let mut header = MsgHeader::new(msg_id, self.proc_id, dst, body.len());
+        if let Some(reply) = reply {
+            header.set_reply_queue(reply);
+        }
+        header.seq_num = self.seq_num;
+        self.seq_num = self.seq_num.wrapping_add(1);
+        if dst.proc_id() == self.proc_id {
+            self.deliver(&header, body)
+        } else {
+            let mut buffer = [0u8; rpmsg::MAX_PAYLOAD_LEN];
+            let len = ti_messageq::HEADER_LEN + body.len();
+            header.encode(&mut buffer)?;
+            buffer[ti_messageq::HEADER_LEN..len].copy_from_slice(body);
+            // Linux queues listen on the rpmsg port given by their index
+            tx.send_bytes(
+                ti_messageq::MESSAGEQ_PORT,
+                dst.index() as u32,
+                &buffer[0..len],
+            )?;
+            Ok(())
+        }
+    }
+
+    /// Send a message to the reply queue of a message we received.
+    pub fn reply<S>(
+        &mut self,
+        tx: &mut S,
+        request: &Message,
+        msg_id: u16,
+        body: &[u8],
+    ) -> Result<(), Error>
+    where
+        S: SendMessage,
+    {
+        let dst = request.header.reply_queue().ok_or(Error::NoReplyQueue)?;
+        self.put(tx, dst, msg_id, None, body)
+    }
+
+    /// Handle an rpmsg message sent to `MESSAGEQ_PORT`. Returns the queue the
+    /// message was placed in.
+    pub fn handle_message(&mut self, payload: &[u8]) -> Result<QueueId, Error> {
+        let header = MsgHeader::decode(payload)?;
+        let body_len = header.body_len();
+        if ti_messageq::HEADER_LEN + body_len > payload.len() {
+            return Err(Error::Codec(ti_messageq::Error::TooShort));
+        }
+        let body = &payload[ti_messageq::HEADER_LEN..ti_messageq::HEADER_LEN + body_len];
+        self.deliver(&header, body)?;
+        Ok(header.dst_queue())
+    }
+
+    /// Handle a NameServer request sent to `MESSAGEQ_PORT` (see
+    /// `ti_messageq::is_name_server_msg`). The response goes to
+    /// `NAME_SERVER_ADDR` on Linux, not to whoever sent the request, as
+    /// that's where LAD listens for it.
+    pub fn handle_name_server<S>(&self, tx: &mut S, payload: &[u8]) -> Result<(), Error>
+    where
+        S: SendMessage,
+    {
+        let request = NameServerMsg::decode(payload)?;
+        if request.kind != NameServerKind::Request {
+            // We never make requests, so we don't expect responses
+            return Ok(());
+        }
+        let value = if request.instance == ti_messageq::MESSAGEQ_INSTANCE {
+            self.open(request.name).map(|q| q.0)
+        } else {
+            None
+        };
+        let mut buffer = [0u8; ti_messageq::NAME_SERVER_MSG_LEN];
+        let len = request.response(value).encode(&mut buffer)?;
+        tx.send_bytes(
+            ti_messageq::MESSAGEQ_PORT,
+            ti_messageq::NAME_SERVER_ADDR,
+            &buffer[0..len],
+        )?;
+        Ok(())
+    }
+
+    /// Convert a queue ID to an index in our table, if it's one of ours.
+    fn local_index(&self, queue: QueueId) -> Option<usize> {
+        let idx = queue.index() as usize;
+        if (queue.proc_id() == self.proc_id) && (idx < MAX_QUEUES) && self.queues[idx].is_some() {
+            Some(idx)
+        } else {
+            None
+        }
+    }
+
+    /// Put a message in one of our queues.
+    fn deliver(&mut self, header: &MsgHeader, body: &[u8]) -> Result<(), Error> {
+        if body.len() > MAX_BODY_LEN {
+            return Err(Error::TooLarge);
+        }
+        let idx = self
+            .local_index(header.dst_queue())
+            .ok_or(Error::NoSuchQueue)?;
+        match self.queues[idx] {
+            Some(ref mut q) => {
+                if q.count == INBOX_LEN {
+                    q.dropped = q.dropped.wrapping_add(1);
+                    return Err(Error::QueueFull);
+                }
+                let msg = &mut q.inbox[(q.read + q.count) % INBOX_LEN];
+                msg.header = *header;
+                msg.len = body.len();
+                msg.data[0..body.len()].copy_from_slice(body);
+                q.count += 1;
+                Ok(())
+            }
+            None => Err(Error::NoSuchQueue),
+        }
+    }
+}
+
+impl ::core::fmt::Debug for MessageQ {
+    fn fmt(&self, fmt: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        let mut list = fmt.debug_list();
+        for (idx, q) in self.queues.iter().enumerate() {
+            if let Some(ref q) = *q {
+                list.entry(&(
+                    QueueId::new(self.proc_id, idx as u16),
+                    q.name,
+                    q.count,
+                    q.dropped,
+                ));
+            }
+        }
+        list.finish()
+    }
+}
+
+impl Message {
+    /// The message body, after the header.
+    pub fn body(&self) -> &[u8] {
+        &self.data[0..self.len]
+    }
+
+    fn empty() -> Message {
+        Message {
+            header: MsgHeader::new(0, 0, QueueId(0), 0),
+            len: 0,
+            data: [0u8; MAX_BODY_LEN],
+        }
+    }
+}
+
+impl From<ti_messageq::Error> for Error {
+    fn from(e: ti_messageq::Error) -> Error {
+        Error::Codec(e)
+    }
+}
+
+impl From<rpmsg::Error> for Error {
+    fn from(e: rpmsg::Error) -> Error {
+        Error::Transport(e)
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,88 @@
+//! # Resource Table
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! The resource table for this application, which the kernel reads out of
+//! our ELF file before booting us. The table itself is generated by
+//! `build.rs` from `resources.toml`. It has no dependencies on the rest of
+//! the firmware, so `host-tests` can include it and check it on the host.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use memory_map;
+use resource_table as rt;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+include!(concat!(env!("OUT_DIR"), "/resource_table.rs"));
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
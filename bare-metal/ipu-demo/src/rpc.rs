@@ -0,0 +1,158 @@
+//! # RPC Handlers
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! The methods we offer on the RPC endpoint. The method IDs live in the
+//! `demo-messages` crate, so Linux can use them too.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use demo_messages;
+use log::LevelFilter;
+use rpmsg_rpc::{Dispatcher, Status};
+use trace;
+use version;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Build a dispatcher with all our handlers registered.
+pub fn dispatcher() -> Dispatcher<()> {
+    let mut dispatcher = Dispatcher::new();
+    // We've got far fewer than `MAX_HANDLERS`, and they're all different
+    dispatcher
+        .register(demo_messages::METHOD_ECHO, echo)
+        .unwrap();
+    dispatcher.register(demo_messages::METHOD_ADD, add).unwrap();
+    dispatcher
+        .register(demo_messages::METHOD_VERSION, get_version)
+        .unwrap();
+    dispatcher
+        .register(demo_messages::METHOD_SET_LOG_LEVEL, set_log_level)
+        .unwrap();
+    dispatcher
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+fn echo(_context: &mut (), request: &[u8], reply: &mut [u8]) -> Result<usize, Status> {
+    copy_to(reply, request)
+}
+
+fn add(_context: &mut (), request: &[u8], reply: &mut [u8]) -> Result<usize, Status> {
+    let values = request.chunks_exact(4);
+    if !values.remainder().is_empty() {
+        return Err(Status::BadRequest);
+    }
+    let sum = values.fold(0u32, |acc, b| {
+        let value =
+            (b[0] as u32) | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24);
+        acc.wrapping_add(value)
+    });
+    copy_to(
+        reply,
+        &[
+            sum as u8,
+            (sum >> 8) as u8,
+            (sum >> 16) as u8,
+            (sum >> 24) as u8,
+        ],
+    )
+}
+
+fn get_version(_context: &mut (), _request: &[u8], reply: &mut [u8]) -> Result<usize, Status> {
+    copy_to(reply, version::version().as_bytes())
+}
+
+fn set_log_level(_context: &mut (), request: &[u8], _reply: &mut [u8]) -> Result<usize, Status> {
+    let level = match request.first() {
+        Some(0) => LevelFilter::Off,
+        Some(1) => LevelFilter::Error,
+        Some(2) => LevelFilter::Warn,
+        Some(3) => LevelFilter::Info,
+        Some(4) => LevelFilter::Debug,
+        Some(5) => LevelFilter::Trace,
+        _ => return Err(Status::BadRequest),
+    };
+    let module = ::core::str::from_utf8(&request[1..]).map_err(|_| Status::BadRequest)?;
+    if module.is_empty() {
+        trace::set_default_level(level);
+    } else if !trace::set_module_level(module, level) {
+        return Err(Status::Failed);
+    }
+    Ok(0)
+}
+
+fn copy_to(reply: &mut [u8], data: &[u8]) -> Result<usize, Status> {
+    if data.len() > reply.len() {
+        return Err(Status::Failed);
+    }
+    reply[0..data.len()].copy_from_slice(data);
+    Ok(data.len())
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
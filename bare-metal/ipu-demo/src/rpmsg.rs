@@ -19,7 +19,8 @@
 //
 // ****************************************************************************
 
-pub use super::string::String32;
+pub use resource_table::String32;
+use rpmsg_fragment;
 use vring;
 
 // ****************************************************************************
@@ -45,12 +46,69 @@ use vring;
 // ****************************************************************************
 
 pub struct Transport {
-    send_channel: vring::GuestVring,
+    sender: Sender,
     receive_channel: vring::GuestVring,
+    services: [Option<Service>; MAX_SERVICES],
+}
+
+/// What to do with a new outgoing message when the to-host ring has no free
+/// buffers and the backlog is also full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BacklogPolicy {
+    /// Throw away the oldest message in the backlog to make room.
+    DropOldest,
+    /// Throw away the new message.
+    DropNewest,
+    /// Spin until the host frees up a buffer, for up to `BLOCK_LIMIT`
+    /// attempts, then throw away the new message.
+    Block,
+}
+
+/// Counters for the transmit backlog.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BacklogStats {
+    /// Messages which went in to the backlog because the ring was full
+    pub queued: u32,
+    /// Messages which were later sent from the backlog
+    pub flushed: u32,
+    /// Messages lost under `BacklogPolicy::DropOldest`
+    pub dropped_oldest: u32,
+    /// Messages lost under `BacklogPolicy::DropNewest`
+    pub dropped_newest: u32,
+    /// Times we had to spin under `BacklogPolicy::Block`
+    pub blocked: u32,
+    /// Messages lost because we gave up spinning under
+    /// `BacklogPolicy::Block`
+    pub block_timeouts: u32,
+    /// Messages lost from the backlog because the ring rejected them for
+    /// some reason other than being full
+    pub failed: u32,
+}
+
+/// A fixed-size FIFO of messages waiting for space on the to-host ring. It
+/// holds `BACKLOG_LEN` full-sized messages, which is too much for the stack,
+/// so give `Transport::new` a static one.
+pub struct Backlog {
+    entries: [BacklogEntry; BACKLOG_LEN],
+    read: usize,
+    count: usize,
+}
+
+/// A service we have announced to the host's name server. We remember these
+/// so we can withdraw them later, or announce them again if the host's
+/// virtio bus is restarted.
+#[derive(Debug, Clone, Copy)]
+pub struct Service {
+    /// name of the service, which selects the kernel driver
+    pub name: String32,
+    /// description of the service
+    pub description: String32,
+    /// the local endpoint address the service listens on
+    pub address: u32,
 }
 
 /// All RemoteProc messages start with this header.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct Header {
     pub source: u32,
@@ -91,6 +149,17 @@ pub enum NameServiceAnnounceFlags {
 pub enum Error {
     Empty,
     Vring(vring::Error),
+    /// There are already `MAX_SERVICES` services registered
+    TooManyServices,
+    /// No service is registered at the given address
+    UnknownService,
+    /// The message would not fit in an rpmsg buffer
+    PayloadTooLarge,
+    /// The to-host ring and the backlog were both full, so the message was
+    /// discarded
+    Dropped,
+    /// A fragmented message could not be split or reassembled
+    Fragment(rpmsg_fragment::Error),
 }
 
 impl From<vring::Error> for Error {
@@ -102,6 +171,12 @@ impl From<vring::Error> for Error {
     }
 }
 
+impl From<rpmsg_fragment::Error> for Error {
+    fn from(e: rpmsg_fragment::Error) -> Error {
+        Error::Fragment(e)
+    }
+}
+
 // ****************************************************************************
 //
 // Public Data
@@ -121,13 +196,49 @@ pub const MBOX_HIBERNATION_FORCE: u32 = 0xFFFFFF11;
 pub const MBOX_HIBERNATION_ACK: u32 = 0xFFFFFF12;
 pub const MBOX_HIBERNATION_CANCEL: u32 = 0xFFFFFF13;
 
+/// The well-known address of the host's name service. Announcements are
+/// sent here.
+pub const NAME_SERVICE_ADDRESS: u32 = 53;
+
+/// The maximum number of services we can have registered at once.
+pub const MAX_SERVICES: usize = 8;
+
+/// The size of every buffer the host gives us, including the `Header`.
+pub const BUFFER_SIZE: usize = 512;
+
+/// The largest payload which fits in a buffer after the `Header`.
+pub const MAX_PAYLOAD_LEN: usize = BUFFER_SIZE - ::core::mem::size_of::<Header>();
+
+/// The number of outgoing messages we can hold while the to-host ring is
+/// full.
+pub const BACKLOG_LEN: usize = 16;
+
+/// How many times `BacklogPolicy::Block` tries to flush the backlog before
+/// giving up. The host may never free up a buffer, for example if it has
+/// crashed.
+pub const BLOCK_LIMIT: u32 = 1_000_000;
+
 // ****************************************************************************
 //
 // Private Types / Traits
 //
 // ****************************************************************************
 
-// None
+/// The transmit half of a `Transport`. Messages go straight on to the vring
+/// if there's space, otherwise they wait in the backlog.
+struct Sender {
+    channel: vring::GuestVring,
+    backlog: &'static mut Backlog,
+    policy: BacklogPolicy,
+    stats: BacklogStats,
+}
+
+#[derive(Clone, Copy)]
+struct BacklogEntry {
+    header: Header,
+    len: usize,
+    data: [u8; MAX_PAYLOAD_LEN],
+}
 
 // ****************************************************************************
 //
@@ -135,7 +246,17 @@ pub const MBOX_HIBERNATION_CANCEL: u32 = 0xFFFFFF13;
 //
 // ****************************************************************************
 
-// None
+const EMPTY_ENTRY: BacklogEntry = BacklogEntry {
+    header: Header {
+        source: 0,
+        destination: 0,
+        _reserved: 0,
+        length: 0,
+        flags: 0,
+    },
+    len: 0,
+    data: [0u8; MAX_PAYLOAD_LEN],
+};
 
 // ****************************************************************************
 //
@@ -145,55 +266,199 @@ pub const MBOX_HIBERNATION_CANCEL: u32 = 0xFFFFFF13;
 
 impl ::core::fmt::Debug for Transport {
     fn fmt(&self, fmt: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
-        writeln!(fmt, "Send: {:?}", self.send_channel)?;
+        writeln!(fmt, "Send: {:?}", self.sender.channel)?;
+        writeln!(
+            fmt,
+            "Backlog: {}/{} ({:?}) {:?}",
+            self.sender.backlog.count, BACKLOG_LEN, self.sender.policy, self.sender.stats
+        )?;
         writeln!(fmt, "Receive: {:?}", self.receive_channel)?;
+        for service in self.services() {
+            writeln!(fmt, "Service: {:?}", service)?;
+        }
         Ok(())
     }
 }
 
-pub struct SubSender<'a>(&'a mut vring::GuestVring);
+pub struct SubSender<'a>(&'a mut Sender);
 
 pub trait SendMessage {
-    fn send<P>(&mut self, source: u32, destination: u32, payload: &P) -> Result<(), Error>
-    where
-        P: Sized;
-}
+    /// Send some bytes as the payload of an rpmsg message.
+    fn send_bytes(&mut self, source: u32, destination: u32, payload: &[u8]) -> Result<(), Error>;
 
-impl SendMessage for Transport {
+    /// Send an object as the payload of an rpmsg message. The object is
+    /// copied byte-for-byte, so it should be `#[repr(C)]`.
     fn send<P>(&mut self, source: u32, destination: u32, payload: &P) -> Result<(), Error>
     where
         P: Sized,
     {
-        let tx_header = Header::new(source, destination, ::core::mem::size_of::<P>());
-        self.send_channel.transmit(&tx_header, payload)?;
+        self.send_bytes(source, destination, as_bytes(payload))
+    }
+
+    /// Send a payload of any length, split across as many rpmsg messages as
+    /// required. The far end needs an `rpmsg_fragment::Reassembler` to put
+    /// it back together.
+    fn send_fragmented(
+        &mut self,
+        source: u32,
+        destination: u32,
+        message_id: u16,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let mut fragmenter = rpmsg_fragment::Fragmenter::new(message_id, payload, MAX_PAYLOAD_LEN)?;
+        let mut buffer = [0u8; MAX_PAYLOAD_LEN];
+        while let Some(res) = fragmenter.next_fragment(&mut buffer) {
+            let len = res?;
+            self.send_bytes(source, destination, &buffer[0..len])?;
+        }
         Ok(())
     }
 }
 
+impl SendMessage for Transport {
+    fn send_bytes(&mut self, source: u32, destination: u32, payload: &[u8]) -> Result<(), Error> {
+        self.sender.send_bytes(source, destination, payload)
+    }
+}
+
 impl<'a> SendMessage for SubSender<'a> {
-    fn send<P>(&mut self, source: u32, destination: u32, payload: &P) -> Result<(), Error>
-    where
-        P: Sized,
-    {
-        let tx_header = Header::new(source, destination, ::core::mem::size_of::<P>());
-        self.0.transmit(&tx_header, payload)?;
-        Ok(())
+    fn send_bytes(&mut self, source: u32, destination: u32, payload: &[u8]) -> Result<(), Error> {
+        self.0.send_bytes(source, destination, payload)
     }
 }
 
 impl Transport {
-    pub fn new(send_channel: vring::GuestVring, receive_channel: vring::GuestVring) -> Transport {
+    pub fn new(
+        send_channel: vring::GuestVring,
+        receive_channel: vring::GuestVring,
+        backlog: &'static mut Backlog,
+    ) -> Transport {
+        backlog.clear();
         Transport {
-            send_channel,
+            sender: Sender {
+                channel: send_channel,
+                backlog,
+                policy: BacklogPolicy::DropOldest,
+                stats: BacklogStats::default(),
+            },
             receive_channel,
+            services: [None; MAX_SERVICES],
         }
     }
 
+    /// Choose what happens when the backlog is full.
+    pub fn set_backlog_policy(&mut self, policy: BacklogPolicy) {
+        self.sender.policy = policy;
+    }
+
+    /// Get the transmit backlog counters.
+    pub fn backlog_stats(&self) -> BacklogStats {
+        self.sender.stats
+    }
+
+    /// The number of messages waiting in the backlog.
+    pub fn backlog_len(&self) -> usize {
+        self.sender.backlog.count
+    }
+
+    /// Move as many messages as possible from the backlog on to the to-host
+    /// ring. Call this when the host tells us it has freed up some buffers.
+    /// Returns the number of messages sent.
+    pub fn flush(&mut self) -> usize {
+        self.sender.flush()
+    }
+
+    /// Register a service and announce it to the host. The host will create
+    /// an rpmsg channel for it, and bind any driver which matches `name`.
+    pub fn announce(&mut self, name: &str, description: &str, address: u32) -> Result<(), Error> {
+        let service = Service {
+            name: name.into(),
+            description: description.into(),
+            address,
+        };
+        let idx = match self.find_service(address) {
+            Some(idx) => idx,
+            None => self
+                .services
+                .iter()
+                .position(|s| s.is_none())
+                .ok_or(Error::TooManyServices)?,
+        };
+        self.services[idx] = Some(service);
+        self.send_announcement(&service, NameServiceAnnounceFlags::Create)
+    }
+
+    /// Withdraw the service at the given address. The host will destroy the
+    /// matching rpmsg channel.
+    pub fn withdraw(&mut self, address: u32) -> Result<(), Error> {
+        let idx = self.find_service(address).ok_or(Error::UnknownService)?;
+        let service = self.services[idx].take().unwrap();
+        self.send_announcement(&service, NameServiceAnnounceFlags::Destroy)
+    }
+
+    /// Withdraw every registered service, e.g. because we're shutting down.
+    /// We try them all, and return the first error (if any).
+    pub fn withdraw_all(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for idx in 0..self.services.len() {
+            if let Some(service) = self.services[idx].take() {
+                let res = self.send_announcement(&service, NameServiceAnnounceFlags::Destroy);
+                if result.is_ok() {
+                    result = res;
+                }
+            }
+        }
+        result
+    }
+
+    /// Announce every registered service again. Call this after the host has
+    /// restarted its virtio bus, as it will have forgotten about them.
+    pub fn reannounce(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for idx in 0..self.services.len() {
+            if let Some(service) = self.services[idx] {
+                let res = self.send_announcement(&service, NameServiceAnnounceFlags::Create);
+                if result.is_ok() {
+                    result = res;
+                }
+            }
+        }
+        result
+    }
+
+    /// Iterate through the services we currently have registered.
+    pub fn services(&self) -> impl Iterator<Item = &Service> {
+        self.services.iter().filter_map(|s| s.as_ref())
+    }
+
+    /// Returns true if the host has sent us messages we haven't received
+    /// yet, or we have messages in the backlog the host hasn't made room
+    /// for. This reads the vrings, so invalidate any cached copy of them
+    /// first.
+    pub fn has_pending(&self) -> bool {
+        self.receive_channel.has_available() || self.backlog_len() != 0
+    }
+
+    /// Returns true if the host has re-initialised our vrings, which
+    /// happens when its virtio bus is restarted.
+    pub fn is_reinitialised(&self) -> bool {
+        self.sender.channel.is_reinitialised() || self.receive_channel.is_reinitialised()
+    }
+
+    /// Start again with freshly initialised vrings. The registered services
+    /// are kept, so call `reannounce` once the host is ready. Anything in
+    /// the backlog was meant for the previous host, so it is discarded.
+    pub fn reset(&mut self) {
+        self.sender.channel.reset();
+        self.sender.backlog.clear();
+        self.receive_channel.reset();
+    }
+
     pub fn receive<F>(&mut self, callback: F) -> Result<(), Error>
     where
         F: FnOnce(SubSender, &Header, &[u8]),
     {
-        let tx = &mut self.send_channel;
+        let tx = &mut self.sender;
         self.receive_channel.process(move |rx| {
             let buf = rx.get_buffer();
             let (head, tail) = buf.split_at(::core::mem::size_of::<Header>());
@@ -208,7 +473,28 @@ impl Transport {
     }
 
     pub fn split(self) -> (vring::GuestVring, vring::GuestVring) {
-        (self.send_channel, self.receive_channel)
+        (self.sender.channel, self.receive_channel)
+    }
+
+    fn find_service(&self, address: u32) -> Option<usize> {
+        self.services.iter().position(|s| match *s {
+            Some(ref s) => s.address == address,
+            None => false,
+        })
+    }
+
+    fn send_announcement(
+        &mut self,
+        service: &Service,
+        mode: NameServiceAnnounceFlags,
+    ) -> Result<(), Error> {
+        let msg = NameServiceAnnounce {
+            name: service.name,
+            description: service.description,
+            address: service.address,
+            flags: mode,
+        };
+        self.send(service.address, NAME_SERVICE_ADDRESS, &msg)
     }
 }
 
@@ -241,13 +527,143 @@ impl Header {
     }
 }
 
+impl Sender {
+    fn send_bytes(&mut self, source: u32, destination: u32, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(Error::PayloadTooLarge);
+        }
+        let header = Header::new(source, destination, payload.len());
+        // Anything already waiting must go first, to keep messages in order.
+        self.flush();
+        if self.backlog.count == 0 {
+            match self.channel.transmit_slices(as_bytes(&header), payload) {
+                Ok(()) => return Ok(()),
+                Err(vring::Error::NoData) => {
+                    // Ring is full - fall through to the backlog
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if self.backlog.count == BACKLOG_LEN {
+            match self.policy {
+                BacklogPolicy::DropOldest => {
+                    self.backlog.pop();
+                    self.stats.dropped_oldest = self.stats.dropped_oldest.wrapping_add(1);
+                }
+                BacklogPolicy::DropNewest => {
+                    self.stats.dropped_newest = self.stats.dropped_newest.wrapping_add(1);
+                    return Err(Error::Dropped);
+                }
+                BacklogPolicy::Block => {
+                    self.stats.blocked = self.stats.blocked.wrapping_add(1);
+                    let mut attempts = 0;
+                    while self.backlog.count == BACKLOG_LEN {
+                        if attempts == BLOCK_LIMIT {
+                            self.stats.block_timeouts = self.stats.block_timeouts.wrapping_add(1);
+                            return Err(Error::Dropped);
+                        }
+                        self.flush();
+                        attempts += 1;
+                    }
+                }
+            }
+        }
+        self.backlog.push(&header, payload);
+        self.stats.queued = self.stats.queued.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Send messages from the backlog until it is empty or the ring is
+    /// full. A message the ring rejects for any other reason would never
+    /// go, so it is thrown away rather than holding up the rest.
+    fn flush(&mut self) -> usize {
+        let mut sent = 0;
+        while let Some(res) = self.transmit_oldest() {
+            match res {
+                Ok(()) => {
+                    self.stats.flushed = self.stats.flushed.wrapping_add(1);
+                    sent += 1;
+                }
+                Err(vring::Error::NoData) => break,
+                Err(_) => {
+                    self.stats.failed = self.stats.failed.wrapping_add(1);
+                }
+            }
+            self.backlog.pop();
+        }
+        sent
+    }
+
+    /// Try to send the message at the front of the backlog, without
+    /// removing it. Returns `None` if the backlog is empty.
+    fn transmit_oldest(&mut self) -> Option<Result<(), vring::Error>> {
+        let entry = self.backlog.peek()?;
+        Some(
+            self.channel
+                .transmit_slices(as_bytes(&entry.header), &entry.data[0..entry.len]),
+        )
+    }
+}
+
+impl Backlog {
+    /// An empty backlog.
+    pub const fn new() -> Backlog {
+        Backlog {
+            entries: [EMPTY_ENTRY; BACKLOG_LEN],
+            read: 0,
+            count: 0,
+        }
+    }
+
+    /// Throw away every message.
+    fn clear(&mut self) {
+        self.read = 0;
+        self.count = 0;
+    }
+
+    /// Add a message to the back. The caller must check there is space.
+    fn push(&mut self, header: &Header, payload: &[u8]) {
+        let idx = (self.read + self.count) % BACKLOG_LEN;
+        let entry = &mut self.entries[idx];
+        entry.header = *header;
+        entry.len = payload.len();
+        entry.data[0..payload.len()].copy_from_slice(payload);
+        self.count += 1;
+    }
+
+    /// Look at the message at the front.
+    fn peek(&self) -> Option<&BacklogEntry> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(&self.entries[self.read])
+        }
+    }
+
+    /// Discard the message at the front.
+    fn pop(&mut self) {
+        if self.count != 0 {
+            self.read = (self.read + 1) % BACKLOG_LEN;
+            self.count -= 1;
+        }
+    }
+}
+
 // ****************************************************************************
 //
 // Private Functions
 //
 // ****************************************************************************
 
-// None
+/// View any object as its raw bytes.
+fn as_bytes<P>(payload: &P) -> &[u8] {
+    unsafe {
+        ::core::slice::from_raw_parts(
+            payload as *const P as *const u8,
+            ::core::mem::size_of::<P>(),
+        )
+    }
+}
 
 // ****************************************************************************
 //
@@ -0,0 +1,268 @@
+//! # Standard rpmsg Services
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Optional services which match drivers in the mainline Linux kernel, so
+//! the IPU is useful without the TI kernel's AF_RPMSG sockets. Each one is
+//! enabled with a Cargo feature:
+//!
+//! * `client-sample` announces "rpmsg-client-sample", which binds
+//!   `samples/rpmsg/rpmsg_client_sample.ko`. We echo back everything it
+//!   sends.
+//! * `tty` announces "rpmsg-tty", which binds `drivers/tty/rpmsg_tty.ko` and
+//!   creates `/dev/ttyRPMSG*`. We run a tiny line-based console on it.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::rpmsg::{self, SendMessage};
+#[cfg(feature = "tty")]
+use core::fmt::Write;
+#[cfg(feature = "tty")]
+use version;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// State for whichever services are enabled.
+#[derive(Debug)]
+pub struct Services {
+    #[cfg(feature = "tty")]
+    console: Console,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// Our end of the "rpmsg-client-sample" channel.
+pub const CLIENT_SAMPLE_ID: u32 = 1024;
+
+/// Our end of the "rpmsg-tty" channel.
+pub const TTY_ID: u32 = 1025;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+/// A line editor and command interpreter for the tty.
+#[cfg(feature = "tty")]
+struct Console {
+    line: [u8; LINE_LEN],
+    len: usize,
+}
+
+/// Collects output until it can be sent. Anything which doesn't fit is
+/// dropped.
+#[cfg(feature = "tty")]
+struct Output {
+    buffer: [u8; rpmsg::MAX_PAYLOAD_LEN],
+    len: usize,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+#[cfg(feature = "tty")]
+const LINE_LEN: usize = 80;
+
+#[cfg(feature = "tty")]
+const PROMPT: &str = "ipu> ";
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl Services {
+    pub fn new() -> Services {
+        Services {
+            #[cfg(feature = "tty")]
+            console: Console {
+                line: [0u8; LINE_LEN],
+                len: 0,
+            },
+        }
+    }
+
+    /// Announce all the enabled services to the host.
+    pub fn announce(&self, transport: &mut rpmsg::Transport) -> Result<(), rpmsg::Error> {
+        #[cfg(feature = "client-sample")]
+        transport.announce(
+            "rpmsg-client-sample",
+            "rpmsg-client-sample",
+            CLIENT_SAMPLE_ID,
+        )?;
+        #[cfg(feature = "tty")]
+        transport.announce("rpmsg-tty", "rpmsg-tty", TTY_ID)?;
+        let _ = transport;
+        Ok(())
+    }
+
+    /// Handle a message, if it's for one of our services. Returns `None` if
+    /// it isn't.
+    pub fn handle<S>(
+        &mut self,
+        tx: &mut S,
+        header: &rpmsg::Header,
+        payload: &[u8],
+    ) -> Option<Result<(), rpmsg::Error>>
+    where
+        S: SendMessage,
+    {
+        match header.destination {
+            #[cfg(feature = "client-sample")]
+            CLIENT_SAMPLE_ID => Some(tx.send_bytes(CLIENT_SAMPLE_ID, header.source, payload)),
+            #[cfg(feature = "tty")]
+            TTY_ID => {
+                let mut output = Output {
+                    buffer: [0u8; rpmsg::MAX_PAYLOAD_LEN],
+                    len: 0,
+                };
+                self.console.input(payload, &mut output);
+                if output.len == 0 {
+                    Some(Ok(()))
+                } else {
+                    Some(tx.send_bytes(TTY_ID, header.source, &output.buffer[0..output.len]))
+                }
+            }
+            _ => {
+                let _ = (tx, payload);
+                None
+            }
+        }
+    }
+}
+
+impl Default for Services {
+    fn default() -> Services {
+        Services::new()
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+#[cfg(feature = "tty")]
+impl Console {
+    /// Process some bytes typed by the user, echoing them back as we go.
+    fn input(&mut self, data: &[u8], output: &mut Output) {
+        for &ch in data {
+            match ch {
+                b'\r' | b'\n' => {
+                    output.push(b"\r\n");
+                    self.execute(output);
+                    self.len = 0;
+                    output.push(PROMPT.as_bytes());
+                }
+                // Backspace or delete
+                0x08 | 0x7F => {
+                    if self.len != 0 {
+                        self.len -= 1;
+                        output.push(b"\x08 \x08");
+                    }
+                }
+                0x20..=0x7E => {
+                    if self.len < LINE_LEN {
+                        self.line[self.len] = ch;
+                        self.len += 1;
+                        output.push(&[ch]);
+                    }
+                }
+                _ => {
+                    // Ignore other control characters
+                }
+            }
+        }
+    }
+
+    /// Run the command in the line buffer.
+    fn execute(&self, output: &mut Output) {
+        // We only put printable ASCII in the buffer
+        let line = ::core::str::from_utf8(&self.line[0..self.len]).unwrap_or("");
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None => {}
+            Some("help") => {
+                output.push(b"Commands: help, version, echo <text>\r\n");
+            }
+            Some("version") => {
+                let _ = write!(output, "{}\r\n", version::version());
+            }
+            Some("echo") => {
+                for (idx, word) in words.enumerate() {
+                    let _ = write!(output, "{}{}", if idx == 0 { "" } else { " " }, word);
+                }
+                output.push(b"\r\n");
+            }
+            Some(cmd) => {
+                let _ = write!(output, "Unknown command {:?}\r\n", cmd);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tty")]
+impl ::core::fmt::Debug for Console {
+    fn fmt(&self, fmt: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        write!(fmt, "Console {{ len: {} }}", self.len)
+    }
+}
+
+#[cfg(feature = "tty")]
+impl Output {
+    fn push(&mut self, data: &[u8]) {
+        let space = self.buffer.len() - self.len;
+        let n = data.len().min(space);
+        self.buffer[self.len..self.len + n].copy_from_slice(&data[0..n]);
+        self.len += n;
+    }
+}
+
+#[cfg(feature = "tty")]
+impl ::core::fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
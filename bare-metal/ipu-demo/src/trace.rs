@@ -4,7 +4,35 @@
 //! See the top-level README.md for licence details.
 //!
 //! This module is for emitting text to
-//! `/sys/kernel/debug/remoteproc/remoteproc0/trace`.
+//! `/sys/kernel/debug/remoteproc/remoteproc0/trace0`. The buffer is laid
+//! out by the `trace-ring` crate: a header line, then a ring of text which
+//! overwrites the oldest lines when it fills up. `trace-dump` in
+//! `linux/rproc-tools` puts the text back in order.
+//!
+//! It's also our backend for the `log` crate. Call `init` once at start-up,
+//! then use `error!`, `warn!`, `info!`, `debug!` and `trace!` anywhere,
+//! including in interrupt handlers. Each line starts with the time since
+//! `start_clock` (from the 32 kHz sync timer), the level and the module.
+//!
+//! Lines are formatted in to a buffer on the caller's stack, with
+//! interrupts enabled, then copied in to the trace buffer with interrupts
+//! disabled. So every line arrives whole, whichever context it comes from,
+//! but lines from different contexts may not be in timestamp order. Lines
+//! longer than `LINE_LEN` are formatted with interrupts disabled instead.
+//! The panic handler should use `write_line_unbuffered`.
+//!
+//! The level can be changed at run time, either for everything
+//! (`set_default_level`) or for one module and its sub-modules
+//! (`set_module_level`). Anything more verbose than the `max_level_*` and
+//! `release_max_level_*` features given to `log` in `Cargo.toml` is
+//! compiled out altogether, so `trace!` costs nothing in a release build.
+//!
+//! For hot paths there are also `dlog!`, `derror!`, `dwarn!`, `dinfo!`,
+//! `ddebug!` and `dtrace!`, which take the same levels but defer the
+//! formatting to the host (see `trace_ring::deferred`). They only take
+//! integers, `bool`, `char` and `&str` as arguments, and their format
+//! strings stay in the ELF file. Run `trace-decode` or `trace-follow -e`
+//! from `linux/rproc-tools` with the firmware ELF to read them.
 
 // ****************************************************************************
 //
@@ -12,7 +40,13 @@
 //
 // ****************************************************************************
 
-// None
+use am5728;
+use core::fmt::{self, Write};
+use cortex_m;
+use log::{self, Level, LevelFilter, Log, Metadata, Record};
+use resource_table as rt;
+use resources::TRACE_BUFFER_LEN;
+use trace_ring::{deferred, Ring, MIN_LEN};
 
 // ****************************************************************************
 //
@@ -28,7 +62,50 @@
 //
 // ****************************************************************************
 
-// None
+/// Like `log!`, but with deferred formatting. The entry in the
+/// `.trace_strings` section is the record's ID.
+macro_rules! dlog {
+    ($level:expr, $fmt:expr) => {
+        dlog!($level, $fmt,)
+    };
+    ($level:expr, $fmt:expr, $($arg:expr),* $(,)*) => {{
+        const ENTRY_TEXT: &str = concat!(module_path!(), "\0", $fmt, "\0");
+        #[link_section = ".trace_strings"]
+        static ENTRY: [u8; ENTRY_TEXT.len()] = ::trace_ring::deferred::intern(ENTRY_TEXT);
+        let level: ::log::Level = $level;
+        if level <= ::log::STATIC_MAX_LEVEL && $crate::trace::enabled(level, module_path!()) {
+            let mut record =
+                ::trace_ring::deferred::Record::new(&ENTRY as *const _ as u32, level as u8);
+            $( record.push(&$arg); )*
+            $crate::trace::write_record(&mut record);
+        }
+    }};
+}
+
+#[allow(unused_macros)]
+macro_rules! derror {
+    ($($arg:tt)*) => { dlog!(::log::Level::Error, $($arg)*) };
+}
+
+#[allow(unused_macros)]
+macro_rules! dwarn {
+    ($($arg:tt)*) => { dlog!(::log::Level::Warn, $($arg)*) };
+}
+
+#[allow(unused_macros)]
+macro_rules! dinfo {
+    ($($arg:tt)*) => { dlog!(::log::Level::Info, $($arg)*) };
+}
+
+#[allow(unused_macros)]
+macro_rules! ddebug {
+    ($($arg:tt)*) => { dlog!(::log::Level::Debug, $($arg)*) };
+}
+
+#[allow(unused_macros)]
+macro_rules! dtrace {
+    ($($arg:tt)*) => { dlog!(::log::Level::Trace, $($arg)*) };
+}
 
 // ****************************************************************************
 //
@@ -36,12 +113,7 @@
 //
 // ****************************************************************************
 
-/// Represents our tracebuffer. Uses a shared mutable buffer,
-/// so only one of these can exist at any one time.
-pub struct Trace<'a> {
-    out_idx: usize,
-    buffer: &'a mut [u8; 16384],
-}
+// None
 
 // ****************************************************************************
 //
@@ -49,7 +121,14 @@ pub struct Trace<'a> {
 //
 // ****************************************************************************
 
-// None
+/// The most modules which can have their own level.
+pub const MAX_MODULE_LEVELS: usize = 8;
+
+/// The longest module path which can have its own level.
+pub const MAX_MODULE_LEN: usize = 48;
+
+/// The longest line which can be formatted with interrupts enabled.
+pub const LINE_LEN: usize = 256;
 
 // ****************************************************************************
 //
@@ -57,7 +136,32 @@ pub struct Trace<'a> {
 //
 // ****************************************************************************
 
-// None
+/// Our `log::Log` implementation.
+struct TraceLogger;
+
+/// One line of text, formatted on the stack.
+struct Line {
+    buffer: [u8; LINE_LEN],
+    len: usize,
+}
+
+/// A level for a module and its sub-modules.
+#[derive(Clone, Copy)]
+struct ModuleLevel {
+    module: [u8; MAX_MODULE_LEN],
+    len: usize,
+    level: LevelFilter,
+}
+
+/// Counts ticks of the 32 kHz sync timer since `start_clock`. The timer
+/// runs whatever we're doing, including hibernating, but it wraps every 36
+/// hours or so, which we only notice if we take a timestamp at least that
+/// often.
+struct Clock {
+    counter: Option<&'static am5728::SyncCounter>,
+    last: u32,
+    ticks: u64,
+}
 
 // ****************************************************************************
 //
@@ -66,10 +170,27 @@ pub struct Trace<'a> {
 // ****************************************************************************
 
 /// Our output text buffer we share with the kernel. Must must must be linked
-/// at address 0x9F000000 and the size and address must match that given in
-/// the `rt::Trace` part of `main::RESOURCE_TABLE`.
+/// at the address given in the `rt::Trace` part of
+/// `resources::RESOURCE_TABLE`, which is the start of the carveout named in
+/// `resources.toml`.
 #[link_section = ".tracebuffer"]
-static mut TRACE_BUFFER: [u8; 16384] = [0u8; 16384];
+static mut TRACE_BUFFER: [u8; TRACE_BUFFER_LEN] = [0u8; TRACE_BUFFER_LEN];
+
+// `Ring::new` panics on anything shorter, and we'd panic in `init`.
+const_assert!(TRACE_BUFFER_HOLDS_A_RING, TRACE_BUFFER_LEN >= MIN_LEN);
+
+static LOGGER: TraceLogger = TraceLogger;
+
+/// The level for modules which don't have one in `MODULE_LEVELS`.
+static mut DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+static mut MODULE_LEVELS: [Option<ModuleLevel>; MAX_MODULE_LEVELS] = [None; MAX_MODULE_LEVELS];
+
+static mut CLOCK: Clock = Clock {
+    counter: None,
+    last: 0,
+    ticks: 0,
+};
 
 // ****************************************************************************
 //
@@ -77,64 +198,229 @@ static mut TRACE_BUFFER: [u8; 16384] = [0u8; 16384];
 //
 // ****************************************************************************
 
-/// The first time you call this you'll get Some(t), where t
-/// can be passed to `writeln!` and friends. The second
-/// time you'll get None, so only call it once!
-pub fn get_trace() -> Option<&'static mut Trace<'static>> {
-    singleton!(: Trace = Trace {
-        out_idx: 0,
-        buffer: unsafe { &mut TRACE_BUFFER },
-    })
+/// Make the trace buffer the `log` backend, logging `level` and above from
+/// every module. Call this once, before logging anything.
+pub fn init(level: LevelFilter) {
+    // This only fails if there's already a logger, which would be our own.
+    let _ = log::set_logger(&LOGGER);
+    set_default_level(level);
 }
 
-/// Only call this from a panic handler.
-pub unsafe fn steal_trace() -> Trace<'static> {
-    let mut used_space = 0_usize;
-    for (idx, ch) in TRACE_BUFFER.iter().enumerate() {
-        if *ch == 0 {
-            used_space = idx;
-            break;
-        }
-    }
-    Trace {
-        out_idx: used_space,
-        buffer: &mut TRACE_BUFFER,
+/// Set the level for modules which don't have one of their own.
+pub fn set_default_level(level: LevelFilter) {
+    cortex_m::interrupt::free(|_cs| unsafe {
+        DEFAULT_LEVEL = level;
+    });
+    update_max_level();
+}
+
+/// Set the level for `module` (a path like `ipu_demo::control`) and its
+/// sub-modules, replacing any level it already had. Returns false if the
+/// path is too long, or too many modules already have a level.
+pub fn set_module_level(module: &str, level: LevelFilter) -> bool {
+    if module.len() > MAX_MODULE_LEN {
+        return false;
     }
+    let mut entry = ModuleLevel {
+        module: [0; MAX_MODULE_LEN],
+        len: module.len(),
+        level,
+    };
+    entry.module[..module.len()].copy_from_slice(module.as_bytes());
+    let added = cortex_m::interrupt::free(|_cs| unsafe {
+        let slot = MODULE_LEVELS
+            .iter()
+            .position(|m| m.map_or(false, |m| m.name() == module))
+            .or_else(|| MODULE_LEVELS.iter().position(|m| m.is_none()));
+        match slot {
+            Some(idx) => {
+                MODULE_LEVELS[idx] = Some(entry);
+                true
+            }
+            None => false,
+        }
+    });
+    update_max_level();
+    added
 }
 
-impl<'a> ::core::fmt::Write for Trace<'a> {
-    fn write_str(&mut self, s: &str) -> Result<(), ::core::fmt::Error> {
-        // Can never fit (with the null), so return an error.
-        if (s.len() + 1) > self.buffer.len() {
-            return Err(::core::fmt::Error);
+/// Go back to using the default level for `module`.
+pub fn clear_module_level(module: &str) {
+    cortex_m::interrupt::free(|_cs| unsafe {
+        for slot in MODULE_LEVELS.iter_mut() {
+            if slot.map_or(false, |m| m.name() == module) {
+                *slot = None;
+            }
         }
+    });
+    update_max_level();
+}
+
+/// Would a message at `level` from `module` be logged? Used by `dlog!`.
+pub fn enabled(level: Level, module: &str) -> bool {
+    level <= log::max_level() && level <= level_for(module)
+}
 
-        let space = self.buffer.len() - self.out_idx;
+/// Start the clock the timestamps come from. Call this as soon as the
+/// peripherals are mapped; lines logged before then are stamped with zero.
+pub fn start_clock<T>(mapper: &T)
+where
+    T: rt::AddressMapper,
+{
+    let counter = am5728::get_sync_counter(mapper);
+    cortex_m::interrupt::free(|_cs| unsafe {
+        CLOCK.counter = counter;
+        CLOCK.last = counter.map_or(0, |c| c.read());
+        CLOCK.ticks = 0;
+    });
+}
 
-        // Doesn't fit (with the null), let's wrap to make us some more space.
-        if (s.len() + 1) > space {
-            self.out_idx = 0;
+/// Stamp a deferred log record with the time and write it out. Used by
+/// `dlog!`.
+pub fn write_record(record: &mut deferred::Record) {
+    cortex_m::interrupt::free(|_cs| {
+        let (seconds, micros) = unsafe { timestamp() };
+        record.set_time(seconds, micros);
+        record.write_to(&mut unsafe { ring() });
+    });
+}
+
+/// Seconds and microseconds since `start_clock`, as at the start of each line.
+pub fn uptime() -> (u32, u32) {
+    cortex_m::interrupt::free(|_cs| unsafe { timestamp() })
+}
+
+/// Format a line straight in to the trace buffer, with interrupts disabled
+/// throughout. Nothing else can log in the meantime, so this is safe in a
+/// panic handler, and the line can be any length.
+pub fn write_line_unbuffered(args: fmt::Arguments) {
+    cortex_m::interrupt::free(|_cs| {
+        let mut ring = unsafe { ring() };
+        // Writing to the ring can't fail
+        let _ = ring.write_fmt(args);
+        ring.write(b"\n");
+    });
+}
+
+impl Log for TraceLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
         }
+        let (seconds, micros) = uptime();
+        let mut line = Line {
+            buffer: [0u8; LINE_LEN],
+            len: 0,
+        };
+        if format_record(&mut line, seconds, micros, record).is_ok() {
+            cortex_m::interrupt::free(|_cs| unsafe { ring() }.write(&line.buffer[..line.len]));
+        } else {
+            // Too long, so do it the slow way
+            cortex_m::interrupt::free(|_cs| {
+                // Writing to the ring can't fail
+                let _ = format_record(&mut unsafe { ring() }, seconds, micros, record);
+            });
+        }
+    }
+
+    fn flush(&self) {}
+}
 
-        for (s, d) in s
-            .bytes()
-            .zip(self.buffer[self.out_idx..self.out_idx + s.len()].iter_mut())
-        {
-            *d = s;
+impl fmt::Write for Line {
+    /// Fails if the line is full, which stops the formatting early.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buffer.len() {
+            return Err(fmt::Error);
         }
-        self.out_idx += s.len();
-        self.buffer[self.out_idx] = 0;
+        self.buffer[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
         Ok(())
     }
 }
 
+impl ModuleLevel {
+    fn name(&self) -> &str {
+        // Only ever set from a `&str`, so this can't fail
+        ::core::str::from_utf8(&self.module[..self.len]).unwrap_or("")
+    }
+
+    /// Does this cover the given module path?
+    fn covers(&self, target: &str) -> bool {
+        let name = self.name();
+        target.starts_with(name)
+            && (target.len() == name.len() || target[name.len()..].starts_with("::"))
+    }
+}
+
 // ****************************************************************************
 //
 // Private Functions
 //
 // ****************************************************************************
 
-// None
+/// The level for a module: that of the longest matching entry in
+/// `MODULE_LEVELS`, or the default.
+fn level_for(target: &str) -> LevelFilter {
+    cortex_m::interrupt::free(|_cs| unsafe {
+        let mut best: Option<&ModuleLevel> = None;
+        for m in MODULE_LEVELS.iter().filter_map(|m| m.as_ref()) {
+            if m.covers(target) && best.map_or(true, |b| m.len > b.len) {
+                best = Some(m);
+            }
+        }
+        best.map_or(DEFAULT_LEVEL, |m| m.level)
+    })
+}
+
+/// The `log` macros check `log::max_level()` before calling us, so it
+/// has to be the most verbose of all our levels.
+fn update_max_level() {
+    let max = cortex_m::interrupt::free(|_cs| unsafe {
+        MODULE_LEVELS
+            .iter()
+            .filter_map(|m| m.map(|m| m.level))
+            .fold(DEFAULT_LEVEL, ::core::cmp::max)
+    });
+    log::set_max_level(max);
+}
+
+/// Format a `log` record as a line of text.
+fn format_record<W: Write>(w: &mut W, seconds: u32, micros: u32, record: &Record) -> fmt::Result {
+    writeln!(
+        w,
+        "[{:5}.{:06}] {:5} {}: {}",
+        seconds,
+        micros,
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+/// The trace buffer. Call with interrupts disabled, and only keep the
+/// result until they're enabled again.
+unsafe fn ring() -> Ring<'static> {
+    Ring::new(&mut TRACE_BUFFER)
+}
+
+/// Seconds and microseconds since `start_clock`. Call with interrupts
+/// disabled.
+unsafe fn timestamp() -> (u32, u32) {
+    if let Some(counter) = CLOCK.counter {
+        let now = counter.read();
+        CLOCK.ticks += u64::from(now.wrapping_sub(CLOCK.last));
+        CLOCK.last = now;
+    }
+    let hz = u64::from(am5728::SYNC_COUNTER_HZ);
+    let seconds = CLOCK.ticks / hz;
+    let micros = (CLOCK.ticks % hz) * 1_000_000 / hz;
+    (seconds as u32, micros as u32)
+}
 
 // ****************************************************************************
 //
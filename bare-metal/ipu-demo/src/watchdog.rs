@@ -0,0 +1,164 @@
+//! # Watchdog
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Only built with the `watchdog` feature. A general purpose timer counts
+//! towards overflow and the main loop keeps winding it back. If the main
+//! loop stops going round, because of a deadlock or a loop which never
+//! ends, the timer overflows. Its interrupt goes to the host, whose omap
+//! remoteproc driver treats it as a crash, so recovery can restart us.
+//!
+//! The kernel must own the timer, through the `watchdog-timers` property
+//! (`ti,watchdog-timers` on newer kernels) of the IPU1 node in the Device
+//! Tree. It powers the timer up and down along with us, and handles the
+//! overflow. We only set the timeout and wind it back.
+//!
+//! SysTick wakes the main loop every `WAKE_CYCLES`, so we keep winding the
+//! timer back even when the host has nothing for us to do.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use am5728;
+use cortex_m;
+use resource_table as rt;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// How long the main loop can go without coming round.
+pub const TIMEOUT_SECS: u32 = 5;
+
+/// The timer the kernel gives IPU1's first core.
+pub const TIMER: am5728::TimerId = am5728::TimerId::Timer7;
+
+/// The timer's functional clock. The kernel runs it from SYS_CLK1, which
+/// is 20 MHz on the X15.
+pub const TIMER_HZ: u32 = 20_000_000;
+
+/// How often SysTick wakes the main loop. SysTick only counts 24 bits.
+pub const WAKE_CYCLES: u32 = am5728::CPU_HZ / 16;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const SYST_CSR_ENABLE: u32 = 1 << 0;
+const SYST_CSR_TICKINT: u32 = 1 << 1;
+/// Count CPU cycles
+const SYST_CSR_CLKSOURCE: u32 = 1 << 2;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Start the timer, and SysTick.
+pub fn start<T>(mapper: &T)
+where
+    T: rt::AddressMapper,
+{
+    let timer = match am5728::get_timer(TIMER, mapper) {
+        Some(t) => t,
+        None => {
+            warn!("Watchdog timer not mapped");
+            return;
+        }
+    };
+    timer.start_overflow(TIMEOUT_SECS * TIMER_HZ);
+    unsafe {
+        let syst = &*cortex_m::peripheral::SYST::ptr();
+        syst.rvr.write(WAKE_CYCLES - 1);
+        syst.cvr.write(0);
+        syst.csr
+            .write(SYST_CSR_ENABLE | SYST_CSR_TICKINT | SYST_CSR_CLKSOURCE);
+    }
+    info!("Watchdog started ({} s)", TIMEOUT_SECS);
+}
+
+/// Wind the timer back. Only call this from the main loop.
+pub fn pet<T>(mapper: &T)
+where
+    T: rt::AddressMapper,
+{
+    if let Some(timer) = am5728::get_timer(TIMER, mapper) {
+        timer.reload();
+    }
+}
+
+/// Stop the timer, so it never overflows.
+pub fn stop<T>(mapper: &T)
+where
+    T: rt::AddressMapper,
+{
+    if let Some(timer) = am5728::get_timer(TIMER, mapper) {
+        timer.stop();
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+exception!(SysTick, sys_tick);
+
+/// Taking the exception is enough to wake the main loop from `wfe`.
+fn sys_tick() {}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
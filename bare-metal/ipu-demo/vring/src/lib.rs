@@ -397,8 +397,24 @@ impl GuestVring {
         }
     }
 
+    /// Copy two objects, back to back, into the next buffer the host has
+    /// made available to us and hand it back on the used ring.
     pub fn transmit<P1, P2>(&mut self, payload1: &P1, payload2: &P2) -> Result<(), Error> {
-        if self.last_seen_available != self.available.idx {
+        let bytes1 = unsafe {
+            core::slice::from_raw_parts(payload1 as *const P1 as *const u8, ::core::mem::size_of::<P1>())
+        };
+        let bytes2 = unsafe {
+            core::slice::from_raw_parts(payload2 as *const P2 as *const u8, ::core::mem::size_of::<P2>())
+        };
+        self.transmit_slices(bytes1, bytes2)
+    }
+
+    /// As `transmit`, but for data which is already a byte slice. Returns
+    /// `Error::NoData` if the host has no buffers available for us.
+    pub fn transmit_slices(&mut self, payload1: &[u8], payload2: &[u8]) -> Result<(), Error> {
+        // The host adds buffers behind our back, so this must be a real read.
+        let available_idx = unsafe { ::core::ptr::read_volatile(&self.available.idx) };
+        if self.last_seen_available != available_idx {
             // Must have new stuff to play with
             let available_table: *mut AvailableEntry =
                 &mut self.available.ring as *mut AvailableEntry;
@@ -411,8 +427,8 @@ impl GuestVring {
 
             let addr = (self.addr_map)(e.addr) as *mut u8;
 
-            let length1 = ::core::mem::size_of::<P1>();
-            let length2 = ::core::mem::size_of::<P2>();
+            let length1 = payload1.len();
+            let length2 = payload2.len();
             let length = length1 + length2;
 
             if length > e.len as usize {
@@ -420,8 +436,8 @@ impl GuestVring {
             }
 
             unsafe {
-                core::ptr::copy_nonoverlapping(payload1 as *const P1 as *const u8, addr, length1);
-                core::ptr::copy_nonoverlapping(payload2 as *const P2 as *const u8, addr.offset(length1 as isize), length2);
+                core::ptr::copy_nonoverlapping(payload1.as_ptr(), addr, length1);
+                core::ptr::copy_nonoverlapping(payload2.as_ptr(), addr.offset(length1 as isize), length2);
             };
 
             e.len = length as u32;
@@ -445,6 +461,30 @@ impl GuestVring {
             Err(Error::NoData)
         }
     }
+
+    /// Returns true if the host has put something on the available ring
+    /// that we haven't processed yet.
+    pub fn has_available(&self) -> bool {
+        // The host adds buffers behind our back, so this must be a real read.
+        let available_idx = unsafe { ::core::ptr::read_volatile(&self.available.idx) };
+        available_idx != self.last_seen_available
+    }
+
+    /// Returns true if the host has re-initialised this vring behind our
+    /// back (for example, because the virtio bus was restarted). We are the
+    /// only writer of `used.idx` and it always moves in step with
+    /// `last_seen_available`, so if they disagree the host must have zeroed
+    /// the ring.
+    pub fn is_reinitialised(&self) -> bool {
+        let used_idx = unsafe { ::core::ptr::read_volatile(&self.used.idx) };
+        used_idx != self.last_seen_available
+    }
+
+    /// Forget everything we knew about the ring, ready for the host to start
+    /// again from index zero.
+    pub fn reset(&mut self) {
+        self.last_seen_available = 0;
+    }
 }
 
 /// Align a value. `alignment` must be a power of 2.
@@ -0,0 +1,550 @@
+//! # crash-report - What the IPU firmware was doing when it crashed
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! When the firmware panics or takes a HardFault, it fills in a
+//! `CrashReport` and writes it to the trace buffer as text (see the
+//! `Display` impl). It also writes a binary copy to a region it keeps for
+//! the purpose, which survives until the firmware is loaded again, so the
+//! host can pick it up afterwards.
+//!
+//! The binary form is `ENCODED_LEN` bytes of little-endian 32-bit words,
+//! starting with `MAGIC` and `FORMAT_VERSION`, then a checksum. `find`
+//! looks for one anywhere in a larger dump, such as a copy of the whole
+//! carveout.
+//!
+//! This crate does not allocate, so it can be used by both the IPU firmware
+//! and the Linux tools.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(test)]
+extern crate core;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::fmt;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// What went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Panic = 1,
+    HardFault = 2,
+}
+
+/// The registers the CPU pushes on to the stack when it takes an exception.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionFrame {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+}
+
+/// Everything we know about a crash.
+#[derive(Clone, Copy)]
+pub struct CrashReport {
+    pub kind: Kind,
+    /// The time since boot, as in the trace buffer
+    pub seconds: u32,
+    pub micros: u32,
+    /// Only for `Kind::HardFault`
+    pub frame: ExceptionFrame,
+    /// Configurable Fault Status Register
+    pub cfsr: u32,
+    /// HardFault Status Register
+    pub hfsr: u32,
+    /// MemManage Fault Address Register
+    pub mmfar: u32,
+    /// BusFault Address Register
+    pub bfar: u32,
+    /// The exception which was running: 0 for thread mode, 16 or more for
+    /// an interrupt.
+    pub exception: u32,
+    /// The stack pointer, after any exception frame
+    pub sp: u32,
+    stack: [u32; STACK_WORDS],
+    stack_len: usize,
+    version: [u8; VERSION_LEN],
+    version_len: usize,
+    message: [u8; MESSAGE_LEN],
+    message_len: usize,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The first word of an encoded report. "CRSH" in memory.
+pub const MAGIC: u32 = 0x4853_5243;
+
+/// Changes whenever the encoding does.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The most words from the top of the stack a report holds.
+pub const STACK_WORDS: usize = 32;
+
+/// The longest firmware version string a report holds.
+pub const VERSION_LEN: usize = 64;
+
+/// The longest panic message a report holds.
+pub const MESSAGE_LEN: usize = 128;
+
+/// The length of an encoded report.
+pub const ENCODED_LEN: usize = (HEADER_WORDS + STACK_WORDS) * 4 + VERSION_LEN + MESSAGE_LEN;
+
+/// The fault bits in the CFSR, with their names from the ARMv7-M ARM.
+pub const CFSR_BITS: [(u32, &str); 17] = [
+    (1 << 0, "IACCVIOL: instruction access violation"),
+    (1 << 1, "DACCVIOL: data access violation"),
+    (1 << 3, "MUNSTKERR: MemManage fault on unstacking"),
+    (1 << 4, "MSTKERR: MemManage fault on stacking"),
+    (1 << 5, "MLSPERR: MemManage fault saving FP state"),
+    (1 << 8, "IBUSERR: instruction bus error"),
+    (1 << 9, "PRECISERR: precise data bus error, address in BFAR"),
+    (1 << 10, "IMPRECISERR: imprecise data bus error"),
+    (1 << 11, "UNSTKERR: BusFault on unstacking"),
+    (1 << 12, "STKERR: BusFault on stacking"),
+    (1 << 13, "LSPERR: BusFault saving FP state"),
+    (1 << 16, "UNDEFINSTR: undefined instruction"),
+    (1 << 17, "INVSTATE: invalid state (Thumb bit clear?)"),
+    (1 << 18, "INVPC: invalid EXC_RETURN"),
+    (1 << 19, "NOCP: no coprocessor"),
+    (1 << 24, "UNALIGNED: unaligned access"),
+    (1 << 25, "DIVBYZERO: divide by zero"),
+];
+
+/// The fault bits in the HFSR.
+pub const HFSR_BITS: [(u32, &str); 3] = [
+    (1 << 1, "VECTTBL: BusFault reading the vector table"),
+    (1 << 30, "FORCED: escalated from a configurable fault"),
+    (1 << 31, "DEBUGEVT: debug event"),
+];
+
+/// MMFAR holds the faulting address.
+pub const CFSR_MMARVALID: u32 = 1 << 7;
+
+/// BFAR holds the faulting address.
+pub const CFSR_BFARVALID: u32 = 1 << 15;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// Magic, format, checksum, kind, seconds, micros, eight frame registers,
+/// CFSR, HFSR, MMFAR, BFAR, exception, SP and three lengths.
+const HEADER_WORDS: usize = 23;
+
+/// Where the checksum goes.
+const CHECKSUM_OFFSET: usize = 8;
+
+/// How many stack words to show per line.
+const STACK_WORDS_PER_LINE: usize = 8;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Look for a report anywhere in `data`, at a four-byte boundary. Returns
+/// the first valid one, and its offset.
+pub fn find(data: &[u8]) -> Option<(usize, CrashReport)> {
+    (0..data.len())
+        .step_by(4)
+        .filter(|&offset| data[offset..].starts_with(&MAGIC.to_le_bytes()))
+        .filter_map(|offset| CrashReport::decode(&data[offset..]).map(|r| (offset, r)))
+        .next()
+}
+
+impl CrashReport {
+    /// An empty report.
+    pub fn new(kind: Kind) -> CrashReport {
+        CrashReport {
+            kind,
+            seconds: 0,
+            micros: 0,
+            frame: ExceptionFrame::default(),
+            cfsr: 0,
+            hfsr: 0,
+            mmfar: 0,
+            bfar: 0,
+            exception: 0,
+            sp: 0,
+            stack: [0; STACK_WORDS],
+            stack_len: 0,
+            version: [0; VERSION_LEN],
+            version_len: 0,
+            message: [0; MESSAGE_LEN],
+            message_len: 0,
+        }
+    }
+
+    /// The words at the top of the stack, starting at `sp`.
+    pub fn stack(&self) -> &[u32] {
+        &self.stack[..self.stack_len]
+    }
+
+    /// Keeps the first `STACK_WORDS` of `words`.
+    pub fn set_stack(&mut self, words: &[u32]) {
+        let len = words.len().min(STACK_WORDS);
+        self.stack[..len].copy_from_slice(&words[..len]);
+        self.stack_len = len;
+    }
+
+    /// The firmware's version string.
+    pub fn version(&self) -> &str {
+        str_prefix(&self.version[..self.version_len])
+    }
+
+    /// Keeps as much of `version` as fits.
+    pub fn set_version(&mut self, version: &str) {
+        self.version_len = copy_str(&mut self.version, version);
+    }
+
+    /// The panic message. Add to it with `fmt::Write`.
+    pub fn message(&self) -> &str {
+        str_prefix(&self.message[..self.message_len])
+    }
+
+    /// Write the report to the start of `buffer`.
+    ///
+    /// # Panics
+    ///
+    /// If `buffer` is shorter than `ENCODED_LEN`.
+    pub fn encode(&self, buffer: &mut [u8]) {
+        let buffer = &mut buffer[..ENCODED_LEN];
+        let f = &self.frame;
+        let header: [u32; HEADER_WORDS] = [
+            MAGIC,
+            FORMAT_VERSION,
+            0,
+            self.kind as u32,
+            self.seconds,
+            self.micros,
+            f.r0,
+            f.r1,
+            f.r2,
+            f.r3,
+            f.r12,
+            f.lr,
+            f.pc,
+            f.xpsr,
+            self.cfsr,
+            self.hfsr,
+            self.mmfar,
+            self.bfar,
+            self.exception,
+            self.sp,
+            self.stack_len as u32,
+            self.version_len as u32,
+            self.message_len as u32,
+        ];
+        let words = header.iter().chain(self.stack.iter());
+        for (chunk, word) in buffer.chunks_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        let strings = (HEADER_WORDS + STACK_WORDS) * 4;
+        buffer[strings..strings + VERSION_LEN].copy_from_slice(&self.version);
+        buffer[strings + VERSION_LEN..].copy_from_slice(&self.message);
+        let sum = checksum(buffer);
+        buffer[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&sum.to_le_bytes());
+    }
+
+    /// Read a report from the start of `buffer`. Returns `None` if there
+    /// isn't a valid one there.
+    pub fn decode(buffer: &[u8]) -> Option<CrashReport> {
+        let buffer = buffer.get(..ENCODED_LEN)?;
+        let word = |idx: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&buffer[idx * 4..idx * 4 + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        if word(0) != MAGIC || word(1) != FORMAT_VERSION || word(2) != checksum(buffer) {
+            return None;
+        }
+        let kind = match word(3) {
+            1 => Kind::Panic,
+            2 => Kind::HardFault,
+            _ => return None,
+        };
+        let stack_len = word(20) as usize;
+        let version_len = word(21) as usize;
+        let message_len = word(22) as usize;
+        if stack_len > STACK_WORDS || version_len > VERSION_LEN || message_len > MESSAGE_LEN {
+            return None;
+        }
+        let mut report = CrashReport::new(kind);
+        report.seconds = word(4);
+        report.micros = word(5);
+        report.frame = ExceptionFrame {
+            r0: word(6),
+            r1: word(7),
+            r2: word(8),
+            r3: word(9),
+            r12: word(10),
+            lr: word(11),
+            pc: word(12),
+            xpsr: word(13),
+        };
+        report.cfsr = word(14);
+        report.hfsr = word(15);
+        report.mmfar = word(16);
+        report.bfar = word(17);
+        report.exception = word(18);
+        report.sp = word(19);
+        for (idx, w) in report.stack.iter_mut().enumerate() {
+            *w = word(HEADER_WORDS + idx);
+        }
+        report.stack_len = stack_len;
+        let strings = (HEADER_WORDS + STACK_WORDS) * 4;
+        report
+            .version
+            .copy_from_slice(&buffer[strings..strings + VERSION_LEN]);
+        report.version_len = version_len;
+        report
+            .message
+            .copy_from_slice(&buffer[strings + VERSION_LEN..]);
+        report.message_len = message_len;
+        Some(report)
+    }
+}
+
+impl fmt::Write for CrashReport {
+    /// Never fails. Anything that doesn't fit is lost.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.message_len;
+        self.message_len += copy_str(&mut self.message[len..], s);
+        Ok(())
+    }
+}
+
+impl fmt::Display for CrashReport {
+    /// Several lines, without a final newline.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "*** CRASH: {:?} at {}.{:06}\nFirmware: {}\nRunning: ",
+            self.kind,
+            self.seconds,
+            self.micros,
+            self.version()
+        )?;
+        match self.exception {
+            0 => write!(f, "thread mode")?,
+            2 => write!(f, "NMI")?,
+            3 => write!(f, "HardFault")?,
+            4 => write!(f, "MemManage")?,
+            5 => write!(f, "BusFault")?,
+            6 => write!(f, "UsageFault")?,
+            11 => write!(f, "SVCall")?,
+            12 => write!(f, "DebugMonitor")?,
+            14 => write!(f, "PendSV")?,
+            15 => write!(f, "SysTick")?,
+            n if n >= 16 => write!(f, "IRQ {}", n - 16)?,
+            n => write!(f, "exception {}", n)?,
+        }
+        if !self.message().is_empty() {
+            write!(f, "\nMessage: {}", self.message())?;
+        }
+        if self.kind == Kind::HardFault {
+            let r = &self.frame;
+            write!(
+                f,
+                "\nPC 0x{:08x} LR 0x{:08x} xPSR 0x{:08x}\n\
+                 R0 0x{:08x} R1 0x{:08x} R2 0x{:08x} R3 0x{:08x} R12 0x{:08x}",
+                r.pc, r.lr, r.xpsr, r.r0, r.r1, r.r2, r.r3, r.r12
+            )?;
+        }
+        write!(
+            f,
+            "\nCFSR 0x{:08x} HFSR 0x{:08x} MMFAR 0x{:08x} BFAR 0x{:08x}",
+            self.cfsr, self.hfsr, self.mmfar, self.bfar
+        )?;
+        let faults = CFSR_BITS
+            .iter()
+            .filter(|&&(bit, _)| self.cfsr & bit != 0)
+            .chain(HFSR_BITS.iter().filter(|&&(bit, _)| self.hfsr & bit != 0));
+        for &(_, name) in faults {
+            write!(f, "\n  {}", name)?;
+        }
+        if self.cfsr & CFSR_MMARVALID != 0 {
+            write!(f, "\n  MemManage fault at 0x{:08x}", self.mmfar)?;
+        }
+        if self.cfsr & CFSR_BFARVALID != 0 {
+            write!(f, "\n  BusFault at 0x{:08x}", self.bfar)?;
+        }
+        write!(f, "\nStack at 0x{:08x}:", self.sp)?;
+        for (idx, line) in self.stack().chunks(STACK_WORDS_PER_LINE).enumerate() {
+            let addr = self
+                .sp
+                .wrapping_add((idx * STACK_WORDS_PER_LINE * 4) as u32);
+            write!(f, "\n  0x{:08x}:", addr)?;
+            for word in line {
+                write!(f, " {:08x}", word)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// The wrapping sum of the words in `buffer`, skipping the checksum.
+fn checksum(buffer: &[u8]) -> u32 {
+    buffer
+        .chunks(4)
+        .enumerate()
+        .filter(|&(idx, _)| idx * 4 != CHECKSUM_OFFSET)
+        .fold(0u32, |sum, (_, chunk)| {
+            let mut bytes = [0u8; 4];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            sum.wrapping_add(u32::from_le_bytes(bytes))
+        })
+}
+
+/// Copy as much of `s` as fits in `buffer`, without splitting a character.
+/// Returns the number of bytes copied.
+fn copy_str(buffer: &mut [u8], s: &str) -> usize {
+    let mut len = s.len().min(buffer.len());
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    buffer[..len].copy_from_slice(&s.as_bytes()[..len]);
+    len
+}
+
+/// The valid UTF-8 at the start of `bytes`.
+fn str_prefix(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fmt::Write;
+
+    fn hard_fault() -> CrashReport {
+        let mut report = CrashReport::new(Kind::HardFault);
+        report.seconds = 12;
+        report.micros = 345;
+        report.frame.pc = 0x1234;
+        report.frame.lr = 0x5679;
+        report.cfsr = (1 << 9) | CFSR_BFARVALID;
+        report.hfsr = 1 << 30;
+        report.bfar = 0xDEAD_0000;
+        report.exception = 60;
+        report.sp = 0x8000_1000;
+        report.set_stack(&(0..40).collect::<Vec<u32>>());
+        report.set_version("ipu-demo (heads/master-0-gabcdef)");
+        report
+    }
+
+    #[test]
+    fn round_trip() {
+        let report = hard_fault();
+        assert_eq!(report.stack().len(), STACK_WORDS);
+        let mut buffer = vec![0xFFu8; ENCODED_LEN + 10];
+        report.encode(&mut buffer[6..]);
+        assert_eq!(buffer[6 + ENCODED_LEN], 0xFF);
+        let (offset, found) = find(&buffer[2..]).unwrap();
+        assert_eq!(offset, 4);
+        assert_eq!(found.to_string(), report.to_string());
+        assert_eq!(found.frame, report.frame);
+        assert_eq!(found.stack(), report.stack());
+        assert_eq!(found.version(), report.version());
+
+        // Any damage is spotted
+        buffer[100] ^= 1;
+        assert!(find(&buffer).is_none());
+        assert!(CrashReport::decode(&buffer[6..ENCODED_LEN]).is_none());
+    }
+
+    #[test]
+    fn panic_message() {
+        let mut report = CrashReport::new(Kind::Panic);
+        write!(report, "{}", "\u{e9}".repeat(MESSAGE_LEN)).unwrap();
+        assert_eq!(report.message().len(), MESSAGE_LEN);
+        let mut buffer = [0u8; ENCODED_LEN];
+        report.encode(&mut buffer);
+        let found = CrashReport::decode(&buffer).unwrap();
+        assert_eq!(found.kind, Kind::Panic);
+        assert_eq!(found.message(), report.message());
+    }
+
+    #[test]
+    fn decoded_text() {
+        let text = hard_fault().to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "*** CRASH: HardFault at 12.000345");
+        assert_eq!(lines[2], "Running: IRQ 44");
+        assert_eq!(lines[3], "PC 0x00001234 LR 0x00005679 xPSR 0x00000000");
+        assert!(text.contains("\n  PRECISERR: precise data bus error, address in BFAR\n"));
+        assert!(text.contains("\n  FORCED: escalated from a configurable fault\n"));
+        assert!(text.contains("\n  BusFault at 0xdead0000\n"));
+        assert!(text.contains("\n  0x80001020: 00000008 00000009"));
+        assert!(text.ends_with(" 0000001f"));
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
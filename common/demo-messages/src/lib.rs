@@ -0,0 +1,412 @@
+//! # demo-messages - Application messages for the IPU demo
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! The messages exchanged between `socket-demo` on Linux and `ipu-demo` on
+//! the IPU are defined here, so both sides agree on the format. Every
+//! message starts with a four byte header:
+//!
+//! | Offset | Size | Field                               |
+//! |--------|------|-------------------------------------|
+//! | 0      | 1    | Protocol version                    |
+//! | 1      | 1    | Message kind                        |
+//! | 2      | 2    | Length of the body (little-endian)  |
+//!
+//! The body follows, with all integers little-endian. We don't transmute
+//! structures to bytes, so the layout doesn't depend on either compiler.
+//!
+//! Later versions of the protocol may append fields to a body. A decoder
+//! ignores any bytes it doesn't understand, so the version only needs to
+//! change when an existing field changes meaning.
+//!
+//! The IDs of the RPC methods the IPU offers (see the `rpmsg-rpc` crate)
+//! are also defined here.
+//!
+//! TI's rpmsg socket driver only lets Linux send to the IPU's `rpmsg-proto`
+//! endpoint, which also carries TI's MessageQ. So every message Linux sends
+//! to one of the demo's services starts with a four byte tag (see
+//! `Service::tag`) saying which `Service` it's for. A MessageQ message
+//! starts with a list pointer, which is zero or a user-space address below
+//! `0xC000_0000`, and a NameServer message starts with its token, so
+//! neither can be mistaken for a tag. The IPU replies straight to the
+//! socket which sent the request, so replies aren't tagged.
+//!
+//! Enable the `std` feature to get `std::error::Error` for `Error`.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+#[cfg(any(test, feature = "std"))]
+extern crate core;
+extern crate le_bytes;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use le_bytes::{read_u16, read_u32, u32_bytes, write_u16, write_u32};
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// All the messages in the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    TestRequest(TestRequest),
+    TestResponse(TestResponse),
+}
+
+/// Identifies which message follows the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    TestRequest = 1,
+    TestResponse = 2,
+}
+
+/// The services the IPU offers on its `rpmsg-proto` endpoint, besides
+/// MessageQ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Service {
+    /// `Message`s. Responses go to address 100 on Linux.
+    Demo = 1,
+    /// Fragmented messages (see the `rpmsg-fragment` crate), which are
+    /// reassembled and echoed back, fragmented again
+    Fragment = 2,
+    /// Remote procedure calls (see the `rpmsg-rpc` crate)
+    Rpc = 3,
+}
+
+/// Sent by Linux to the IPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestRequest {
+    pub test1: u32,
+    pub test2: u32,
+    pub test3: u32,
+}
+
+/// Sent by the IPU in reply to each `TestRequest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestResponse {
+    /// Counts up for every message the IPU handles
+    pub sequence: u32,
+    /// A copy of the request we're responding to
+    pub request: TestRequest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The output buffer is too small for the encoded message
+    BufferTooSmall,
+    /// The input is too short to contain the header, or the body the header
+    /// describes
+    TooShort,
+    /// The message uses a version of the protocol we don't understand
+    UnsupportedVersion(u8),
+    /// The message kind isn't one we know about
+    UnknownKind(u8),
+    /// The body is too short for the given message kind
+    BadLength,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The version of the protocol this crate implements.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// The length of the message header.
+pub const HEADER_LEN: usize = 4;
+
+/// The length of the longest message we can encode.
+pub const MAX_MESSAGE_LEN: usize = HEADER_LEN + TEST_RESPONSE_LEN;
+
+/// A message for a `Service` starts with this, little-endian, with the
+/// service in the bottom byte.
+pub const SERVICE_TAG: u32 = 0xC0DE_5E00;
+
+/// The length of the tag in front of a message for a `Service`.
+pub const SERVICE_TAG_LEN: usize = 4;
+
+/// RPC method: the reply body is a copy of the call body.
+pub const METHOD_ECHO: u16 = 1;
+
+/// RPC method: the call body is a list of little-endian `u32`s, and the
+/// reply body is their (wrapping) sum, as a little-endian `u32`.
+pub const METHOD_ADD: u16 = 2;
+
+/// RPC method: the reply body is the firmware version, as UTF-8 text.
+pub const METHOD_VERSION: u16 = 3;
+
+/// RPC method: the call body is a log level (0 for off, then 1 for errors
+/// up to 5 for trace), followed by a module path as UTF-8 text. An empty
+/// path sets the level for every module without a level of its own. The
+/// reply body is empty.
+pub const METHOD_SET_LOG_LEVEL: u16 = 4;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const SERVICE_TAG_MASK: u32 = 0xFFFF_FF00;
+
+const TEST_REQUEST_LEN: usize = 12;
+const TEST_RESPONSE_LEN: usize = 4 + TEST_REQUEST_LEN;
+
+// ****************************************************************************
+//
+// Public Functions / Impl
+//
+// ****************************************************************************
+
+impl Message {
+    /// Which kind of message this is.
+    pub fn kind(&self) -> MessageKind {
+        match *self {
+            Message::TestRequest(_) => MessageKind::TestRequest,
+            Message::TestResponse(_) => MessageKind::TestResponse,
+        }
+    }
+
+    /// Write the message, including the header, to `buffer`. Returns the
+    /// number of bytes used.
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let body_len = match *self {
+            Message::TestRequest(_) => TEST_REQUEST_LEN,
+            Message::TestResponse(_) => TEST_RESPONSE_LEN,
+        };
+        if buffer.len() < HEADER_LEN + body_len {
+            return Err(Error::BufferTooSmall);
+        }
+        buffer[0] = PROTOCOL_VERSION;
+        buffer[1] = self.kind() as u8;
+        write_u16(&mut buffer[2..4], body_len as u16);
+        let body = &mut buffer[HEADER_LEN..HEADER_LEN + body_len];
+        match *self {
+            Message::TestRequest(ref req) => req.encode(body),
+            Message::TestResponse(ref rsp) => {
+                write_u32(&mut body[0..4], rsp.sequence);
+                rsp.request.encode(&mut body[4..]);
+            }
+        }
+        Ok(HEADER_LEN + body_len)
+    }
+
+    /// Read a message, including the header, from `buffer`. Any bytes after
+    /// the end of the message are ignored.
+    pub fn decode(buffer: &[u8]) -> Result<Message, Error> {
+        if buffer.len() < HEADER_LEN {
+            return Err(Error::TooShort);
+        }
+        if buffer[0] != PROTOCOL_VERSION {
+            return Err(Error::UnsupportedVersion(buffer[0]));
+        }
+        let body_len = read_u16(&buffer[2..4]) as usize;
+        if buffer.len() < HEADER_LEN + body_len {
+            return Err(Error::TooShort);
+        }
+        let body = &buffer[HEADER_LEN..HEADER_LEN + body_len];
+        match buffer[1] {
+            x if x == MessageKind::TestRequest as u8 => {
+                if body.len() < TEST_REQUEST_LEN {
+                    return Err(Error::BadLength);
+                }
+                Ok(Message::TestRequest(TestRequest::decode(body)))
+            }
+            x if x == MessageKind::TestResponse as u8 => {
+                if body.len() < TEST_RESPONSE_LEN {
+                    return Err(Error::BadLength);
+                }
+                Ok(Message::TestResponse(TestResponse {
+                    sequence: read_u32(&body[0..4]),
+                    request: TestRequest::decode(&body[4..]),
+                }))
+            }
+            x => Err(Error::UnknownKind(x)),
+        }
+    }
+}
+
+impl Service {
+    /// The tag to put in front of a message for this service.
+    pub fn tag(self) -> [u8; SERVICE_TAG_LEN] {
+        u32_bytes(SERVICE_TAG | (self as u32))
+    }
+
+    /// Split the tag off the front of a message sent to the `rpmsg-proto`
+    /// endpoint. Returns the service and the rest of the message, or `None`
+    /// if the message has no tag, and so is for MessageQ.
+    pub fn untag(payload: &[u8]) -> Option<(Service, &[u8])> {
+        if payload.len() < SERVICE_TAG_LEN {
+            return None;
+        }
+        let tag = read_u32(&payload[0..SERVICE_TAG_LEN]);
+        if (tag & SERVICE_TAG_MASK) != SERVICE_TAG {
+            return None;
+        }
+        let service = match tag & !SERVICE_TAG_MASK {
+            x if x == Service::Demo as u32 => Service::Demo,
+            x if x == Service::Fragment as u32 => Service::Fragment,
+            x if x == Service::Rpc as u32 => Service::Rpc,
+            _ => return None,
+        };
+        Some((service, &payload[SERVICE_TAG_LEN..]))
+    }
+}
+
+impl ::core::fmt::Display for Error {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        match *self {
+            Error::BufferTooSmall => write!(f, "buffer too small"),
+            Error::TooShort => write!(f, "message truncated"),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported protocol version {}", v),
+            Error::UnknownKind(k) => write!(f, "unknown message kind {}", k),
+            Error::BadLength => write!(f, "message body too short"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl ::std::error::Error for Error {}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+impl TestRequest {
+    /// `body` must be at least `TEST_REQUEST_LEN` bytes long.
+    fn encode(&self, body: &mut [u8]) {
+        write_u32(&mut body[0..4], self.test1);
+        write_u32(&mut body[4..8], self.test2);
+        write_u32(&mut body[8..12], self.test3);
+    }
+
+    /// `body` must be at least `TEST_REQUEST_LEN` bytes long.
+    fn decode(body: &[u8]) -> TestRequest {
+        TestRequest {
+            test1: read_u32(&body[0..4]),
+            test2: read_u32(&body[4..8]),
+            test3: read_u32(&body[8..12]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let request = TestRequest {
+            test1: 0xAAAAAAAA,
+            test2: 0xBBBBBBBB,
+            test3: 0x12345678,
+        };
+        let messages = [
+            Message::TestRequest(request),
+            Message::TestResponse(TestResponse {
+                sequence: 99,
+                request,
+            }),
+        ];
+        for msg in messages.iter() {
+            let mut buffer = [0u8; MAX_MESSAGE_LEN + 4];
+            let len = msg.encode(&mut buffer).unwrap();
+            assert_eq!(Message::decode(&buffer[0..len]), Ok(*msg));
+            // Trailing bytes are ignored
+            assert_eq!(Message::decode(&buffer), Ok(*msg));
+            // Truncated messages are not
+            assert_eq!(Message::decode(&buffer[0..len - 1]), Err(Error::TooShort));
+        }
+        assert_eq!(
+            &buffer_for(&messages[0])[0..8],
+            &[1, 1, 12, 0, 0xAA, 0xAA, 0xAA, 0xAA]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Message::decode(&[1, 1, 0]), Err(Error::TooShort));
+        assert_eq!(
+            Message::decode(&[2, 1, 0, 0]),
+            Err(Error::UnsupportedVersion(2))
+        );
+        assert_eq!(Message::decode(&[1, 9, 0, 0]), Err(Error::UnknownKind(9)));
+        assert_eq!(Message::decode(&[1, 1, 1, 0, 0]), Err(Error::BadLength));
+        let mut small = [0u8; 8];
+        let msg = Message::TestRequest(TestRequest {
+            test1: 1,
+            test2: 2,
+            test3: 3,
+        });
+        assert_eq!(msg.encode(&mut small), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn test_service_tag() {
+        let mut message = Service::Rpc.tag().to_vec();
+        assert_eq!(message, [0x03, 0x5E, 0xDE, 0xC0]);
+        message.extend_from_slice(b"call");
+        assert_eq!(Service::untag(&message), Some((Service::Rpc, &b"call"[..])));
+        // A MessageQ message starts with a zero list pointer
+        assert_eq!(Service::untag(&[0; 32]), None);
+        // An unknown service
+        assert_eq!(Service::untag(&[0x09, 0x5E, 0xDE, 0xC0]), None);
+        assert_eq!(Service::untag(&message[0..3]), None);
+    }
+
+    fn buffer_for(msg: &Message) -> [u8; MAX_MESSAGE_LEN] {
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        msg.encode(&mut buffer).unwrap();
+        buffer
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
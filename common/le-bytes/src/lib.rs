@@ -0,0 +1,148 @@
+//! # le-bytes - Little-endian integers in byte buffers
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Every message format we share between the IPU and Linux encodes its
+//! integers little-endian, as per the ARM cores at each end. These are the
+//! helpers they all use to do it.
+//!
+//! Each function works on the start of the slice it is given, and panics if
+//! the slice is too short, so check lengths before calling.
+//!
+//! This crate does not allocate, so it can be used by both the IPU firmware
+//! and the Linux applications.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+#![cfg_attr(not(test), no_std)]
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Write `value` to the first two bytes of `buffer`.
+pub fn write_u16(buffer: &mut [u8], value: u16) {
+    buffer[0] = value as u8;
+    buffer[1] = (value >> 8) as u8;
+}
+
+/// Write `value` to the first four bytes of `buffer`.
+pub fn write_u32(buffer: &mut [u8], value: u32) {
+    buffer[0] = value as u8;
+    buffer[1] = (value >> 8) as u8;
+    buffer[2] = (value >> 16) as u8;
+    buffer[3] = (value >> 24) as u8;
+}
+
+/// Read a value from the first two bytes of `buffer`.
+pub fn read_u16(buffer: &[u8]) -> u16 {
+    (buffer[0] as u16) | ((buffer[1] as u16) << 8)
+}
+
+/// Read a value from the first four bytes of `buffer`.
+pub fn read_u32(buffer: &[u8]) -> u32 {
+    (buffer[0] as u32)
+        | ((buffer[1] as u32) << 8)
+        | ((buffer[2] as u32) << 16)
+        | ((buffer[3] as u32) << 24)
+}
+
+/// The four bytes of `value`, for appending to a message.
+pub fn u32_bytes(value: u32) -> [u8; 4] {
+    let mut bytes = [0u8; 4];
+    write_u32(&mut bytes, value);
+    bytes
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+// None
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut buffer = [0u8; 6];
+        write_u16(&mut buffer, 0x1234);
+        write_u32(&mut buffer[2..], 0xDEAD_BEEF);
+        assert_eq!(buffer, [0x34, 0x12, 0xEF, 0xBE, 0xAD, 0xDE]);
+        assert_eq!(read_u16(&buffer), 0x1234);
+        assert_eq!(read_u32(&buffer[2..]), 0xDEAD_BEEF);
+        assert_eq!(u32_bytes(0xDEAD_BEEF), [0xEF, 0xBE, 0xAD, 0xDE]);
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,753 @@
+//! # resource-table - Remote Proc Resource Tables
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! This is the code that is generic to all resource tables. Your specific
+//! resource table for your application should be defined elsewhere, using
+//! the `resource_table!` macro.
+//!
+//! The structures have the layout the kernel expects whether they're built
+//! for the remote core or for a 64-bit host, so host tools can use them to
+//! pick apart a table too.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+#![cfg_attr(not(test), no_std)]
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[cfg(test)]
+use std as core;
+
+pub use map::RegionMap;
+pub use string::String32;
+
+/// Used by `resource_table!`, which may be expanded in crates that call
+/// `core` something else.
+#[doc(hidden)]
+pub use core::mem as __mem;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+pub mod map;
+pub mod string;
+pub mod validate;
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+/// Declares a resource table struct and a static instance of it. The
+/// `Header` and the offset array are generated for you, with the offset of
+/// each entry worked out at compile time from the size and alignment of the
+/// entries before it.
+///
+/// Each entry is a field name, a type and a value, separated by `;`. Some
+/// entries (like a `Vdev`) must be immediately followed by other structures
+/// which are part of the same entry, and so don't get an offset of their
+/// own. List those after the entry, each introduced with `=>`.
+///
+/// Every entry and part type must implement `AsResource`, so that the
+/// generated `Table` impl can hand them to `validate::validate`.
+///
+/// ```ignore
+/// resource_table! {
+///     /// The resource table for this application
+///     pub struct ResourceTable;
+///
+///     #[link_section = ".resource_table"]
+///     #[no_mangle]
+///     pub static RESOURCE_TABLE = {
+///         rpmsg_vdev: rt::Vdev = rt::Vdev { ... }
+///             => rpmsg_vring0: rt::VdevVring = rt::VdevVring { ... }
+///             => rpmsg_vring1: rt::VdevVring = rt::VdevVring { ... };
+///         trace: rt::Trace = rt::Trace { ... };
+///     };
+/// }
+/// ```
+#[macro_export]
+macro_rules! resource_table {
+    (@count) => { 0 };
+    (@count $head:ident $($tail:ident)*) => {
+        1 + resource_table!(@count $($tail)*)
+    };
+    (@align $offset:expr, $ty:ty) => {
+        (($offset) + $crate::__mem::align_of::<$ty>() - 1) & !($crate::__mem::align_of::<$ty>() - 1)
+    };
+    (@offsets [$($done:expr),*] $offset:expr;) => {
+        [$(($done) as u32),*]
+    };
+    (@offsets [$($done:expr),*] $offset:expr; (entry $ty:ty) $($rest:tt)*) => {
+        resource_table!(
+            @offsets [$($done,)* resource_table!(@align $offset, $ty)]
+            resource_table!(@align $offset, $ty) + $crate::__mem::size_of::<$ty>();
+            $($rest)*
+        )
+    };
+    (@offsets [$($done:expr),*] $offset:expr; (part $ty:ty) $($rest:tt)*) => {
+        resource_table!(
+            @offsets [$($done),*]
+            resource_table!(@align $offset, $ty) + $crate::__mem::size_of::<$ty>();
+            $($rest)*
+        )
+    };
+    (
+        $(#[$struct_attr:meta])*
+        pub struct $name:ident;
+
+        $(#[$static_attr:meta])*
+        pub static $table:ident = {
+            $(
+                $(#[$field_attr:meta])*
+                $field:ident: $ty:ty = $init:expr
+                $(=> $(#[$part_attr:meta])* $part:ident: $part_ty:ty = $part_init:expr)*
+            );+ $(;)*
+        };
+    ) => {
+        $(#[$struct_attr])*
+        #[repr(C)]
+        #[derive(Debug)]
+        pub struct $name {
+            pub base: $crate::Header,
+            pub offsets: [u32; resource_table!(@count $($field)+)],
+            $(
+                $(#[$field_attr])*
+                pub $field: $ty,
+                $(
+                    $(#[$part_attr])*
+                    pub $part: $part_ty,
+                )*
+            )+
+        }
+
+        impl $crate::Table for $name {
+            fn for_each_resource<'a, F>(&'a self, mut f: F)
+            where
+                F: FnMut($crate::Resource<'a>),
+            {
+                $(
+                    f($crate::AsResource::as_resource(&self.$field));
+                    $(f($crate::AsResource::as_resource(&self.$part));)*
+                )+
+            }
+        }
+
+        $(#[$static_attr])*
+        pub static $table: $name = $name {
+            base: $crate::Header {
+                ver: 1,
+                num: resource_table!(@count $($field)+),
+                reserved: [0, 0],
+            },
+            offsets: resource_table!(
+                @offsets [] 0;
+                (part $crate::Header)
+                (part [u32; resource_table!(@count $($field)+)])
+                $(
+                    (entry $ty)
+                    $((part $part_ty))*
+                )+
+            ),
+            $(
+                $field: $init,
+                $($part: $part_init,)*
+            )+
+        };
+    };
+}
+
+/// Fails to compile if the given constant expression is false. The name is
+/// only there to make the error message easier to follow.
+#[macro_export]
+macro_rules! const_assert {
+    ($name:ident, $cond:expr) => {
+        #[allow(dead_code)]
+        const $name: [(); 0] = [(); 0 - !($cond) as usize];
+    };
+}
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// The type of an entry in a Resource Table. This is a plain `u32` rather
+/// than an enum, so that a table read from a file or from memory the host
+/// has written to can hold types we don't know about.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceType(pub u32);
+
+/// All resource tables start with this header, followed by
+/// the offset array.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Header {
+    pub ver: u32,
+    pub num: u32,
+    pub reserved: [u32; 2],
+}
+
+/// This is the structure for `ResourceType::CARVEOUT`.
+///
+/// Leave `pa` as zero and the kernel will allocate the memory and write its
+/// physical address into the copy of the table it loaded for us. That's
+/// behind the compiler's back, so `Region::get_pa` reads it with a volatile
+/// read.
+#[repr(C)]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct Carveout {
+    pub rtype: ResourceType,
+    pub da: u32,
+    pub pa: u32,
+    pub len: u32,
+    pub flags: u32,
+    pub reserved: u32,
+    pub name: String32,
+}
+
+/// This is the structure for `ResourceType::DEVMEM`.
+#[repr(C)]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct Devmem {
+    pub rtype: ResourceType,
+    pub da: u32,
+    pub pa: u32,
+    pub len: u32,
+    pub flags: u32,
+    pub reserved: u32,
+    pub name: String32,
+}
+
+/// This is the structure for `ResourceType::TRACE`.
+#[repr(C)]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct Trace {
+    pub rtype: ResourceType,
+    pub da: u32,
+    pub len: u32,
+    pub reserved: u32,
+    pub name: String32,
+}
+
+/// This is the structure for `ResourceType::VDEV`. It must be followed by the
+/// appropriate number of `VdevVring` structures.
+#[repr(C)]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct Vdev {
+    pub rtype: ResourceType,
+    pub id: u32,
+    pub notifyid: u32,
+    pub dfeatures: u32,
+    pub gfeatures: u32,
+    pub config_len: u32,
+    pub status: u8,
+    pub num_of_vrings: u8,
+    pub reserved: [u8; 2],
+}
+
+/// The individual vrings follow on from their `Vdev`.
+#[repr(C)]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct VdevVring {
+    pub da: u32,
+    pub align: u32,
+    pub num: u32,
+    pub notifyid: u32,
+    pub reserved: u32,
+}
+
+/// This is the structure for `ResourceType::INTMEM`. Unlike a `Devmem`, the
+/// host doesn't map anything for us; it just learns where the memory is so
+/// it can load sections into it.
+#[repr(C)]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct Intmem {
+    pub rtype: ResourceType,
+    /// Must be `INTMEM_VERSION`
+    pub version: u32,
+    pub da: u32,
+    pub pa: u32,
+    pub len: u32,
+    pub reserved: u32,
+    pub name: String32,
+}
+
+/// This is the structure for `ResourceType::CUSTOM`. The kernel hands the
+/// entry to the platform driver, which decides what to do based on the
+/// `sub_type`.
+#[repr(C)]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct Custom<T> {
+    pub rtype: ResourceType,
+    pub sub_type: u32,
+    /// The size of `data` in bytes
+    pub size: u32,
+    pub data: T,
+}
+
+/// A vendor-specific entry, with a type from `RSC_VENDOR_START` to
+/// `RSC_VENDOR_END` inclusive. The kernel ignores these unless the platform driver
+/// asks for them.
+#[repr(C)]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct Vendor<T> {
+    pub rtype: ResourceType,
+    pub data: T,
+}
+
+/// A reference to one of the structures in a resource table, so that code
+/// can walk any table. See `Table`.
+#[derive(Debug, Clone, Copy)]
+pub enum Resource<'a> {
+    Carveout(&'a Carveout),
+    Devmem(&'a Devmem),
+    Trace(&'a Trace),
+    Vdev(&'a Vdev),
+    /// Part of the most recent `Vdev` entry
+    Vring(&'a VdevVring),
+    Intmem(&'a Intmem),
+    /// A `Custom` entry: the sub-type, the size it claims and the actual
+    /// size of its payload.
+    Custom(u32, u32, usize),
+    /// A `Vendor` entry, with the given type
+    Vendor(u32),
+    /// An entry this crate knows nothing about
+    Other,
+}
+
+/// Implemented by everything which can go in a `resource_table!`.
+pub trait AsResource {
+    fn as_resource(&self) -> Resource<'_>;
+}
+
+/// Implemented by every table `resource_table!` generates.
+pub trait Table {
+    /// Call `f` with every entry in the table, and every part of every
+    /// entry, in order.
+    fn for_each_resource<'a, F>(&'a self, f: F)
+    where
+        F: FnMut(Resource<'a>);
+}
+
+pub trait AddressMapper {
+    /// Convert a physical address (e.g. an L3/L4 address) to a device address the Cortex-M4 can use.
+    fn pa_to_da(&self, pa: usize) -> Option<usize>;
+    /// Convert a device address the Cortex-M4 can use to a physical address (e.g. an L3/L4 address).
+    fn da_to_pa(&self, da: usize) -> Option<usize>;
+}
+
+pub trait Region {
+    fn get_pa(&self) -> usize;
+    fn get_da(&self) -> usize;
+    fn get_len(&self) -> usize;
+
+    /// Convert a physical address (e.g. an L3/L4 address) to a device address the Cortex-M4 can use.
+    fn pa_to_da(&self, given_pa: usize) -> Option<usize> {
+        if (given_pa >= self.get_pa()) && (given_pa < (self.get_pa() + self.get_len())) {
+            let offset = given_pa - self.get_pa();
+            Some(self.get_da() + offset)
+        } else {
+            None
+        }
+    }
+
+    /// Convert a device address the Cortex-M4 can use to a physical address (e.g. an L3/L4 address).
+    fn da_to_pa(&self, given_da: usize) -> Option<usize> {
+        if (given_da >= self.get_da()) && (given_da < (self.get_da() + self.get_len())) {
+            let offset = given_da - self.get_da();
+            Some(self.get_pa() + offset)
+        } else {
+            None
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The only version of `Intmem` there is.
+pub const INTMEM_VERSION: u32 = 1;
+
+/// The lowest resource type reserved for vendor-specific entries.
+pub const RSC_VENDOR_START: u32 = 128;
+/// The highest resource type reserved for vendor-specific entries. The
+/// range is inclusive, as in the kernel's `include/linux/remoteproc.h`.
+pub const RSC_VENDOR_END: u32 = 512;
+
+// Size constants must match those used on host: include/asm-generic/sizes.h
+
+#[allow(dead_code)]
+pub const SZ_64K: u32 = 0x00010000;
+#[allow(dead_code)]
+pub const SZ_128K: u32 = 0x00020000;
+#[allow(dead_code)]
+pub const SZ_256K: u32 = 0x00040000;
+#[allow(dead_code)]
+pub const SZ_512K: u32 = 0x00080000;
+#[allow(dead_code)]
+pub const SZ_1M: u32 = 0x00100000;
+#[allow(dead_code)]
+pub const SZ_2M: u32 = 0x00200000;
+#[allow(dead_code)]
+pub const SZ_4M: u32 = 0x00400000;
+#[allow(dead_code)]
+pub const SZ_8M: u32 = 0x00800000;
+#[allow(dead_code)]
+pub const SZ_16M: u32 = 0x01000000;
+#[allow(dead_code)]
+pub const SZ_32M: u32 = 0x02000000;
+#[allow(dead_code)]
+pub const SZ_64M: u32 = 0x04000000;
+#[allow(dead_code)]
+pub const SZ_128M: u32 = 0x08000000;
+#[allow(dead_code)]
+pub const SZ_256M: u32 = 0x10000000;
+#[allow(dead_code)]
+pub const SZ_512M: u32 = 0x20000000;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// The kernel's layout, from include/linux/remoteproc.h. These hold on a
+// 64-bit host too, so host tools can use these types to read a table.
+const_assert!(HEADER_SIZE, __mem::size_of::<Header>() == 16);
+const_assert!(CARVEOUT_SIZE, __mem::size_of::<Carveout>() == 56);
+const_assert!(DEVMEM_SIZE, __mem::size_of::<Devmem>() == 56);
+const_assert!(TRACE_SIZE, __mem::size_of::<Trace>() == 48);
+const_assert!(VDEV_SIZE, __mem::size_of::<Vdev>() == 28);
+const_assert!(VRING_SIZE, __mem::size_of::<VdevVring>() == 20);
+const_assert!(INTMEM_SIZE, __mem::size_of::<Intmem>() == 56);
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl ResourceType {
+    /// Get the host to allocate you some memory
+    pub const CARVEOUT: ResourceType = ResourceType(0);
+    /// Map some MMIO registers in
+    pub const DEVMEM: ResourceType = ResourceType(1);
+    /// Point at a buffer where you can write strings
+    pub const TRACE: ResourceType = ResourceType(2);
+    /// Map a VirtIO device
+    pub const VDEV: ResourceType = ResourceType(3);
+    /// Publish some internal memory (like the L2RAM) to the host
+    pub const INTMEM: ResourceType = ResourceType(4);
+    /// Something implementation specific, identified by a sub-type
+    pub const CUSTOM: ResourceType = ResourceType(5);
+
+    /// Is this one of the types reserved for vendor-specific entries?
+    pub fn is_vendor(self) -> bool {
+        (RSC_VENDOR_START..=RSC_VENDOR_END).contains(&self.0)
+    }
+}
+
+impl AsResource for Carveout {
+    fn as_resource(&self) -> Resource<'_> {
+        Resource::Carveout(self)
+    }
+}
+
+impl AsResource for Devmem {
+    fn as_resource(&self) -> Resource<'_> {
+        Resource::Devmem(self)
+    }
+}
+
+impl AsResource for Trace {
+    fn as_resource(&self) -> Resource<'_> {
+        Resource::Trace(self)
+    }
+}
+
+impl AsResource for Vdev {
+    fn as_resource(&self) -> Resource<'_> {
+        Resource::Vdev(self)
+    }
+}
+
+impl AsResource for VdevVring {
+    fn as_resource(&self) -> Resource<'_> {
+        Resource::Vring(self)
+    }
+}
+
+impl AsResource for Intmem {
+    fn as_resource(&self) -> Resource<'_> {
+        Resource::Intmem(self)
+    }
+}
+
+impl<T> AsResource for Custom<T> {
+    fn as_resource(&self) -> Resource<'_> {
+        Resource::Custom(self.sub_type, self.size, __mem::size_of::<T>())
+    }
+}
+
+impl<T> AsResource for Vendor<T> {
+    fn as_resource(&self) -> Resource<'_> {
+        Resource::Vendor(self.rtype.0)
+    }
+}
+
+impl Region for Carveout {
+    fn get_pa(&self) -> usize {
+        unsafe { ::core::ptr::read_volatile(&self.pa) as usize }
+    }
+    fn get_da(&self) -> usize {
+        self.da as usize
+    }
+    fn get_len(&self) -> usize {
+        self.len as usize
+    }
+}
+
+impl Region for Devmem {
+    fn get_pa(&self) -> usize {
+        self.pa as usize
+    }
+    fn get_da(&self) -> usize {
+        self.da as usize
+    }
+    fn get_len(&self) -> usize {
+        self.len as usize
+    }
+}
+
+impl Region for Intmem {
+    fn get_pa(&self) -> usize {
+        self.pa as usize
+    }
+    fn get_da(&self) -> usize {
+        self.da as usize
+    }
+    fn get_len(&self) -> usize {
+        self.len as usize
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+// None
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An entry with an awkward size, to make sure padding is accounted for.
+    #[repr(C)]
+    #[derive(Debug)]
+    pub struct Odd {
+        pub data: [u8; 5],
+    }
+
+    impl AsResource for Odd {
+        fn as_resource(&self) -> Resource<'_> {
+            Resource::Other
+        }
+    }
+
+    resource_table! {
+        pub struct TestTable;
+
+        pub static TEST_TABLE = {
+            vdev: Vdev = Vdev {
+                rtype: ResourceType::VDEV,
+                id: 7,
+                notifyid: 0,
+                dfeatures: 1,
+                gfeatures: 0,
+                config_len: 0,
+                status: 0,
+                num_of_vrings: 2,
+                reserved: [0, 0],
+            }
+                => vring0: VdevVring = VdevVring {
+                    da: 0x6000_0000,
+                    align: 4096,
+                    num: 256,
+                    notifyid: 1,
+                    reserved: 0,
+                }
+                => vring1: VdevVring = VdevVring {
+                    da: 0x6000_4000,
+                    align: 4096,
+                    num: 256,
+                    notifyid: 2,
+                    reserved: 0,
+                };
+            odd: Odd = Odd { data: [1; 5] };
+            custom: Custom<u32> = Custom {
+                rtype: ResourceType::CUSTOM,
+                sub_type: 7,
+                size: 4,
+                data: 5000,
+            };
+            vendor: Vendor<[u8; 3]> = Vendor {
+                rtype: ResourceType(RSC_VENDOR_START),
+                data: [1, 2, 3],
+            };
+            carveout: Carveout = Carveout {
+                rtype: ResourceType::CARVEOUT,
+                da: 0,
+                pa: 0,
+                len: SZ_1M,
+                flags: 0,
+                reserved: 0,
+                name: String32 { buffer: [0; 32] },
+            };
+            trace: Trace = Trace {
+                rtype: ResourceType::TRACE,
+                da: 0x9F00_0000,
+                len: 16384,
+                reserved: 0,
+                name: String32 { buffer: [0; 32] },
+            };
+        };
+    }
+
+    fn offset_of<T>(field: &T) -> u32 {
+        (field as *const T as usize - &TEST_TABLE as *const TestTable as usize) as u32
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(TEST_TABLE.base.num, 6);
+        assert_eq!(
+            TEST_TABLE.offsets,
+            [
+                offset_of(&TEST_TABLE.vdev),
+                offset_of(&TEST_TABLE.odd),
+                offset_of(&TEST_TABLE.custom),
+                offset_of(&TEST_TABLE.vendor),
+                offset_of(&TEST_TABLE.carveout),
+                offset_of(&TEST_TABLE.trace),
+            ]
+        );
+        // The vrings are part of the vdev entry
+        assert!(offset_of(&TEST_TABLE.vring1) < TEST_TABLE.offsets[1]);
+    }
+
+    #[test]
+    fn validation() {
+        // Nothing maps the vrings or the trace buffer
+        let mut found = Vec::new();
+        let count = validate::validate(&TEST_TABLE, |v| found.push(format!("{:?}", v)));
+        assert_eq!(count, 3);
+        assert!(found[0].starts_with("VringNotMapped"));
+        assert!(found[1].starts_with("VringNotMapped"));
+        assert!(found[2].starts_with("TraceNotMapped"));
+
+        assert_eq!(validate::vring_size(256, 4096), 0x2000 + 6 + 8 * 256);
+    }
+
+    resource_table! {
+        pub struct BadAlignTable;
+
+        pub static BAD_ALIGN_TABLE = {
+            vdev: Vdev = Vdev {
+                rtype: ResourceType::VDEV,
+                id: 7,
+                notifyid: 0,
+                dfeatures: 1,
+                gfeatures: 0,
+                config_len: 0,
+                status: 0,
+                num_of_vrings: 2,
+                reserved: [0, 0],
+            }
+                => vring0: VdevVring = VdevVring {
+                    da: 0x6000_0000,
+                    align: 0,
+                    num: 256,
+                    notifyid: 1,
+                    reserved: 0,
+                }
+                => vring1: VdevVring = VdevVring {
+                    da: 0x6000_4000,
+                    align: 3000,
+                    num: 256,
+                    notifyid: 2,
+                    reserved: 0,
+                };
+            devmem: Devmem = Devmem {
+                rtype: ResourceType::DEVMEM,
+                da: 0x6000_0000,
+                pa: 0x4A00_0000,
+                len: SZ_1M,
+                flags: 0,
+                reserved: 0,
+                name: String32 { buffer: [0; 32] },
+            };
+        };
+    }
+
+    #[test]
+    fn vring_align() {
+        // Reported, rather than overflowing while working out the vring size
+        let mut found = Vec::new();
+        let count = validate::validate(&BAD_ALIGN_TABLE, |v| found.push(format!("{:?}", v)));
+        assert_eq!(count, 2);
+        assert!(found[0].starts_with("VringAlign"));
+        assert!(found[1].starts_with("VringAlign"));
+    }
+
+    #[test]
+    fn vendor_range() {
+        assert!(!ResourceType(RSC_VENDOR_START - 1).is_vendor());
+        assert!(ResourceType(RSC_VENDOR_START).is_vendor());
+        assert!(ResourceType(RSC_VENDOR_END).is_vendor());
+        assert!(!ResourceType(RSC_VENDOR_END + 1).is_vendor());
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,425 @@
+//! # Address Mapping
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! A `RegionMap` converts between physical and device addresses using a set
+//! of `Region`s, usually the carveouts, devmems and intmems in a resource
+//! table. The regions are kept sorted by both kinds of address, so a lookup
+//! is a binary search rather than a walk over every entry, which matters
+//! as the mailbox code does a lookup on every access, even in interrupt
+//! context.
+//!
+//! The map has a fixed capacity and needs no allocator, so it can live in a
+//! `static` and be filled in at start-up.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::{AddressMapper, Region, Resource, Table};
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// One entry in a `RegionMap`: `len` bytes at device address `da` are
+/// physical addresses `pa` onwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub pa: usize,
+    pub da: usize,
+    pub len: usize,
+}
+
+/// Which address space two regions overlap in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Space {
+    Physical,
+    Device,
+}
+
+/// Reasons a region can't be added to a `RegionMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The map already holds `MAX_REGIONS` regions
+    Full,
+    /// The new region (the second) overlaps one already in the map (the
+    /// first), so some addresses would have two translations.
+    Overlap(Space, Mapping, Mapping),
+}
+
+/// Translates addresses using a set of non-overlapping regions.
+///
+/// A region with a physical address of zero is a carveout the kernel hasn't
+/// allocated yet. It has no translation either way, but it still reserves
+/// its device addresses, so other regions can't overlap it.
+pub struct RegionMap {
+    by_pa: [Mapping; MAX_REGIONS],
+    num_pa: usize,
+    by_da: [Mapping; MAX_REGIONS],
+    num_da: usize,
+    /// Unallocated carveouts, sorted by device address
+    unallocated: [Mapping; MAX_REGIONS],
+    num_unallocated: usize,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The most regions a `RegionMap` can hold.
+pub const MAX_REGIONS: usize = 32;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const UNUSED: Mapping = Mapping {
+    pa: 0,
+    da: 0,
+    len: 0,
+};
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl Mapping {
+    /// The mapping a region describes.
+    pub fn of<R>(region: &R) -> Mapping
+    where
+        R: Region + ?Sized,
+    {
+        Mapping {
+            pa: region.get_pa(),
+            da: region.get_da(),
+            len: region.get_len(),
+        }
+    }
+}
+
+impl RegionMap {
+    /// An empty map, which translates nothing.
+    pub const fn new() -> RegionMap {
+        RegionMap {
+            by_pa: [UNUSED; MAX_REGIONS],
+            num_pa: 0,
+            by_da: [UNUSED; MAX_REGIONS],
+            num_da: 0,
+            unallocated: [UNUSED; MAX_REGIONS],
+            num_unallocated: 0,
+        }
+    }
+
+    /// Build a map from the given regions.
+    pub fn from_regions<R>(regions: &[&R]) -> Result<RegionMap, Error>
+    where
+        R: Region + ?Sized,
+    {
+        let mut map = RegionMap::new();
+        for region in regions {
+            map.add(*region)?;
+        }
+        Ok(map)
+    }
+
+    /// Build a map from every carveout, devmem and intmem in a table.
+    pub fn from_table<T>(table: &T) -> Result<RegionMap, Error>
+    where
+        T: Table,
+    {
+        let mut map = RegionMap::new();
+        map.add_table(table)?;
+        Ok(map)
+    }
+
+    /// Add every carveout, devmem and intmem in a table. Stops at the first
+    /// one which can't be added.
+    pub fn add_table<T>(&mut self, table: &T) -> Result<(), Error>
+    where
+        T: Table,
+    {
+        let mut result = Ok(());
+        table.for_each_resource(|r| {
+            if result.is_ok() {
+                result = match r {
+                    Resource::Carveout(c) => self.add(c),
+                    Resource::Devmem(d) => self.add(d),
+                    Resource::Intmem(i) => self.add(i),
+                    _ => Ok(()),
+                };
+            }
+        });
+        result
+    }
+
+    /// Add a region. Empty regions are ignored.
+    pub fn add<R>(&mut self, region: &R) -> Result<(), Error>
+    where
+        R: Region + ?Sized,
+    {
+        let new = Mapping::of(region);
+        if new.len == 0 {
+            return Ok(());
+        }
+        if (self.num_da + self.num_unallocated) == MAX_REGIONS {
+            return Err(Error::Full);
+        }
+        let da_idx = find_slot(&self.by_da[..self.num_da], new, Space::Device)?;
+        let unallocated_idx = find_slot(
+            &self.unallocated[..self.num_unallocated],
+            new,
+            Space::Device,
+        )?;
+        if new.pa == 0 {
+            insert(
+                &mut self.unallocated,
+                &mut self.num_unallocated,
+                unallocated_idx,
+                new,
+            );
+            return Ok(());
+        }
+        let pa_idx = find_slot(&self.by_pa[..self.num_pa], new, Space::Physical)?;
+        // Only change anything once we know both inserts will work
+        insert(&mut self.by_da, &mut self.num_da, da_idx, new);
+        insert(&mut self.by_pa, &mut self.num_pa, pa_idx, new);
+        Ok(())
+    }
+
+    /// Forget every region.
+    pub fn clear(&mut self) {
+        self.num_pa = 0;
+        self.num_da = 0;
+        self.num_unallocated = 0;
+    }
+
+    /// The regions in the map which translate addresses, in device address
+    /// order.
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.by_da[..self.num_da]
+    }
+
+    /// The carveouts the kernel hasn't allocated yet, in device address
+    /// order.
+    pub fn unallocated(&self) -> &[Mapping] {
+        &self.unallocated[..self.num_unallocated]
+    }
+}
+
+impl Default for RegionMap {
+    fn default() -> RegionMap {
+        RegionMap::new()
+    }
+}
+
+impl AddressMapper for RegionMap {
+    fn pa_to_da(&self, pa: usize) -> Option<usize> {
+        lookup(&self.by_pa[..self.num_pa], pa, Space::Physical).map(|m| m.da + (pa - m.pa))
+    }
+
+    fn da_to_pa(&self, da: usize) -> Option<usize> {
+        lookup(&self.by_da[..self.num_da], da, Space::Device).map(|m| m.pa + (da - m.da))
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// The start of a mapping in the given address space.
+fn start(m: &Mapping, space: Space) -> usize {
+    match space {
+        Space::Physical => m.pa,
+        Space::Device => m.da,
+    }
+}
+
+/// Where `new` goes in `sorted` (which is sorted by `space` address), or the
+/// mapping it overlaps.
+fn find_slot(sorted: &[Mapping], new: Mapping, space: Space) -> Result<usize, Error> {
+    let new_start = start(&new, space);
+    let idx = match sorted.binary_search_by(|m| start(m, space).cmp(&new_start)) {
+        Ok(idx) => return Err(Error::Overlap(space, sorted[idx], new)),
+        Err(idx) => idx,
+    };
+    // Written as differences so regions at the top of memory don't overflow
+    if idx > 0 {
+        let before = sorted[idx - 1];
+        if new_start - start(&before, space) < before.len {
+            return Err(Error::Overlap(space, before, new));
+        }
+    }
+    if idx < sorted.len() {
+        let after = sorted[idx];
+        if start(&after, space) - new_start < new.len {
+            return Err(Error::Overlap(space, after, new));
+        }
+    }
+    Ok(idx)
+}
+
+/// Put `new` at `idx`, shuffling up everything after it.
+fn insert(array: &mut [Mapping; MAX_REGIONS], num: &mut usize, idx: usize, new: Mapping) {
+    let mut i = *num;
+    while i > idx {
+        array[i] = array[i - 1];
+        i -= 1;
+    }
+    array[idx] = new;
+    *num += 1;
+}
+
+/// The mapping in `sorted` which contains `address`.
+fn lookup(sorted: &[Mapping], address: usize, space: Space) -> Option<&Mapping> {
+    let idx = match sorted.binary_search_by(|m| start(m, space).cmp(&address)) {
+        Ok(idx) => idx,
+        Err(0) => return None,
+        Err(idx) => idx - 1,
+    };
+    let m = &sorted[idx];
+    if address - start(m, space) < m.len {
+        Some(m)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct R(usize, usize, usize);
+
+    impl Region for R {
+        fn get_pa(&self) -> usize {
+            self.0
+        }
+        fn get_da(&self) -> usize {
+            self.1
+        }
+        fn get_len(&self) -> usize {
+            self.2
+        }
+    }
+
+    #[test]
+    fn lookups() {
+        let map = RegionMap::from_regions(&[
+            &R(0x4A00_0000, 0x6A00_0000, 0x100_0000),
+            &R(0, 0, 0x10_0000),
+            &R(0x9D00_0000, 0x6000_0000, 0x10_0000),
+            &R(0xFFF0_0000, 0xFFF0_0000, 0x10_0000),
+        ])
+        .unwrap();
+        assert_eq!(map.mappings().len(), 3);
+        assert_eq!(map.unallocated().len(), 1);
+        assert_eq!(map.pa_to_da(0x4A0F_4000), Some(0x6A0F_4000));
+        assert_eq!(map.da_to_pa(0x6000_4000), Some(0x9D00_4000));
+        assert_eq!(map.pa_to_da(0x9D10_0000), None);
+        assert_eq!(map.pa_to_da(0xFFFF_FFFF), Some(0xFFFF_FFFF));
+        // Unallocated carveouts don't translate either way
+        assert_eq!(map.da_to_pa(0x100), None);
+        assert_eq!(map.pa_to_da(0x100), None);
+    }
+
+    #[test]
+    fn unallocated_overlaps() {
+        let mut map = RegionMap::new();
+        map.add(&R(0, 0x1000, 0x1000)).unwrap();
+        match map.add(&R(0x9D00_0000, 0x1800, 0x1000)) {
+            Err(Error::Overlap(Space::Device, _, _)) => {}
+            other => panic!("{:?}", other),
+        }
+        match map.add(&R(0, 0, 0x1001)) {
+            Err(Error::Overlap(Space::Device, _, _)) => {}
+            other => panic!("{:?}", other),
+        }
+        // Two unallocated carveouts don't clash in physical address space
+        map.add(&R(0, 0x2000, 0x1000)).unwrap();
+        assert_eq!(map.unallocated().len(), 2);
+        assert_eq!(map.mappings().len(), 0);
+        map.clear();
+        assert_eq!(map.unallocated().len(), 0);
+    }
+
+    #[test]
+    fn overlaps() {
+        let mut map = RegionMap::new();
+        map.add(&R(0x9D00_0000, 0x6000_0000, 0x10_0000)).unwrap();
+        assert_eq!(
+            map.add(&R(0x8000_0000, 0x600F_F000, 0x1000)),
+            Err(Error::Overlap(
+                Space::Device,
+                Mapping {
+                    pa: 0x9D00_0000,
+                    da: 0x6000_0000,
+                    len: 0x10_0000
+                },
+                Mapping {
+                    pa: 0x8000_0000,
+                    da: 0x600F_F000,
+                    len: 0x1000
+                }
+            ))
+        );
+        match map.add(&R(0x9CFF_F000, 0x7000_0000, 0x2000)) {
+            Err(Error::Overlap(Space::Physical, _, _)) => {}
+            other => panic!("{:?}", other),
+        }
+        // A failed add leaves the map alone
+        assert_eq!(map.mappings().len(), 1);
+        assert_eq!(map.pa_to_da(0x9CFF_F000), None);
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
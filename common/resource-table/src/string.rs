@@ -45,6 +45,7 @@
 // ****************************************************************************
 
 /// Represents a UTF-8 string in a fixed-length 32-byte buffer.
+#[derive(Clone, Copy)]
 pub struct String32 {
     pub buffer: [u8; BUFFER_LEN],
 }
@@ -79,7 +80,7 @@ pub const BUFFER_LEN: usize = 32;
 //
 // ****************************************************************************
 
-impl<'a> ::core::convert::From<&'a str> for String32 {
+impl ::core::convert::From<&str> for String32 {
     /// Converts an `&str` to a fixed 32-byte buffer. If the given string is
     /// too long, it is truncated. The buffer is null padded, not null
     /// terminated.
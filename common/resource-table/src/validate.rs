@@ -0,0 +1,310 @@
+//! # Resource Table Validation
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! The kernel does very little checking of a resource table before acting
+//! on it, and a bad table usually shows up as an IOMMU fault some time
+//! later. This module checks the things that can be checked without
+//! knowing anything about the application: that no two mappings overlap,
+//! that each vdev has the vrings it claims to have, and that the vrings
+//! and trace buffer sit in memory that is actually mapped.
+//!
+//! Anything which can only be known at link time (like whether the trace
+//! buffer really is where the table says it is) has to be checked by the
+//! application, in a host test.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::INTMEM_VERSION;
+use super::{Intmem, Resource, ResourceType, Table, Trace, Vdev, VdevVring};
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// Something wrong with a resource table.
+#[derive(Debug, Clone, Copy)]
+pub enum Violation<'a> {
+    /// A carveout, devmem or intmem with a zero length
+    Empty(Resource<'a>),
+    /// A carveout, devmem or intmem which runs off the end of the 32-bit device
+    /// address space
+    Wraps(Resource<'a>),
+    /// Two carveouts, devmems or intmems which map some of the same device addresses
+    Overlap(Resource<'a>, Resource<'a>),
+    /// A vdev followed by a different number of vrings to its
+    /// `num_of_vrings`
+    VringCount(&'a Vdev, usize),
+    /// A vring whose `align` isn't a non-zero power of two
+    VringAlign(&'a VdevVring),
+    /// A vring which isn't wholly inside a single devmem
+    VringNotMapped(&'a VdevVring),
+    /// A trace buffer which isn't wholly inside a single carveout
+    TraceNotMapped(&'a Trace),
+    /// An intmem with a version other than `INTMEM_VERSION`
+    IntmemVersion(&'a Intmem),
+    /// A custom entry whose `size` isn't the size of its payload
+    CustomSize(Resource<'a>),
+    /// A vendor entry whose type is outside the vendor range
+    VendorType(u32),
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// Device addresses are 32-bit, even if we're running on a 64-bit host.
+const DEVICE_ADDRESS_LIMIT: u64 = 1 << 32;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Check the given table, calling `report` for every problem found. Returns
+/// the number of problems found.
+pub fn validate<'a, T, F>(table: &'a T, mut report: F) -> usize
+where
+    T: Table,
+    F: FnMut(Violation<'a>),
+{
+    let mut count = 0;
+    {
+        let mut report = |v| {
+            count += 1;
+            report(v)
+        };
+        check_regions(table, &mut report);
+        check_vring_counts(table, &mut report);
+        check_mapped(table, &mut report);
+        check_headers(table, &mut report);
+    }
+    count
+}
+
+/// The number of bytes a vring with `num` entries occupies, given the
+/// alignment of its used ring. See section 2.4.2 of the VirtIO 1.0 spec.
+/// `align` must be a non-zero power of two.
+pub fn vring_size(num: usize, align: usize) -> usize {
+    let descriptors = 16 * num;
+    let available = 6 + 2 * num;
+    let used = 6 + 8 * num;
+    align_up(descriptors + available, align) + used
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Check each carveout, devmem and intmem on its own, and against every other.
+fn check_regions<'a, T, F>(table: &'a T, report: &mut F)
+where
+    T: Table,
+    F: FnMut(Violation<'a>),
+{
+    let mut outer_idx = 0;
+    table.for_each_resource(|a| {
+        if let Some((a_start, a_end)) = region(a) {
+            if a_start == a_end {
+                report(Violation::Empty(a));
+            } else if a_end > DEVICE_ADDRESS_LIMIT {
+                report(Violation::Wraps(a));
+            }
+            let mut inner_idx = 0;
+            table.for_each_resource(|b| {
+                if inner_idx > outer_idx {
+                    if let Some((b_start, b_end)) = region(b) {
+                        if a_start < b_end && b_start < a_end {
+                            report(Violation::Overlap(a, b));
+                        }
+                    }
+                }
+                inner_idx += 1;
+            });
+        }
+        outer_idx += 1;
+    });
+}
+
+/// Check each vdev is followed by the right number of vrings.
+fn check_vring_counts<'a, T, F>(table: &'a T, report: &mut F)
+where
+    T: Table,
+    F: FnMut(Violation<'a>),
+{
+    let mut current: Option<(&Vdev, usize)> = None;
+    {
+        let mut finish = |current: Option<(&'a Vdev, usize)>| {
+            if let Some((vdev, found)) = current {
+                if found != vdev.num_of_vrings as usize {
+                    report(Violation::VringCount(vdev, found));
+                }
+            }
+        };
+        table.for_each_resource(|r| match r {
+            Resource::Vring(_) => {
+                if let Some((_, ref mut found)) = current {
+                    *found += 1;
+                }
+            }
+            Resource::Vdev(vdev) => {
+                finish(current.take());
+                current = Some((vdev, 0));
+            }
+            _ => finish(current.take()),
+        });
+        finish(current.take());
+    }
+}
+
+/// Check the vrings and trace buffer are in memory the host has mapped for
+/// us.
+fn check_mapped<'a, T, F>(table: &'a T, report: &mut F)
+where
+    T: Table,
+    F: FnMut(Violation<'a>),
+{
+    table.for_each_resource(|r| match r {
+        Resource::Vring(vring) if !vring.align.is_power_of_two() => {
+            report(Violation::VringAlign(vring));
+        }
+        Resource::Vring(vring) => {
+            let start = vring.da as u64;
+            let end = start + vring_size(vring.num as usize, vring.align as usize) as u64;
+            if !inside(table, start, end, devmem_region) {
+                report(Violation::VringNotMapped(vring));
+            }
+        }
+        Resource::Trace(trace) => {
+            let start = trace.da as u64;
+            let end = start + trace.len as u64;
+            if !inside(table, start, end, carveout_region) {
+                report(Violation::TraceNotMapped(trace));
+            }
+        }
+        _ => {}
+    });
+}
+
+/// Check the fields of each entry which have to hold particular values.
+fn check_headers<'a, T, F>(table: &'a T, report: &mut F)
+where
+    T: Table,
+    F: FnMut(Violation<'a>),
+{
+    table.for_each_resource(|r| match r {
+        Resource::Intmem(intmem) if intmem.version != INTMEM_VERSION => {
+            report(Violation::IntmemVersion(intmem))
+        }
+        Resource::Custom(_, size, actual) if size as usize != actual => {
+            report(Violation::CustomSize(r))
+        }
+        Resource::Vendor(rtype) if !ResourceType(rtype).is_vendor() => {
+            report(Violation::VendorType(rtype))
+        }
+        _ => {}
+    });
+}
+
+/// Is `start..end` wholly inside one of the regions `region` picks out?
+fn inside<T, F>(table: &T, start: u64, end: u64, region: F) -> bool
+where
+    T: Table,
+    F: Fn(Resource) -> Option<(u64, u64)>,
+{
+    let mut found = false;
+    table.for_each_resource(|r| {
+        if let Some((r_start, r_end)) = region(r) {
+            if start >= r_start && end <= r_end {
+                found = true;
+            }
+        }
+    });
+    found
+}
+
+/// The device addresses a carveout, devmem or intmem covers.
+fn region(r: Resource) -> Option<(u64, u64)> {
+    match r {
+        Resource::Intmem(i) => Some((i.da as u64, i.da as u64 + i.len as u64)),
+        _ => carveout_region(r).or_else(|| devmem_region(r)),
+    }
+}
+
+fn carveout_region(r: Resource) -> Option<(u64, u64)> {
+    if let Resource::Carveout(c) = r {
+        Some((c.da as u64, c.da as u64 + c.len as u64))
+    } else {
+        None
+    }
+}
+
+fn devmem_region(r: Resource) -> Option<(u64, u64)> {
+    if let Resource::Devmem(d) = r {
+        Some((d.da as u64, d.da as u64 + d.len as u64))
+    } else {
+        None
+    }
+}
+
+fn align_up(offset: usize, align: usize) -> usize {
+    (offset + align - 1) & !(align - 1)
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,445 @@
+//! # rpmsg-fragment - Message fragmentation over rpmsg
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! rpmsg buffers are 512 bytes, including the 16 byte rpmsg header, so
+//! larger messages must be split up. Every fragment starts with a small
+//! sub-header giving the message ID, the fragment index and the total
+//! length of the message. Fragments are expected to arrive in order, which
+//! rpmsg guarantees for a single channel.
+//!
+//! This crate does not allocate, so it can be used by both the IPU firmware
+//! and the Linux application.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate le_bytes;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use le_bytes::{read_u16, read_u32, write_u16, write_u32};
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// The sub-header at the start of every fragment. On the wire, each field
+/// is little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    /// Identifies which message this fragment belongs to
+    pub message_id: u16,
+    /// Counts up from zero for each fragment in a message
+    pub index: u16,
+    /// The length of the whole message, not this fragment
+    pub total_length: u32,
+}
+
+/// Splits a message in to fragments.
+#[derive(Debug)]
+pub struct Fragmenter<'a> {
+    message_id: u16,
+    payload: &'a [u8],
+    max_fragment_len: usize,
+    offset: usize,
+    index: u16,
+    done: bool,
+}
+
+/// Joins fragments back together in to a message, using a buffer supplied
+/// by the caller.
+#[derive(Debug)]
+pub struct Reassembler<'a> {
+    buffer: &'a mut [u8],
+    timeout: u32,
+    partial: Option<Partial>,
+    stats: Stats,
+}
+
+/// Counters kept by a `Reassembler`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    /// Messages successfully reassembled
+    pub completed: u32,
+    /// Partial messages discarded because the next fragment was too slow
+    pub timeouts: u32,
+    /// Messages discarded because they were larger than our buffer
+    pub out_of_memory: u32,
+    /// Fragments discarded because they didn't follow on from the last one
+    pub out_of_sequence: u32,
+    /// Fragments discarded because they carried more data than the message
+    /// had left
+    pub overrun: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The fragment is too short to contain a `FragmentHeader`
+    TooShort,
+    /// The output buffer can't hold a header and at least one byte of data
+    BufferTooSmall,
+    /// The message is too large to describe in a `FragmentHeader`
+    TooLarge,
+    /// The message won't fit in the reassembly buffer
+    OutOfMemory,
+    /// The fragment was not the one we were expecting next
+    OutOfSequence,
+    /// The fragment carries more data than the total length allows
+    Overrun,
+    /// The partial message we were assembling was abandoned because the
+    /// next fragment took too long to arrive
+    Timeout,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The length of an encoded `FragmentHeader`.
+pub const HEADER_LEN: usize = 8;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+/// A message we have started to reassemble.
+#[derive(Debug, Clone, Copy)]
+struct Partial {
+    message_id: u16,
+    next_index: u16,
+    total_length: usize,
+    received: usize,
+    last_time: u32,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions / Impl
+//
+// ****************************************************************************
+
+impl FragmentHeader {
+    /// Write the header to the start of `buffer`.
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        if buffer.len() < HEADER_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        write_u16(&mut buffer[0..2], self.message_id);
+        write_u16(&mut buffer[2..4], self.index);
+        write_u32(&mut buffer[4..8], self.total_length);
+        Ok(())
+    }
+
+    /// Read a header from the start of `buffer`.
+    pub fn decode(buffer: &[u8]) -> Result<FragmentHeader, Error> {
+        if buffer.len() < HEADER_LEN {
+            return Err(Error::TooShort);
+        }
+        Ok(FragmentHeader {
+            message_id: read_u16(&buffer[0..2]),
+            index: read_u16(&buffer[2..4]),
+            total_length: read_u32(&buffer[4..8]),
+        })
+    }
+}
+
+impl<'a> Fragmenter<'a> {
+    /// Prepare to split `payload` in to fragments of at most
+    /// `max_fragment_len` bytes each (including the sub-header). For rpmsg,
+    /// that's the buffer size less the rpmsg header.
+    pub fn new(
+        message_id: u16,
+        payload: &'a [u8],
+        max_fragment_len: usize,
+    ) -> Result<Fragmenter<'a>, Error> {
+        if max_fragment_len <= HEADER_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        let frag_data_len = max_fragment_len - HEADER_LEN;
+        let num_fragments = count_fragments(payload.len(), frag_data_len);
+        if (payload.len() > 0xFFFF_FFFF) || (num_fragments > 0x1_0000) {
+            return Err(Error::TooLarge);
+        }
+        Ok(Fragmenter {
+            message_id,
+            payload,
+            max_fragment_len,
+            offset: 0,
+            index: 0,
+            done: false,
+        })
+    }
+
+    /// How many fragments this message needs. An empty message still needs
+    /// one fragment, so the far end knows about it.
+    pub fn num_fragments(&self) -> usize {
+        let frag_data_len = self.max_fragment_len - HEADER_LEN;
+        count_fragments(self.payload.len(), frag_data_len).max(1)
+    }
+
+    /// Write the next fragment to `buffer`, returning its length, or `None`
+    /// if all the fragments have been produced. `buffer` must be able to
+    /// hold a header and at least one byte of data, but it can be smaller
+    /// than `max_fragment_len`.
+    pub fn next_fragment(&mut self, buffer: &mut [u8]) -> Option<Result<usize, Error>> {
+        if self.done {
+            return None;
+        }
+        if buffer.len() <= HEADER_LEN {
+            return Some(Err(Error::BufferTooSmall));
+        }
+        let space = buffer.len().min(self.max_fragment_len) - HEADER_LEN;
+        let remaining = &self.payload[self.offset..];
+        let len = remaining.len().min(space);
+        let header = FragmentHeader {
+            message_id: self.message_id,
+            index: self.index,
+            total_length: self.payload.len() as u32,
+        };
+        if let Err(e) = header.encode(buffer) {
+            return Some(Err(e));
+        }
+        buffer[HEADER_LEN..HEADER_LEN + len].copy_from_slice(&remaining[0..len]);
+        self.offset += len;
+        self.index = self.index.wrapping_add(1);
+        self.done = self.offset == self.payload.len();
+        Some(Ok(HEADER_LEN + len))
+    }
+}
+
+impl<'a> Reassembler<'a> {
+    /// Create a new reassembler. Messages larger than `buffer` are rejected.
+    /// If the gap between two fragments of a message exceeds `timeout`
+    /// ticks, the partial message is abandoned. You pick the units, as long
+    /// as they match the `now` values given to `push`.
+    pub fn new(buffer: &'a mut [u8], timeout: u32) -> Reassembler<'a> {
+        Reassembler {
+            buffer,
+            timeout,
+            partial: None,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Process a received fragment. `now` is the current time, in ticks.
+    /// When the final fragment of a message arrives, the whole message is
+    /// returned.
+    pub fn push(&mut self, fragment: &[u8], now: u32) -> Result<Option<&[u8]>, Error> {
+        let header = FragmentHeader::decode(fragment)?;
+        let data = &fragment[HEADER_LEN..];
+
+        let timed_out = self.expire(now);
+
+        if header.index == 0 {
+            // Start of a new message - anything partial is abandoned.
+            self.partial = None;
+            let total_length = header.total_length as usize;
+            if total_length > self.buffer.len() {
+                self.stats.out_of_memory = self.stats.out_of_memory.wrapping_add(1);
+                return Err(Error::OutOfMemory);
+            }
+            self.partial = Some(Partial {
+                message_id: header.message_id,
+                next_index: 0,
+                total_length,
+                received: 0,
+                last_time: now,
+            });
+        }
+
+        let mut partial = match self.partial.take() {
+            Some(p) => p,
+            None if timed_out => return Err(Error::Timeout),
+            None => {
+                self.stats.out_of_sequence = self.stats.out_of_sequence.wrapping_add(1);
+                return Err(Error::OutOfSequence);
+            }
+        };
+
+        if (partial.message_id != header.message_id) || (partial.next_index != header.index) {
+            self.stats.out_of_sequence = self.stats.out_of_sequence.wrapping_add(1);
+            return Err(Error::OutOfSequence);
+        }
+
+        if partial.received + data.len() > partial.total_length {
+            self.stats.overrun = self.stats.overrun.wrapping_add(1);
+            return Err(Error::Overrun);
+        }
+
+        self.buffer[partial.received..partial.received + data.len()].copy_from_slice(data);
+        partial.received += data.len();
+        partial.next_index = partial.next_index.wrapping_add(1);
+        partial.last_time = now;
+
+        if partial.received == partial.total_length {
+            self.stats.completed = self.stats.completed.wrapping_add(1);
+            Ok(Some(&self.buffer[0..partial.total_length]))
+        } else {
+            self.partial = Some(partial);
+            Ok(None)
+        }
+    }
+
+    /// Abandon any partial message which has timed out. Returns `true` if
+    /// one was abandoned. `push` does this for you, but you can also call it
+    /// periodically.
+    pub fn expire(&mut self, now: u32) -> bool {
+        let expired = match self.partial {
+            Some(ref p) => now.wrapping_sub(p.last_time) > self.timeout,
+            None => false,
+        };
+        if expired {
+            self.partial = None;
+            self.stats.timeouts = self.stats.timeouts.wrapping_add(1);
+        }
+        expired
+    }
+
+    /// Returns true if we are part way through a message.
+    pub fn is_busy(&self) -> bool {
+        self.partial.is_some()
+    }
+
+    /// Get the counters.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// How many fragments of `frag_data_len` bytes it takes to hold `len` bytes.
+fn count_fragments(len: usize, frag_data_len: usize) -> usize {
+    let whole = len / frag_data_len;
+    if whole * frag_data_len < len {
+        whole + 1
+    } else {
+        whole
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(len: usize, max_fragment_len: usize) {
+        let payload: Vec<u8> = (0..len).map(|x| x as u8).collect();
+        let mut storage = [0u8; 4096];
+        let mut r = Reassembler::new(&mut storage, 10);
+        let mut f = Fragmenter::new(7, &payload, max_fragment_len).unwrap();
+        let expected = f.num_fragments();
+        let mut count = 0;
+        let mut buffer = [0u8; 512];
+        let mut result = None;
+        while let Some(frag_len) = f.next_fragment(&mut buffer) {
+            let frag_len = frag_len.unwrap();
+            assert!(frag_len <= max_fragment_len);
+            assert!(result.is_none());
+            count += 1;
+            if let Some(msg) = r.push(&buffer[0..frag_len], count).unwrap() {
+                result = Some(msg.to_vec());
+            }
+        }
+        assert_eq!(count as usize, expected);
+        assert_eq!(result.unwrap(), payload);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(0, 496);
+        round_trip(1, 496);
+        round_trip(488, 496);
+        round_trip(489, 496);
+        round_trip(4096, 496);
+        round_trip(100, 9);
+    }
+
+    #[test]
+    fn test_errors() {
+        let payload = [0xAAu8; 1000];
+        let mut storage = [0u8; 600];
+        let mut buffer = [0u8; 512];
+
+        // Too big for the reassembly buffer
+        let mut r = Reassembler::new(&mut storage, 10);
+        let mut f = Fragmenter::new(1, &payload, 496).unwrap();
+        let len = f.next_fragment(&mut buffer).unwrap().unwrap();
+        assert_eq!(r.push(&buffer[0..len], 0), Err(Error::OutOfMemory));
+        let len = f.next_fragment(&mut buffer).unwrap().unwrap();
+        assert_eq!(r.push(&buffer[0..len], 0), Err(Error::OutOfSequence));
+        assert_eq!(r.stats().out_of_memory, 1);
+
+        // Second fragment arrives too late
+        let mut f = Fragmenter::new(2, &payload[0..500], 496).unwrap();
+        let len = f.next_fragment(&mut buffer).unwrap().unwrap();
+        assert_eq!(r.push(&buffer[0..len], 100), Ok(None));
+        assert!(r.is_busy());
+        let len = f.next_fragment(&mut buffer).unwrap().unwrap();
+        assert_eq!(r.push(&buffer[0..len], 111), Err(Error::Timeout));
+        assert!(!r.is_busy());
+        assert_eq!(r.stats().timeouts, 1);
+
+        // Final fragment is longer than the message has left
+        let mut f = Fragmenter::new(3, &payload[0..500], 496).unwrap();
+        let len = f.next_fragment(&mut buffer).unwrap().unwrap();
+        assert_eq!(r.push(&buffer[0..len], 200), Ok(None));
+        let len = f.next_fragment(&mut buffer).unwrap().unwrap();
+        assert_eq!(r.push(&buffer[0..len + 1], 200), Err(Error::Overrun));
+        assert_eq!(r.stats().overrun, 1);
+        assert_eq!(r.stats().out_of_sequence, 1);
+
+        // Runt
+        assert_eq!(r.push(&buffer[0..3], 0), Err(Error::TooShort));
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
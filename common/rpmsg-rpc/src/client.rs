@@ -0,0 +1,483 @@
+//! # RPC Client
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! The client end of the RPC layer. It sends calls over a `Transport`, and
+//! matches replies to calls using the call ID. Replies may arrive in any
+//! order, and calls which take too long are failed with
+//! `CallError::Timeout`.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::{Dispatcher, Error, Header, Status, HEADER_LEN, MAX_MESSAGE_LEN};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// Something which can carry calls and replies, like an rpmsg socket.
+pub trait Transport {
+    /// Send one message.
+    fn send(&mut self, message: &[u8]) -> io::Result<()>;
+
+    /// Receive one message in to `buffer`, returning its length. Returns
+    /// `None` if nothing arrives within `timeout`.
+    fn recv(&mut self, buffer: &mut [u8], timeout: Duration) -> io::Result<Option<usize>>;
+
+    /// The longest message `send` can carry. Override this if the transport
+    /// adds anything of its own to each message.
+    fn max_message_len(&self) -> usize {
+        MAX_MESSAGE_LEN
+    }
+}
+
+/// Identifies a call made with `Client::start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CallId(pub u32);
+
+#[derive(Debug)]
+pub enum CallError {
+    /// No reply arrived before the call's deadline
+    Timeout,
+    /// The server replied with something other than `Status::Ok`
+    Status(Status),
+    /// The call was too large to send
+    TooLarge,
+    /// We don't know about this call - perhaps its result was already taken
+    UnknownCall,
+    /// The transport failed
+    Io(io::Error),
+    /// The call couldn't be encoded
+    Rpc(Error),
+}
+
+/// Makes calls and collects the replies.
+pub struct Client<T>
+where
+    T: Transport,
+{
+    transport: T,
+    next_call_id: u32,
+    /// Calls we're waiting for, and their deadlines
+    in_flight: HashMap<u32, Instant>,
+    /// Calls which have finished, but whose result hasn't been taken
+    finished: HashMap<u32, Result<Vec<u8>, CallError>>,
+    /// Replies which didn't match any call, e.g. because it timed out
+    unmatched: u32,
+}
+
+/// A `Transport` which runs calls through a `Dispatcher` in the same
+/// process. Useful for testing clients and handlers without an IPU.
+pub struct Loopback<C> {
+    dispatcher: Dispatcher<C>,
+    context: C,
+    replies: VecDeque<Vec<u8>>,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions / Impl
+//
+// ****************************************************************************
+
+impl<T> Client<T>
+where
+    T: Transport,
+{
+    pub fn new(transport: T) -> Client<T> {
+        Client {
+            transport,
+            next_call_id: 0,
+            in_flight: HashMap::new(),
+            finished: HashMap::new(),
+            unmatched: 0,
+        }
+    }
+
+    /// Send a call without waiting for the reply. Use `wait` or `result` to
+    /// collect it later.
+    pub fn start(
+        &mut self,
+        method_id: u16,
+        request: &[u8],
+        timeout: Duration,
+    ) -> Result<CallId, CallError> {
+        if request.len() > self.transport.max_message_len() - HEADER_LEN {
+            return Err(CallError::TooLarge);
+        }
+        let call_id = self.next_call_id;
+        self.next_call_id = self.next_call_id.wrapping_add(1);
+        let mut buffer = vec![0u8; HEADER_LEN + request.len()];
+        Header {
+            call_id,
+            method_id,
+            status: Status::Ok,
+        }
+        .encode(&mut buffer)?;
+        buffer[HEADER_LEN..].copy_from_slice(request);
+        self.transport.send(&buffer)?;
+        self.in_flight.insert(call_id, Instant::now() + timeout);
+        Ok(CallId(call_id))
+    }
+
+    /// Make a call and wait for the reply.
+    pub fn call(
+        &mut self,
+        method_id: u16,
+        request: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, CallError> {
+        let id = self.start(method_id, request, timeout)?;
+        self.wait(id)
+    }
+
+    /// Wait for the given call to finish, processing replies to other calls
+    /// as they arrive.
+    pub fn wait(&mut self, id: CallId) -> Result<Vec<u8>, CallError> {
+        loop {
+            if let Some(result) = self.finished.remove(&id.0) {
+                return result;
+            }
+            if !self.in_flight.contains_key(&id.0) {
+                return Err(CallError::UnknownCall);
+            }
+            self.poll(Duration::from_secs(1))?;
+        }
+    }
+
+    /// Take the result of the given call, if it has finished.
+    pub fn result(&mut self, id: CallId) -> Option<Result<Vec<u8>, CallError>> {
+        self.finished.remove(&id.0)
+    }
+
+    /// Wait up to `max_wait` for a reply, and fail any calls which have
+    /// passed their deadline. Returns early if a reply arrives or a call
+    /// times out.
+    pub fn poll(&mut self, max_wait: Duration) -> Result<(), CallError> {
+        let now = Instant::now();
+        self.expire(now);
+        let earliest = match self.in_flight.values().min() {
+            Some(deadline) => *deadline,
+            None => return Ok(()),
+        };
+        let wait = if earliest > now {
+            (earliest - now).min(max_wait)
+        } else {
+            Duration::from_millis(0)
+        };
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        if let Some(len) = self.transport.recv(&mut buffer, wait)? {
+            self.handle_reply(&buffer[0..len]);
+        }
+        self.expire(Instant::now());
+        Ok(())
+    }
+
+    /// How many calls are waiting for a reply.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// How many replies didn't match a call in flight.
+    pub fn unmatched(&self) -> u32 {
+        self.unmatched
+    }
+
+    /// Get at the transport.
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    fn handle_reply(&mut self, reply: &[u8]) {
+        let header = match Header::decode(reply) {
+            Ok(h) => h,
+            Err(_) => {
+                self.unmatched += 1;
+                return;
+            }
+        };
+        if self.in_flight.remove(&header.call_id).is_none() {
+            self.unmatched += 1;
+            return;
+        }
+        let result = match header.status {
+            Status::Ok => Ok(reply[HEADER_LEN..].to_vec()),
+            status => Err(CallError::Status(status)),
+        };
+        self.finished.insert(header.call_id, result);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<u32> = self
+            .in_flight
+            .iter()
+            .filter(|&(_, deadline)| *deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.in_flight.remove(&id);
+            self.finished.insert(id, Err(CallError::Timeout));
+        }
+    }
+}
+
+impl<C> Loopback<C> {
+    pub fn new(dispatcher: Dispatcher<C>, context: C) -> Loopback<C> {
+        Loopback {
+            dispatcher,
+            context,
+            replies: VecDeque::new(),
+        }
+    }
+
+    /// Get at the handlers' state.
+    pub fn context(&mut self) -> &mut C {
+        &mut self.context
+    }
+
+    /// How many replies are waiting to be received.
+    pub fn pending(&self) -> usize {
+        self.replies.len()
+    }
+
+    /// Throw away waiting replies, as if they had been lost.
+    pub fn drop_replies(&mut self) {
+        self.replies.clear();
+    }
+
+    /// Swap the order of the waiting replies, as if the server had
+    /// handled the calls in a different order.
+    pub fn reverse_replies(&mut self) {
+        let reversed: VecDeque<Vec<u8>> = self.replies.drain(..).rev().collect();
+        self.replies = reversed;
+    }
+}
+
+impl<C> Transport for Loopback<C> {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let mut reply = [0u8; MAX_MESSAGE_LEN];
+        match self
+            .dispatcher
+            .dispatch(&mut self.context, message, &mut reply)
+        {
+            Ok(len) => {
+                self.replies.push_back(reply[0..len].to_vec());
+                Ok(())
+            }
+            Err(e) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?}", e),
+            )),
+        }
+    }
+
+    fn recv(&mut self, buffer: &mut [u8], _timeout: Duration) -> io::Result<Option<usize>> {
+        match self.replies.pop_front() {
+            Some(reply) => {
+                buffer[0..reply.len()].copy_from_slice(&reply);
+                Ok(Some(reply.len()))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl From<io::Error> for CallError {
+    fn from(e: io::Error) -> CallError {
+        CallError::Io(e)
+    }
+}
+
+impl From<Error> for CallError {
+    fn from(e: Error) -> CallError {
+        CallError::Rpc(e)
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ECHO: u16 = 1;
+    const COUNT: u16 = 2;
+
+    fn echo(_context: &mut u32, request: &[u8], reply: &mut [u8]) -> Result<usize, Status> {
+        if request.len() > reply.len() {
+            return Err(Status::BadRequest);
+        }
+        reply[0..request.len()].copy_from_slice(request);
+        Ok(request.len())
+    }
+
+    fn count(context: &mut u32, _request: &[u8], reply: &mut [u8]) -> Result<usize, Status> {
+        *context += 1;
+        reply[0] = *context as u8;
+        Ok(1)
+    }
+
+    fn client() -> Client<Loopback<u32>> {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(ECHO, echo).unwrap();
+        dispatcher.register(COUNT, count).unwrap();
+        assert_eq!(
+            dispatcher.register(ECHO, echo),
+            Err(Error::AlreadyRegistered)
+        );
+        Client::new(Loopback::new(dispatcher, 0))
+    }
+
+    #[test]
+    fn test_call() {
+        let mut c = client();
+        let timeout = Duration::from_millis(100);
+        assert_eq!(c.call(ECHO, b"hello", timeout).unwrap(), b"hello");
+        assert_eq!(c.call(COUNT, &[], timeout).unwrap(), &[1]);
+        match c.call(99, &[], timeout) {
+            Err(CallError::Status(Status::UnknownMethod)) => {}
+            e => panic!("Unexpected result {:?}", e),
+        }
+        assert_eq!(c.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let mut c = client();
+        let timeout = Duration::from_millis(100);
+        let first = c.start(ECHO, b"first", timeout).unwrap();
+        let second = c.start(ECHO, b"second", timeout).unwrap();
+        let third = c.start(COUNT, &[], timeout).unwrap();
+        assert_eq!(c.in_flight(), 3);
+        c.transport().reverse_replies();
+        // Collect them in a different order to the one they arrive in
+        assert_eq!(c.wait(second).unwrap(), b"second");
+        assert_eq!(c.wait(first).unwrap(), b"first");
+        assert_eq!(c.wait(third).unwrap(), &[1]);
+        assert_eq!(c.in_flight(), 0);
+        assert_eq!(c.unmatched(), 0);
+        match c.wait(first) {
+            Err(CallError::UnknownCall) => {}
+            e => panic!("Unexpected result {:?}", e),
+        }
+    }
+
+    struct Tagged(Loopback<u32>);
+
+    impl Transport for Tagged {
+        fn send(&mut self, message: &[u8]) -> io::Result<()> {
+            self.0.send(message)
+        }
+
+        fn recv(&mut self, buffer: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+            self.0.recv(buffer, timeout)
+        }
+
+        fn max_message_len(&self) -> usize {
+            MAX_MESSAGE_LEN - 4
+        }
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut c = client();
+        let timeout = Duration::from_millis(100);
+        let largest = [0u8; MAX_MESSAGE_LEN - HEADER_LEN];
+        assert_eq!(
+            c.call(ECHO, &largest, timeout).unwrap().len(),
+            largest.len()
+        );
+        match c.call(ECHO, &[0u8; MAX_MESSAGE_LEN - HEADER_LEN + 1], timeout) {
+            Err(CallError::TooLarge) => {}
+            e => panic!("Unexpected result {:?}", e),
+        }
+        // A transport which adds to each message leaves less room for calls
+        let mut c = Client::new(Tagged(c.transport));
+        match c.call(ECHO, &largest, timeout) {
+            Err(CallError::TooLarge) => {}
+            e => panic!("Unexpected result {:?}", e),
+        }
+        match CallError::from(Error::BufferTooSmall) {
+            CallError::Rpc(Error::BufferTooSmall) => {}
+            e => panic!("Unexpected result {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut c = client();
+        let slow = c.start(ECHO, b"slow", Duration::from_millis(10)).unwrap();
+        let fast = c.start(ECHO, b"fast", Duration::from_millis(1000)).unwrap();
+        c.transport().drop_replies();
+        match c.wait(slow) {
+            Err(CallError::Timeout) => {}
+            e => panic!("Unexpected result {:?}", e),
+        }
+        assert_eq!(c.in_flight(), 1);
+        assert!(c.result(fast).is_none());
+        // A late reply to the slow call is counted, not delivered
+        c.transport().send(&[0, 0, 0, 0, 1, 0, 0, 0]).unwrap();
+        c.poll(Duration::from_millis(0)).unwrap();
+        assert_eq!(c.unmatched(), 1);
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
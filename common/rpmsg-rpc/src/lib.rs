@@ -0,0 +1,289 @@
+//! # rpmsg-rpc - Remote procedure calls over rpmsg
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Each request carries a call ID, chosen by the client, and a method ID,
+//! which selects the handler to run on the server. The reply carries the
+//! same call ID, so the client can have several calls in flight and match
+//! each reply to its request. Both start with an eight byte header:
+//!
+//! | Offset | Size | Field                                 |
+//! |--------|------|---------------------------------------|
+//! | 0      | 4    | Call ID (little-endian)               |
+//! | 4      | 2    | Method ID (little-endian)             |
+//! | 6      | 2    | Status (little-endian, zero in calls) |
+//!
+//! The server side (`Dispatcher`) does not allocate, so it can run on the
+//! IPU. The client side (`Client`) needs the `std` feature.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+#[cfg(any(test, feature = "std"))]
+extern crate core;
+extern crate le_bytes;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[cfg(any(test, feature = "std"))]
+pub use client::{CallError, CallId, Client, Loopback, Transport};
+use le_bytes::{read_u16, read_u32, write_u16, write_u32};
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+#[cfg(any(test, feature = "std"))]
+mod client;
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// The header at the start of every call and every reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub call_id: u32,
+    pub method_id: u16,
+    pub status: Status,
+}
+
+/// The outcome of a call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The handler ran, and the reply body is its result
+    Ok,
+    /// The server has no handler for the method ID
+    UnknownMethod,
+    /// The handler didn't like the request body
+    BadRequest,
+    /// The handler couldn't complete the call
+    Failed,
+    /// A status this version of the crate doesn't know about
+    Other(u16),
+}
+
+/// Handles a call. It's given the request body and a buffer for the reply
+/// body, and returns the length of the reply body.
+pub type Handler<C> =
+    fn(context: &mut C, request: &[u8], reply: &mut [u8]) -> Result<usize, Status>;
+
+/// Looks up handlers by method ID and runs them. `C` is whatever state the
+/// handlers need.
+pub struct Dispatcher<C> {
+    handlers: [Option<(u16, Handler<C>)>; MAX_HANDLERS],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The message is too short to contain a `Header`
+    TooShort,
+    /// The output buffer is too small
+    BufferTooSmall,
+    /// There are already `MAX_HANDLERS` handlers registered
+    TooManyHandlers,
+    /// There is already a handler for this method ID
+    AlreadyRegistered,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The length of an encoded `Header`.
+pub const HEADER_LEN: usize = 8;
+
+/// The largest call or reply we can send - an rpmsg buffer, less the rpmsg
+/// header.
+pub const MAX_MESSAGE_LEN: usize = 512 - 16;
+
+/// How many handlers a `Dispatcher` can hold.
+pub const MAX_HANDLERS: usize = 16;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions / Impl
+//
+// ****************************************************************************
+
+impl Header {
+    /// Write the header to the start of `buffer`.
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        if buffer.len() < HEADER_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        write_u32(&mut buffer[0..4], self.call_id);
+        write_u16(&mut buffer[4..6], self.method_id);
+        write_u16(&mut buffer[6..8], self.status.to_u16());
+        Ok(())
+    }
+
+    /// Read a header from the start of `buffer`.
+    pub fn decode(buffer: &[u8]) -> Result<Header, Error> {
+        if buffer.len() < HEADER_LEN {
+            return Err(Error::TooShort);
+        }
+        Ok(Header {
+            call_id: read_u32(&buffer[0..4]),
+            method_id: read_u16(&buffer[4..6]),
+            status: Status::from_u16(read_u16(&buffer[6..8])),
+        })
+    }
+}
+
+impl Status {
+    pub fn to_u16(&self) -> u16 {
+        match *self {
+            Status::Ok => 0,
+            Status::UnknownMethod => 1,
+            Status::BadRequest => 2,
+            Status::Failed => 3,
+            Status::Other(x) => x,
+        }
+    }
+
+    pub fn from_u16(value: u16) -> Status {
+        match value {
+            0 => Status::Ok,
+            1 => Status::UnknownMethod,
+            2 => Status::BadRequest,
+            3 => Status::Failed,
+            x => Status::Other(x),
+        }
+    }
+}
+
+impl<C> Dispatcher<C> {
+    pub fn new() -> Dispatcher<C> {
+        Dispatcher {
+            handlers: [None; MAX_HANDLERS],
+        }
+    }
+
+    /// Register a handler for the given method ID.
+    pub fn register(&mut self, method_id: u16, handler: Handler<C>) -> Result<(), Error> {
+        if self.find(method_id).is_some() {
+            return Err(Error::AlreadyRegistered);
+        }
+        match self.handlers.iter().position(|h| h.is_none()) {
+            Some(idx) => {
+                self.handlers[idx] = Some((method_id, handler));
+                Ok(())
+            }
+            None => Err(Error::TooManyHandlers),
+        }
+    }
+
+    /// Handle a call, writing the reply to `reply`, which should be
+    /// `MAX_MESSAGE_LEN` long. Returns the length of the reply. Calls for
+    /// unknown methods, and calls which fail, still get a reply, with a
+    /// suitable status. Only calls too short to have a header get an
+    /// error, as we can't tell who to reply to.
+    pub fn dispatch(
+        &self,
+        context: &mut C,
+        request: &[u8],
+        reply: &mut [u8],
+    ) -> Result<usize, Error> {
+        let header = Header::decode(request)?;
+        if reply.len() < HEADER_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        let result = match self.find(header.method_id) {
+            Some(handler) => handler(context, &request[HEADER_LEN..], &mut reply[HEADER_LEN..]),
+            None => Err(Status::UnknownMethod),
+        };
+        let (status, body_len) = match result {
+            Ok(len) => (Status::Ok, len),
+            Err(status) => (status, 0),
+        };
+        let reply_header = Header {
+            call_id: header.call_id,
+            method_id: header.method_id,
+            status,
+        };
+        reply_header.encode(reply)?;
+        Ok(HEADER_LEN + body_len)
+    }
+
+    fn find(&self, method_id: u16) -> Option<Handler<C>> {
+        for entry in self.handlers.iter() {
+            if let Some((id, handler)) = *entry {
+                if id == method_id {
+                    return Some(handler);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<C> Default for Dispatcher<C> {
+    fn default() -> Dispatcher<C> {
+        Dispatcher::new()
+    }
+}
+
+impl<C> ::core::fmt::Debug for Dispatcher<C> {
+    fn fmt(&self, fmt: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        let mut list = fmt.debug_list();
+        for &(id, _) in self.handlers.iter().flat_map(|h| h.iter()) {
+            list.entry(&id);
+        }
+        list.finish()
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,478 @@
+//! # ti-messageq - TI IPC 3.x MessageQ framing
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! TI's IPC 3.x stack sends MessageQ messages over rpmsg. Every message
+//! starts with a 32 byte `MessageQ_MsgHeader`, and is sent to the
+//! `rpmsg-proto` endpoint on the remote processor (port 61). Messages to
+//! Linux are sent to the port bound by the destination queue.
+//!
+//! Queues are found by name using the NameServer. Requests are
+//! `NameServerRemote_Msg` structures, sent to the same `rpmsg-proto`
+//! endpoint as MessageQ messages, and told apart by the `NAME_SERVER_TOKEN`
+//! they start with (see `is_name_server_msg`). Responses go to address 0 on
+//! Linux, where LAD listens for them.
+//!
+//! Both structures are encoded little-endian, as per the ARM cores at each
+//! end. The layouts must match `ti/ipc/MessageQ.h` and
+//! `NameServer_daemon.c` in IPC 3.x.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(test)]
+extern crate core;
+extern crate le_bytes;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use le_bytes::{read_u16, read_u32, write_u16, write_u32};
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// Identifies a queue anywhere in the system. The top half is the MultiProc
+/// ID of the processor which owns the queue, and the bottom half is the
+/// queue's index on that processor. On Linux, the index is also the rpmsg
+/// port the queue is bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Normal = 0,
+    High = 1,
+    Reserved = 2,
+    Urgent = 3,
+}
+
+/// `MessageQ_MsgHeader`. The two list pointers at the start aren't
+/// meaningful outside the sender, so they're always sent as zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsgHeader {
+    /// The size of the whole message, including this header
+    pub msg_size: u32,
+    /// Header version, trace flag and priority
+    pub flags: u16,
+    /// Chosen by the application
+    pub msg_id: u16,
+    pub dst_id: u16,
+    pub dst_proc: u16,
+    pub reply_id: u16,
+    pub reply_proc: u16,
+    pub src_proc: u16,
+    pub heap_id: u16,
+    pub seq_num: u16,
+}
+
+/// Whether a `NameServerMsg` is asking or answering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameServerKind {
+    Request,
+    Response,
+}
+
+/// `NameServerRemote_Msg`, used to look up a value by name in a remote
+/// NameServer instance. MessageQ uses the instance "MessageQ", and the
+/// value is the `QueueId`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NameServerMsg<'a> {
+    pub kind: NameServerKind,
+    /// Which NameServer table to look in
+    pub instance: &'a str,
+    /// Which entry to look for
+    pub name: &'a str,
+    /// The entry's value, in a response
+    pub value: u32,
+    /// Non-zero in a response if the entry was found
+    pub status: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The input is too short
+    TooShort,
+    /// The output buffer is too small
+    BufferTooSmall,
+    /// The header version isn't one we understand
+    BadVersion(u16),
+    /// A NameServer message didn't start with `NAME_SERVER_TOKEN`
+    BadToken(u32),
+    /// A name isn't valid UTF-8
+    BadName,
+    /// A name is longer than `MAX_NAME_LEN - 1` bytes
+    NameTooLong,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The length of an encoded `MsgHeader`.
+pub const HEADER_LEN: usize = 32;
+
+/// The rpmsg port on the remote processor which receives MessageQ messages.
+pub const MESSAGEQ_PORT: u32 = 61;
+
+/// The rpmsg address on Linux which NameServer responses are sent to.
+pub const NAME_SERVER_ADDR: u32 = 0;
+
+/// Linux binds each queue to an rpmsg port this far above its index.
+pub const PORT_OFFSET: u16 = 0x80;
+
+/// `reply_id` / `reply_proc` when no reply queue has been set.
+pub const INVALID_ID: u16 = 0xFFFF;
+
+/// The header version IPC 3.x uses, in the top bits of `flags`.
+pub const HEADER_VERSION: u16 = 0x2000;
+
+pub const VERSION_MASK: u16 = 0xE000;
+pub const TRACE_MASK: u16 = 0x1000;
+pub const PRIORITY_MASK: u16 = 0x0003;
+
+/// Every `NameServerRemote_Msg` starts with this.
+pub const NAME_SERVER_TOKEN: u32 = 0x5678_ABCD;
+
+/// The NameServer instance MessageQ registers its queues in.
+pub const MESSAGEQ_INSTANCE: &str = "MessageQ";
+
+/// Names in a `NameServerRemote_Msg` are NUL terminated, in this many bytes.
+pub const MAX_NAME_LEN: usize = 80;
+
+/// The length of an encoded `NameServerMsg`.
+pub const NAME_SERVER_MSG_LEN: usize = (5 * 4) + (2 * MAX_NAME_LEN) + MAX_VALUE_LEN;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// Values longer than a `u32` go in a buffer at the end. We only use `u32`
+/// values, but the buffer is still sent.
+const MAX_VALUE_LEN: usize = 300;
+
+const NAME_SERVER_REQUEST: u32 = 0;
+const NAME_SERVER_RESPONSE: u32 = 1;
+
+// ****************************************************************************
+//
+// Public Functions / Impl
+//
+// ****************************************************************************
+
+/// Returns true if a message sent to `MESSAGEQ_PORT` is a
+/// `NameServerRemote_Msg` rather than a MessageQ message. A MessageQ
+/// message starts with a list pointer instead of `NAME_SERVER_TOKEN`.
+pub fn is_name_server_msg(payload: &[u8]) -> bool {
+    (payload.len() >= 4) && (read_u32(&payload[0..4]) == NAME_SERVER_TOKEN)
+}
+
+impl QueueId {
+    pub fn new(proc_id: u16, index: u16) -> QueueId {
+        QueueId(((proc_id as u32) << 16) | (index as u32))
+    }
+
+    pub fn proc_id(&self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+
+    pub fn index(&self) -> u16 {
+        self.0 as u16
+    }
+}
+
+impl MsgHeader {
+    /// A header for a message with a body of `body_len` bytes, at normal
+    /// priority, with no reply queue.
+    pub fn new(msg_id: u16, src_proc: u16, dst: QueueId, body_len: usize) -> MsgHeader {
+        MsgHeader {
+            msg_size: (HEADER_LEN + body_len) as u32,
+            flags: HEADER_VERSION | (Priority::Normal as u16),
+            msg_id,
+            dst_id: dst.index(),
+            dst_proc: dst.proc_id(),
+            reply_id: INVALID_ID,
+            reply_proc: INVALID_ID,
+            src_proc,
+            heap_id: 0,
+            seq_num: 0,
+        }
+    }
+
+    pub fn dst_queue(&self) -> QueueId {
+        QueueId::new(self.dst_proc, self.dst_id)
+    }
+
+    /// Where the sender would like replies to go, as per
+    /// `MessageQ_getReplyQueue`.
+    pub fn reply_queue(&self) -> Option<QueueId> {
+        if self.reply_id == INVALID_ID {
+            None
+        } else {
+            Some(QueueId::new(self.reply_proc, self.reply_id))
+        }
+    }
+
+    /// As per `MessageQ_setReplyQueue`.
+    pub fn set_reply_queue(&mut self, queue: QueueId) {
+        self.reply_id = queue.index();
+        self.reply_proc = queue.proc_id();
+    }
+
+    pub fn priority(&self) -> Priority {
+        match self.flags & PRIORITY_MASK {
+            0 => Priority::Normal,
+            1 => Priority::High,
+            2 => Priority::Reserved,
+            _ => Priority::Urgent,
+        }
+    }
+
+    /// The length of the body, given `msg_size`.
+    pub fn body_len(&self) -> usize {
+        (self.msg_size as usize).saturating_sub(HEADER_LEN)
+    }
+
+    /// Write the header to the start of `buffer`.
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        if buffer.len() < HEADER_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        write_u32(&mut buffer[0..4], 0);
+        write_u32(&mut buffer[4..8], 0);
+        write_u32(&mut buffer[8..12], self.msg_size);
+        write_u16(&mut buffer[12..14], self.flags);
+        write_u16(&mut buffer[14..16], self.msg_id);
+        write_u16(&mut buffer[16..18], self.dst_id);
+        write_u16(&mut buffer[18..20], self.dst_proc);
+        write_u16(&mut buffer[20..22], self.reply_id);
+        write_u16(&mut buffer[22..24], self.reply_proc);
+        write_u16(&mut buffer[24..26], self.src_proc);
+        write_u16(&mut buffer[26..28], self.heap_id);
+        write_u16(&mut buffer[28..30], self.seq_num);
+        write_u16(&mut buffer[30..32], 0);
+        Ok(())
+    }
+
+    /// Read a header from the start of `buffer`.
+    pub fn decode(buffer: &[u8]) -> Result<MsgHeader, Error> {
+        if buffer.len() < HEADER_LEN {
+            return Err(Error::TooShort);
+        }
+        let header = MsgHeader {
+            msg_size: read_u32(&buffer[8..12]),
+            flags: read_u16(&buffer[12..14]),
+            msg_id: read_u16(&buffer[14..16]),
+            dst_id: read_u16(&buffer[16..18]),
+            dst_proc: read_u16(&buffer[18..20]),
+            reply_id: read_u16(&buffer[20..22]),
+            reply_proc: read_u16(&buffer[22..24]),
+            src_proc: read_u16(&buffer[24..26]),
+            heap_id: read_u16(&buffer[26..28]),
+            seq_num: read_u16(&buffer[28..30]),
+        };
+        if (header.flags & VERSION_MASK) != HEADER_VERSION {
+            return Err(Error::BadVersion(header.flags & VERSION_MASK));
+        }
+        if (header.msg_size as usize) < HEADER_LEN {
+            return Err(Error::TooShort);
+        }
+        Ok(header)
+    }
+}
+
+impl<'a> NameServerMsg<'a> {
+    /// Ask for the value of `name` in the `instance` table.
+    pub fn request(instance: &'a str, name: &'a str) -> NameServerMsg<'a> {
+        NameServerMsg {
+            kind: NameServerKind::Request,
+            instance,
+            name,
+            value: 0,
+            status: 0,
+        }
+    }
+
+    /// Answer a request. `value` is `None` if the name wasn't found.
+    pub fn response(&self, value: Option<u32>) -> NameServerMsg<'a> {
+        NameServerMsg {
+            kind: NameServerKind::Response,
+            instance: self.instance,
+            name: self.name,
+            value: value.unwrap_or(0),
+            status: if value.is_some() { 1 } else { 0 },
+        }
+    }
+
+    /// The value, if this is a response and the name was found.
+    pub fn found(&self) -> Option<u32> {
+        if (self.kind == NameServerKind::Response) && (self.status != 0) {
+            Some(self.value)
+        } else {
+            None
+        }
+    }
+
+    /// Write the message to `buffer`, returning the number of bytes used.
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        if buffer.len() < NAME_SERVER_MSG_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        if (self.instance.len() >= MAX_NAME_LEN) || (self.name.len() >= MAX_NAME_LEN) {
+            return Err(Error::NameTooLong);
+        }
+        for b in buffer[0..NAME_SERVER_MSG_LEN].iter_mut() {
+            *b = 0;
+        }
+        let kind = match self.kind {
+            NameServerKind::Request => NAME_SERVER_REQUEST,
+            NameServerKind::Response => NAME_SERVER_RESPONSE,
+        };
+        write_u32(&mut buffer[0..4], NAME_SERVER_TOKEN);
+        write_u32(&mut buffer[4..8], self.value);
+        write_u32(&mut buffer[8..12], kind);
+        write_u32(&mut buffer[12..16], self.status);
+        // valueLen
+        write_u32(&mut buffer[16..20], 4);
+        let instance = &mut buffer[20..20 + MAX_NAME_LEN];
+        instance[0..self.instance.len()].copy_from_slice(self.instance.as_bytes());
+        let name = &mut buffer[20 + MAX_NAME_LEN..20 + (2 * MAX_NAME_LEN)];
+        name[0..self.name.len()].copy_from_slice(self.name.as_bytes());
+        Ok(NAME_SERVER_MSG_LEN)
+    }
+
+    /// Read a message from `buffer`.
+    pub fn decode(buffer: &'a [u8]) -> Result<NameServerMsg<'a>, Error> {
+        if buffer.len() < (20 + (2 * MAX_NAME_LEN)) {
+            return Err(Error::TooShort);
+        }
+        let token = read_u32(&buffer[0..4]);
+        if token != NAME_SERVER_TOKEN {
+            return Err(Error::BadToken(token));
+        }
+        let kind = if read_u32(&buffer[8..12]) == NAME_SERVER_REQUEST {
+            NameServerKind::Request
+        } else {
+            NameServerKind::Response
+        };
+        Ok(NameServerMsg {
+            kind,
+            instance: read_name(&buffer[20..20 + MAX_NAME_LEN])?,
+            name: read_name(&buffer[20 + MAX_NAME_LEN..20 + (2 * MAX_NAME_LEN)])?,
+            value: read_u32(&buffer[4..8]),
+            status: read_u32(&buffer[12..16]),
+        })
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Names are NUL terminated, unless they fill the whole field.
+fn read_name(field: &[u8]) -> Result<&str, Error> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    ::core::str::from_utf8(&field[0..len]).map_err(|_| Error::BadName)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let mut header = MsgHeader::new(0x1234, 2, QueueId::new(0, 0x81), 10);
+        assert_eq!(header.reply_queue(), None);
+        header.set_reply_queue(QueueId::new(2, 1));
+        let mut buffer = [0xFFu8; HEADER_LEN];
+        header.encode(&mut buffer).unwrap();
+        assert_eq!(&buffer[0..12], &[0, 0, 0, 0, 0, 0, 0, 0, 42, 0, 0, 0]);
+        assert_eq!(&buffer[12..16], &[0x00, 0x20, 0x34, 0x12]);
+        assert_eq!(&buffer[16..20], &[0x81, 0x00, 0x00, 0x00]);
+        let decoded = MsgHeader::decode(&buffer).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.dst_queue(), QueueId(0x0000_0081));
+        assert_eq!(decoded.reply_queue(), Some(QueueId(0x0002_0001)));
+        assert_eq!(decoded.body_len(), 10);
+        assert_eq!(decoded.priority(), Priority::Normal);
+
+        buffer[13] = 0x40;
+        assert_eq!(MsgHeader::decode(&buffer), Err(Error::BadVersion(0x4000)));
+        assert_eq!(MsgHeader::decode(&buffer[0..31]), Err(Error::TooShort));
+    }
+
+    #[test]
+    fn test_name_server() {
+        let request = NameServerMsg::request(MESSAGEQ_INSTANCE, "IPU1_DEMO");
+        let mut buffer = [0xFFu8; NAME_SERVER_MSG_LEN];
+        assert_eq!(request.encode(&mut buffer), Ok(NAME_SERVER_MSG_LEN));
+        assert_eq!(&buffer[0..4], &[0xCD, 0xAB, 0x78, 0x56]);
+        assert!(is_name_server_msg(&buffer));
+        let decoded = NameServerMsg::decode(&buffer).unwrap();
+        assert_eq!(decoded, request);
+        assert_eq!(decoded.found(), None);
+
+        let response = decoded.response(Some(0x0002_0000));
+        let mut reply = [0u8; NAME_SERVER_MSG_LEN];
+        response.encode(&mut reply).unwrap();
+        let decoded = NameServerMsg::decode(&reply).unwrap();
+        assert_eq!(decoded.kind, NameServerKind::Response);
+        assert_eq!(decoded.name, "IPU1_DEMO");
+        assert_eq!(decoded.found(), Some(0x0002_0000));
+
+        buffer[0] = 0;
+        assert!(!is_name_server_msg(&buffer));
+        assert_eq!(
+            NameServerMsg::decode(&buffer),
+            Err(Error::BadToken(0x5678_AB00))
+        );
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,407 @@
+//! # Deferred Formatting
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Formatting text on the remote processor is slow and fills the trace
+//! buffer quickly. Instead, a log site can write a `Record`: the ID of its
+//! format string, a timestamp and its raw arguments. The format strings
+//! live in `STRINGS_SECTION`, which is kept in the ELF file but never
+//! loaded, and a string's ID is its address. A host tool with the firmware
+//! ELF can then put the text back together.
+//!
+//! So that records can share the trace buffer with ordinary text, and
+//! `cat` doesn't trip over them, each one is written as a line starting
+//! with `RECORD_START` followed by the record in base64.
+//!
+//! Each entry in `STRINGS_SECTION` is the module path and the format string,
+//! each followed by a NUL. The format strings use `{}` and a subset of the
+//! `core::fmt` options: `{:x}`, `{:X}`, `{:b}`, `{:?}`, an optional `#`,
+//! and an optional width with an optional leading `0`.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use Ring;
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// A record, built up one argument at a time.
+pub struct Record {
+    buffer: [u8; MAX_RECORD_LEN],
+    len: usize,
+}
+
+/// Something which can be an argument in a `Record`.
+pub trait Arg {
+    fn encode(&self, record: &mut Record);
+}
+
+/// A record read back from a line of the trace buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoded<'a> {
+    /// The address of the record's entry in `STRINGS_SECTION`
+    pub id: u32,
+    /// As in the `log` crate, 1 for errors up to 5 for trace
+    pub level: u8,
+    pub seconds: u32,
+    pub micros: u32,
+    args: &'a [u8],
+}
+
+/// An argument read back from a `Decoded` record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value<'a> {
+    Unsigned(u64),
+    Signed(i64),
+    Bool(bool),
+    Char(char),
+    Str(&'a str),
+}
+
+/// Iterates over the arguments in a `Decoded` record.
+#[derive(Debug, Clone)]
+pub struct Args<'a> {
+    data: &'a [u8],
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The ELF section which holds the format strings.
+pub const STRINGS_SECTION: &str = ".trace_strings";
+
+/// The first byte of every line holding a record.
+pub const RECORD_START: u8 = b'@';
+
+/// The longest record, before it's encoded. Arguments which don't fit are
+/// left out.
+pub const MAX_RECORD_LEN: usize = 64;
+
+/// The longest line a record can take up: `RECORD_START`, 86 base64
+/// characters for `MAX_RECORD_LEN` bytes, then a newline.
+pub const MAX_LINE_LEN: usize = 88;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// ID, level, seconds, microseconds
+const HEADER_LEN: usize = 4 + 1 + 4 + 4;
+
+const TAG_UNSIGNED: u8 = b'u';
+const TAG_SIGNED: u8 = b'i';
+const TAG_UNSIGNED64: u8 = b'U';
+const TAG_SIGNED64: u8 = b'I';
+const TAG_BOOL: u8 = b'b';
+const TAG_CHAR: u8 = b'c';
+const TAG_STR: u8 = b's';
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Turn a string in to a fixed-size array at compile time, for an entry in
+/// `STRINGS_SECTION`. `N` must be the length of `s`.
+pub const fn intern<const N: usize>(s: &str) -> [u8; N] {
+    let bytes = s.as_bytes();
+    let mut result = [0u8; N];
+    let mut idx = 0;
+    while idx < N {
+        result[idx] = bytes[idx];
+        idx += 1;
+    }
+    result
+}
+
+/// Read a record back from a line of the trace buffer (with or without its
+/// newline), using `scratch` to hold the decoded bytes. Returns `None` if
+/// the line isn't a record.
+pub fn decode<'a>(line: &[u8], scratch: &'a mut [u8; MAX_RECORD_LEN]) -> Option<Decoded<'a>> {
+    let line = match line.split_last() {
+        Some((&b'\n', rest)) => rest,
+        _ => line,
+    };
+    let (&first, encoded) = line.split_first()?;
+    if first != RECORD_START {
+        return None;
+    }
+    let mut len = 0;
+    let mut bits = 0u32;
+    let mut num_bits = 0;
+    for &c in encoded {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = (bits << 6) | value;
+        num_bits += 6;
+        if num_bits >= 8 {
+            num_bits -= 8;
+            *scratch.get_mut(len)? = (bits >> num_bits) as u8;
+            len += 1;
+        }
+    }
+    let data = &scratch[..len];
+    if data.len() < HEADER_LEN {
+        return None;
+    }
+    Some(Decoded {
+        id: read_u32(&data[0..]),
+        level: data[4],
+        seconds: read_u32(&data[5..]),
+        micros: read_u32(&data[9..]),
+        args: &data[HEADER_LEN..],
+    })
+}
+
+impl Record {
+    /// Start a record for the string at `id`, logged at `level`.
+    pub fn new(id: u32, level: u8) -> Record {
+        let mut record = Record {
+            buffer: [0u8; MAX_RECORD_LEN],
+            len: HEADER_LEN,
+        };
+        record.buffer[0..4].copy_from_slice(&id.to_le_bytes());
+        record.buffer[4] = level;
+        record
+    }
+
+    pub fn set_time(&mut self, seconds: u32, micros: u32) {
+        self.buffer[5..9].copy_from_slice(&seconds.to_le_bytes());
+        self.buffer[9..13].copy_from_slice(&micros.to_le_bytes());
+    }
+
+    /// Add an argument, if there's room.
+    pub fn push<T: Arg>(&mut self, arg: &T) {
+        arg.encode(self);
+    }
+
+    /// Add an argument as a tag and some bytes, if there's room.
+    pub fn push_raw(&mut self, tag: u8, data: &[u8]) {
+        let end = self.len + 1 + data.len();
+        if end <= self.buffer.len() {
+            self.buffer[self.len] = tag;
+            self.buffer[self.len + 1..end].copy_from_slice(data);
+            self.len = end;
+        }
+    }
+
+    /// Write the record to `ring` as a single line.
+    pub fn write_to(&self, ring: &mut Ring) {
+        let mut line = [0u8; MAX_LINE_LEN];
+        line[0] = RECORD_START;
+        let mut len = 1;
+        for chunk in self.buffer[..self.len].chunks(3) {
+            let mut bits = 0u32;
+            for (idx, &b) in chunk.iter().enumerate() {
+                bits |= u32::from(b) << (16 - 8 * idx);
+            }
+            for idx in 0..=chunk.len() {
+                line[len] = BASE64[((bits >> (18 - 6 * idx)) & 0x3F) as usize];
+                len += 1;
+            }
+        }
+        line[len] = b'\n';
+        ring.write(&line[..=len]);
+    }
+}
+
+impl<'a> Decoded<'a> {
+    pub fn args(&self) -> Args<'a> {
+        Args { data: self.args }
+    }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = Value<'a>;
+
+    fn next(&mut self) -> Option<Value<'a>> {
+        let (&tag, rest) = self.data.split_first()?;
+        let (value, len) = match tag {
+            TAG_UNSIGNED if rest.len() >= 4 => (Value::Unsigned(u64::from(read_u32(rest))), 4),
+            TAG_SIGNED if rest.len() >= 4 => (Value::Signed(i64::from(read_u32(rest) as i32)), 4),
+            TAG_UNSIGNED64 if rest.len() >= 8 => (Value::Unsigned(read_u64(rest)), 8),
+            TAG_SIGNED64 if rest.len() >= 8 => (Value::Signed(read_u64(rest) as i64), 8),
+            TAG_BOOL if !rest.is_empty() => (Value::Bool(rest[0] != 0), 1),
+            TAG_CHAR if rest.len() >= 4 => (
+                Value::Char(::core::char::from_u32(read_u32(rest)).unwrap_or('?')),
+                4,
+            ),
+            TAG_STR if !rest.is_empty() && rest.len() > rest[0] as usize => {
+                let len = rest[0] as usize;
+                let s = ::core::str::from_utf8(&rest[1..=len]).unwrap_or("?");
+                (Value::Str(s), len + 1)
+            }
+            _ => {
+                self.data = &[];
+                return None;
+            }
+        };
+        self.data = &rest[len..];
+        Some(value)
+    }
+}
+
+macro_rules! impl_arg {
+    ($tag:expr, $as:ty, $($t:ty),*) => {
+        $(
+            impl Arg for $t {
+                fn encode(&self, record: &mut Record) {
+                    record.push_raw($tag, &(*self as $as).to_le_bytes());
+                }
+            }
+        )*
+    };
+}
+
+impl_arg!(TAG_UNSIGNED, u32, u8, u16, u32, usize);
+impl_arg!(TAG_SIGNED, i32, i8, i16, i32, isize);
+impl_arg!(TAG_UNSIGNED64, u64, u64);
+impl_arg!(TAG_SIGNED64, i64, i64);
+impl_arg!(TAG_CHAR, u32, char);
+
+impl Arg for bool {
+    fn encode(&self, record: &mut Record) {
+        record.push_raw(TAG_BOOL, &[*self as u8]);
+    }
+}
+
+/// Strings longer than 255 bytes are cut short.
+impl Arg for &str {
+    fn encode(&self, record: &mut Record) {
+        let mut len = self.len().min(255);
+        while !self.is_char_boundary(len) {
+            len -= 1;
+        }
+        let end = record.len + 2 + len;
+        if end <= record.buffer.len() {
+            record.buffer[record.len] = TAG_STR;
+            record.buffer[record.len + 1] = len as u8;
+            record.buffer[record.len + 2..end].copy_from_slice(&self.as_bytes()[..len]);
+            record.len = end;
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+fn read_u32(data: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[..4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        const S: &str = concat!("mod", "\0", "{} {}", "\0");
+        static ENTRY: [u8; S.len()] = intern(S);
+        assert_eq!(&ENTRY[..], &b"mod\0{} {}\0"[..]);
+
+        let mut buffer = [0u8; 256];
+        let mut ring = Ring::new(&mut buffer);
+        let mut record = Record::new(0x1234, 3);
+        record.set_time(7, 999_999);
+        record.push(&42u8);
+        record.push(&-5i32);
+        record.push(&0x0123_4567_89AB_CDEFu64);
+        record.push(&true);
+        record.push(&'é');
+        record.push(&"hello");
+        record.write_to(&mut ring);
+
+        let contents = ::read(ring.buffer).unwrap();
+        assert_eq!(contents.first[0], RECORD_START);
+        assert_eq!(*contents.first.last().unwrap(), b'\n');
+        let mut scratch = [0u8; MAX_RECORD_LEN];
+        let decoded = decode(contents.first, &mut scratch).unwrap();
+        assert_eq!(decoded.id, 0x1234);
+        assert_eq!(decoded.level, 3);
+        assert_eq!((decoded.seconds, decoded.micros), (7, 999_999));
+        let args: Vec<Value> = decoded.args().collect();
+        assert_eq!(
+            args,
+            vec![
+                Value::Unsigned(42),
+                Value::Signed(-5),
+                Value::Unsigned(0x0123_4567_89AB_CDEF),
+                Value::Bool(true),
+                Value::Char('é'),
+                Value::Str("hello"),
+            ]
+        );
+    }
+
+    #[test]
+    fn too_many_args() {
+        let mut record = Record::new(0, 1);
+        for _ in 0..100 {
+            record.push(&1u32);
+        }
+        record.push(&"this string is too long to fit");
+        assert!(record.len <= MAX_RECORD_LEN);
+        let mut buffer = [0u8; 256];
+        let mut ring = Ring::new(&mut buffer);
+        record.write_to(&mut ring);
+        let contents = ::read(ring.buffer).unwrap();
+        assert!(contents.len() <= MAX_LINE_LEN);
+
+        // Exactly full
+        let mut record = Record::new(0, 1);
+        for _ in 0..8 {
+            record.push(&1u32);
+        }
+        record.push(&1u64);
+        record.push(&false);
+        assert_eq!(record.len, MAX_RECORD_LEN);
+        let mut buffer = [0u8; 256];
+        let mut ring = Ring::new(&mut buffer);
+        record.write_to(&mut ring);
+        assert_eq!(::read(ring.buffer).unwrap().len(), MAX_LINE_LEN);
+        let mut scratch = [0u8; MAX_RECORD_LEN];
+        let decoded = decode(contents.first, &mut scratch).unwrap();
+        assert_eq!(decoded.args().count(), (MAX_RECORD_LEN - HEADER_LEN) / 5);
+        assert!(decode(b"[    1.000000] INFO  text\n", &mut scratch).is_none());
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
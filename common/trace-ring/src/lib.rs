@@ -0,0 +1,449 @@
+//! # trace-ring - A text ring buffer for remoteproc trace buffers
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! The kernel shows a remote processor's trace buffer as a file in debugfs,
+//! which stops at the first NUL. This crate lays the buffer out so that file
+//! stays readable with `cat`, while a host tool can still put the text back
+//! in order:
+//!
+//! * The first `HEADER_LEN` bytes are a line of text giving the write
+//!   index, the number of times the writer has wrapped and the number of
+//!   bytes dropped because a single write was too big.
+//! * The rest of the buffer, apart from a NUL in the last byte, is a ring of
+//!   text. Until the ring first wraps, the text is followed by NULs.
+//! * Once it has wrapped, `WRAP_MARKER` sits just after the newest byte, so
+//!   `cat` shows the newest text, then the marker, then the oldest text.
+//!
+//! Writes never fail. Any NUL bytes are replaced with `SUBSTITUTE`. The
+//! `deferred` module can also put binary log records in the ring, one per
+//! line.
+//!
+//! This crate does not allocate, so it can be used by both the IPU firmware
+//! and the Linux tools.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(test)]
+extern crate core;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+pub mod deferred;
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// The counters at the start of the buffer. On the wire, each is eight
+/// decimal digits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Where the next byte goes, as an offset from the end of the header
+    pub write: u32,
+    /// How many times the ring has wrapped. Goes back to one, not zero,
+    /// after `COUNTER_MAX`.
+    pub wraps: u32,
+    /// How many bytes were thrown away without being written. Sticks at
+    /// `COUNTER_MAX`.
+    pub dropped: u32,
+}
+
+/// Writes text to a trace buffer.
+#[derive(Debug)]
+pub struct Ring<'a> {
+    buffer: &'a mut [u8],
+    header: Header,
+}
+
+/// The text in a trace buffer, oldest first. It's in two parts because it
+/// may wrap around the end of the ring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contents<'a> {
+    pub header: Header,
+    pub first: &'a [u8],
+    pub second: &'a [u8],
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The length of the header line, including its newline.
+pub const HEADER_LEN: usize = 64;
+
+/// Follows the newest text once the ring has wrapped.
+pub const WRAP_MARKER: &[u8] = b"\n--- wrap ---\n";
+
+/// The smallest buffer `Ring` can use.
+pub const MIN_LEN: usize = HEADER_LEN + WRAP_MARKER.len() + 2;
+
+/// The largest value the header can hold.
+pub const COUNTER_MAX: u32 = 99_999_999;
+
+/// Written in place of any NUL bytes.
+pub const SUBSTITUTE: u8 = b'?';
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const TEMPLATE: &[u8; HEADER_LEN] =
+    b"# trace ring v1: write 00000000 wraps 00000000 dropped 00000000\n";
+
+/// Where the write, wraps and dropped digits start in `TEMPLATE`.
+const FIELDS: [usize; 3] = [23, 38, 55];
+
+const DIGITS: usize = 8;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Find the text in a trace buffer. `buffer` can be the whole buffer, or
+/// what the kernel gives us when we read the trace file, which stops at the
+/// first NUL. Returns `None` if there's no valid header.
+///
+/// The firmware may be writing while we read, so the text can be torn
+/// where it meets `WRAP_MARKER`. The first line is usually partial once the
+/// ring has wrapped.
+pub fn read<'a>(buffer: &'a [u8]) -> Option<Contents<'a>> {
+    let header = Header::parse(buffer)?;
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    let ring = &buffer[HEADER_LEN..end];
+    let write = header.write as usize;
+    if header.wraps == 0 {
+        if write > ring.len() {
+            return None;
+        }
+        return Some(Contents {
+            header,
+            first: &ring[..write],
+            second: &[],
+        });
+    }
+    if write >= ring.len() || ring.len() <= WRAP_MARKER.len() {
+        return None;
+    }
+    let start = (write + WRAP_MARKER.len()) % ring.len();
+    if start > write {
+        Some(Contents {
+            header,
+            first: &ring[start..],
+            second: &ring[..write],
+        })
+    } else {
+        // The marker wrapped, so the text doesn't.
+        Some(Contents {
+            header,
+            first: &ring[start..write],
+            second: &[],
+        })
+    }
+}
+
+impl Header {
+    /// Parse a header from the start of `buffer`.
+    pub fn parse(buffer: &[u8]) -> Option<Header> {
+        if buffer.len() < HEADER_LEN {
+            return None;
+        }
+        let mut values = [0u32; 3];
+        for (idx, (&b, &t)) in buffer.iter().zip(TEMPLATE.iter()).enumerate() {
+            match FIELDS.iter().position(|&f| idx >= f && idx < f + DIGITS) {
+                Some(field) => {
+                    if !b.is_ascii_digit() {
+                        return None;
+                    }
+                    values[field] = values[field] * 10 + u32::from(b - b'0');
+                }
+                None => {
+                    if b != t {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(Header {
+            write: values[0],
+            wraps: values[1],
+            dropped: values[2],
+        })
+    }
+
+    /// Write the header to the first `HEADER_LEN` bytes of `buffer`.
+    pub fn render(&self, buffer: &mut [u8]) {
+        buffer[..HEADER_LEN].copy_from_slice(TEMPLATE);
+        let values = [self.write, self.wraps, self.dropped];
+        for (&start, &value) in FIELDS.iter().zip(values.iter()) {
+            let mut value = value.min(COUNTER_MAX);
+            for d in buffer[start..start + DIGITS].iter_mut().rev() {
+                *d = b'0' + (value % 10) as u8;
+                value /= 10;
+            }
+        }
+    }
+}
+
+impl<'a> Ring<'a> {
+    /// Pick up where the last `Ring` on this buffer left off, or start
+    /// again if the buffer doesn't have a valid header (which it won't at
+    /// boot).
+    ///
+    /// # Panics
+    ///
+    /// If `buffer` is shorter than `MIN_LEN`.
+    pub fn new(buffer: &'a mut [u8]) -> Ring<'a> {
+        assert!(buffer.len() >= MIN_LEN);
+        let ring_len = buffer.len() - HEADER_LEN - 1;
+        let header = match Header::parse(buffer) {
+            Some(h) if (h.write as usize) < ring_len => h,
+            _ => {
+                for b in buffer.iter_mut() {
+                    *b = 0;
+                }
+                let h = Header::default();
+                h.render(buffer);
+                h
+            }
+        };
+        Ring { buffer, header }
+    }
+
+    /// The counters as they are now.
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    /// The most text the ring can hold. Anything older is overwritten.
+    pub fn capacity(&self) -> usize {
+        self.buffer.len() - HEADER_LEN - 1 - WRAP_MARKER.len()
+    }
+
+    /// Append `data`, overwriting the oldest text if need be. If `data` is
+    /// longer than `capacity()`, only the end of it is kept.
+    pub fn write(&mut self, data: &[u8]) {
+        let data = if data.len() > self.capacity() {
+            let excess = data.len() - self.capacity();
+            self.header.dropped = self.header.dropped.saturating_add(excess as u32);
+            &data[excess..]
+        } else {
+            data
+        };
+        let ring_len = self.buffer.len() - HEADER_LEN - 1;
+        let mut write = self.header.write as usize;
+        {
+            let ring = &mut self.buffer[HEADER_LEN..HEADER_LEN + ring_len];
+            for &b in data {
+                ring[write] = if b == 0 { SUBSTITUTE } else { b };
+                write += 1;
+                if write == ring_len {
+                    write = 0;
+                    self.header.wraps = if self.header.wraps >= COUNTER_MAX {
+                        1
+                    } else {
+                        self.header.wraps + 1
+                    };
+                }
+            }
+            if self.header.wraps != 0 {
+                for (idx, &b) in WRAP_MARKER.iter().enumerate() {
+                    ring[(write + idx) % ring_len] = b;
+                }
+            }
+        }
+        self.header.write = write as u32;
+        // Update the header last, so a reader never sees an index pointing
+        // past the text.
+        self.header.render(self.buffer);
+    }
+}
+
+impl<'a> ::core::fmt::Write for Ring<'a> {
+    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl<'a> Contents<'a> {
+    /// The number of bytes of text.
+    pub fn len(&self) -> usize {
+        self.first.len() + self.second.len()
+    }
+
+    /// Is there any text?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// What `cat` would show: everything up to the first NUL.
+    fn cat(buffer: &[u8]) -> &[u8] {
+        let end = buffer.iter().position(|&b| b == 0).unwrap();
+        &buffer[..end]
+    }
+
+    fn text(contents: &Contents) -> Vec<u8> {
+        let mut result = contents.first.to_vec();
+        result.extend_from_slice(contents.second);
+        result
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header {
+            write: 1234,
+            wraps: 56,
+            dropped: COUNTER_MAX + 1,
+        };
+        let mut buffer = [0u8; HEADER_LEN];
+        header.render(&mut buffer);
+        assert_eq!(
+            &buffer[..],
+            &b"# trace ring v1: write 00001234 wraps 00000056 dropped 99999999\n"[..]
+        );
+        assert_eq!(
+            Header::parse(&buffer),
+            Some(Header {
+                dropped: COUNTER_MAX,
+                ..header
+            })
+        );
+        buffer[30] = b'x';
+        assert_eq!(Header::parse(&buffer), None);
+        assert_eq!(Header::parse(&[0u8; HEADER_LEN]), None);
+    }
+
+    #[test]
+    fn before_wrap() {
+        let mut buffer = [0u8; 256];
+        {
+            let mut ring = Ring::new(&mut buffer);
+            ring.write(b"Hello\n");
+            // Picks up where the last one left off
+            let mut ring = Ring::new(ring.buffer);
+            ring.write(b"World\0\n");
+            assert_eq!(ring.header().write, 13);
+        }
+        assert_eq!(&cat(&buffer)[HEADER_LEN..], &b"Hello\nWorld?\n"[..]);
+        let contents = read(&buffer).unwrap();
+        assert_eq!(contents.header.wraps, 0);
+        assert_eq!(text(&contents), b"Hello\nWorld?\n".to_vec());
+        // The same from the debugfs file
+        assert_eq!(read(cat(&buffer)), Some(contents));
+    }
+
+    #[test]
+    fn after_wrap() {
+        let mut buffer = [0u8; 200];
+        let mut written = Vec::new();
+        let capacity = {
+            let mut ring = Ring::new(&mut buffer);
+            for i in 0..100 {
+                let line = format!("Line {}\n", i);
+                ring.write(line.as_bytes());
+                written.extend_from_slice(line.as_bytes());
+            }
+            assert!(ring.header().wraps > 0);
+            assert_eq!(ring.header().dropped, 0);
+            ring.capacity()
+        };
+        // Only the last byte is a NUL, so `cat` shows it all
+        assert_eq!(cat(&buffer).len(), buffer.len() - 1);
+        let contents = read(&buffer).unwrap();
+        assert_eq!(contents.len(), capacity);
+        assert_eq!(text(&contents), &written[written.len() - capacity..]);
+        assert_eq!(read(cat(&buffer)), Some(contents));
+        let shown = String::from_utf8_lossy(cat(&buffer)).into_owned();
+        assert!(shown.contains("Line 99\n\n--- wrap ---\n"));
+    }
+
+    #[test]
+    fn marker_wraps() {
+        let mut buffer = [0u8; 100];
+        let mut ring = Ring::new(&mut buffer);
+        let ring_len = ring.capacity() + WRAP_MARKER.len();
+        let mut written = Vec::new();
+        for i in 0..(ring_len * 2 - 3) {
+            let b = b'a' + (i % 26) as u8;
+            ring.write(&[b]);
+            written.push(b);
+        }
+        assert_eq!(ring.header().write as usize, ring_len - 3);
+        let contents = read(ring.buffer).unwrap();
+        assert!(contents.second.is_empty());
+        assert_eq!(text(&contents), &written[written.len() - ring.capacity()..]);
+    }
+
+    #[test]
+    fn large_write() {
+        let mut buffer = [0u8; 128];
+        let mut ring = Ring::new(&mut buffer);
+        let data: Vec<u8> = (0..1000).map(|x| b'0' + (x % 10) as u8).collect();
+        ring.write(&data);
+        let capacity = ring.capacity();
+        assert_eq!(ring.header().dropped as usize, data.len() - capacity);
+        let contents = read(ring.buffer).unwrap();
+        assert_eq!(text(&contents), &data[data.len() - capacity..]);
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,67 @@
+//! # Crash Report Dumper
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Prints the crash report the IPU firmware leaves in its
+//! `IPU_MEM_IPC_DATA` carveout when it panics or takes a HardFault (see the
+//! `crash-report` crate). Give it a copy of the carveout, or of any memory
+//! which includes it; it looks for the report anywhere in the file.
+//!
+//! Usage: `crash-dump <memory dump>`
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+extern crate crash_report;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::process;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+fn main() {
+    let mut args = ::std::env::args().skip(1);
+    let path = match (args.next(), args.next()) {
+        (Some(path), None) => path,
+        _ => {
+            eprintln!("Usage: crash-dump <memory dump>");
+            process::exit(2);
+        }
+    };
+    let data = match ::std::fs::read(&path) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("crash-dump: {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    match crash_report::find(&data) {
+        Some((offset, report)) => {
+            eprintln!("Crash report at offset 0x{:x}", offset);
+            println!("{}", report);
+        }
+        None => {
+            eprintln!("crash-dump: {}: no crash report", path);
+            process::exit(1);
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,434 @@
+//! # Device Tree Cross-Checker
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Checks that the physical addresses in a firmware's resource table agree
+//! with a board's device tree. Any devmem which points at RAM must be inside
+//! a reserved-memory region (ideally the remote processor's own CMA region)
+//! or the kernel will happily give that RAM to someone else, and it mustn't
+//! touch another remote processor's CMA region. The carveouts, which the
+//! kernel allocates from the CMA region, must all fit in what the devmems
+//! leave of it.
+//!
+//! Usage: `dt-check <firmware.elf> <board.dtb> [<rproc node>]`
+//!
+//! The remoteproc node defaults to `ipu@58820000`, which is IPU1 on the
+//! AM5728. Exits with a non-zero status if any problems are found.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+extern crate rproc_tools;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use rproc_tools::elf::Elf;
+use rproc_tools::fdt::{self, Fdt};
+use rproc_tools::rsc::{self, Resource};
+use rproc_tools::RESOURCE_TABLE_SECTION;
+use std::process;
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+/// A named range of physical addresses from the device tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Area {
+    name: String,
+    start: u64,
+    end: u64,
+}
+
+/// What the device tree says about physical memory.
+#[derive(Debug)]
+struct Areas {
+    /// The `/memory` nodes
+    ram: Vec<Area>,
+    /// The reserved-memory regions and `/memreserve/` entries
+    reserved: Vec<Area>,
+    /// Our remote processor's CMA regions
+    cma: Vec<Area>,
+    /// Every other remote processor's CMA regions
+    other_cma: Vec<Area>,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const DEFAULT_RPROC_NODE: &str = "ipu@58820000";
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+fn main() {
+    let args: Vec<String> = ::std::env::args().skip(1).collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("Usage: dt-check <firmware.elf> <board.dtb> [<rproc node>]");
+        process::exit(2);
+    }
+    let node = args.get(2).map_or(DEFAULT_RPROC_NODE, |s| s.as_str());
+    match check(&args[0], &args[1], node) {
+        Ok(0) => println!("OK"),
+        Ok(problems) => {
+            println!("{} problem(s) found", problems);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("dt-check: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Run the checks, printing a line for each entry. Returns the number of
+/// problems found.
+fn check(elf_path: &str, dtb_path: &str, node_name: &str) -> Result<usize, String> {
+    let elf_data = ::std::fs::read(elf_path).map_err(|e| format!("{}: {}", elf_path, e))?;
+    let elf = Elf::parse(&elf_data).map_err(|e| format!("{}: {}", elf_path, e))?;
+    let section = elf
+        .section(RESOURCE_TABLE_SECTION)
+        .ok_or_else(|| format!("{}: no {} section", elf_path, RESOURCE_TABLE_SECTION))?;
+    let table = rsc::parse(section.data).map_err(|e| format!("{}: {}", elf_path, e))?;
+
+    let dtb_data = ::std::fs::read(dtb_path).map_err(|e| format!("{}: {}", dtb_path, e))?;
+    let dt = fdt::parse(&dtb_data).map_err(|e| format!("{}: {}", dtb_path, e))?;
+    let rproc = dt
+        .root
+        .find_named(node_name)
+        .ok_or_else(|| format!("{}: no {} node", dtb_path, node_name))?;
+
+    let areas = Areas {
+        ram: ram_areas(&dt)?,
+        reserved: reserved_areas(&dt)?,
+        cma: cma_areas(&dt, rproc)?,
+        other_cma: other_cma_areas(&dt, rproc)?,
+    };
+    if areas.cma.is_empty() {
+        println!("WARNING {} has no memory-region", node_name);
+    }
+    for area in &areas.cma {
+        println!(
+            "CMA     {} 0x{:08x}..0x{:08x}",
+            area.name, area.start, area.end
+        );
+    }
+    let (lines, problems) = check_entries(&table.entries, &areas);
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(problems)
+}
+
+/// Check each carveout and devmem against the device tree. Returns a line
+/// for each entry, plus one for the carveout total, and the number of
+/// problems found.
+fn check_entries(entries: &[rsc::Entry], areas: &Areas) -> (Vec<String>, usize) {
+    let mut lines = Vec::new();
+    let mut problems = 0;
+    let mut carveout_total = 0u64;
+    let mut cma_used = 0u64;
+    for entry in entries {
+        let (kind, memory) = match entry.resource {
+            Resource::Carveout(ref m) => ("carveout", m),
+            Resource::Devmem(ref m) => ("devmem", m),
+            _ => continue,
+        };
+        if kind == "carveout" && memory.pa == 0 {
+            // The kernel will allocate this from the CMA region
+            carveout_total += u64::from(memory.len);
+            continue;
+        }
+        // A u32 address plus a u32 length can't overflow a u64
+        let start = u64::from(memory.pa);
+        let end = start + u64::from(memory.len);
+        let label = format!("{} {} pa 0x{:08x}..0x{:08x}", kind, memory.name, start, end);
+        cma_used += areas
+            .cma
+            .iter()
+            .map(|a| overlap(a, start, end))
+            .sum::<u64>();
+        if !areas.ram.iter().any(|a| overlap(a, start, end) != 0) {
+            lines.push(format!("ok      {} is not RAM", label));
+        } else if let Some(a) = inside(&areas.cma, start, end) {
+            lines.push(format!("ok      {} is in {}", label, a.name));
+        } else if let Some(a) = areas.other_cma.iter().find(|a| overlap(a, start, end) != 0) {
+            lines.push(format!(
+                "PROBLEM {} is in {}, which belongs to another core",
+                label, a.name
+            ));
+            problems += 1;
+        } else if let Some(a) = inside(&areas.reserved, start, end) {
+            lines.push(format!("ok      {} is in reserved {}", label, a.name));
+        } else {
+            lines.push(format!(
+                "PROBLEM {} is RAM the kernel hasn't reserved",
+                label
+            ));
+            problems += 1;
+        }
+    }
+
+    let cma_total: u64 = areas.cma.iter().map(|a| a.end - a.start).sum();
+    // Overlapping CMA regions could count some bytes twice
+    let cma_free = cma_total.saturating_sub(cma_used);
+    if carveout_total > cma_free {
+        lines.push(format!(
+            "PROBLEM carveouts need 0x{:x} bytes but only 0x{:x} of 0x{:x} CMA bytes are free",
+            carveout_total, cma_free, cma_total
+        ));
+        problems += 1;
+    } else {
+        lines.push(format!(
+            "ok      carveouts need 0x{:x} of the 0x{:x} CMA bytes the devmems leave",
+            carveout_total, cma_free
+        ));
+    }
+    (lines, problems)
+}
+
+/// How many bytes of `start..end` are in `area`.
+fn overlap(area: &Area, start: u64, end: u64) -> u64 {
+    end.min(area.end).saturating_sub(start.max(area.start))
+}
+
+/// The first area which wholly contains `start..end`.
+fn inside(areas: &[Area], start: u64, end: u64) -> Option<&Area> {
+    areas.iter().find(|a| start >= a.start && end <= a.end)
+}
+
+/// Every range in the `/memory` nodes.
+fn ram_areas(dt: &Fdt) -> Result<Vec<Area>, String> {
+    let mut result = Vec::new();
+    for node in &dt.root.children {
+        if node.strings("device_type").contains(&"memory") {
+            result.extend(areas(&dt.root, node)?);
+        }
+    }
+    Ok(result)
+}
+
+/// Every reserved-memory region, plus the `/memreserve/` entries.
+fn reserved_areas(dt: &Fdt) -> Result<Vec<Area>, String> {
+    let mut result = Vec::new();
+    for &(address, size) in &dt.reservations {
+        result.push(area("/memreserve/", address, size)?);
+    }
+    if let Some(parent) = dt.find("/reserved-memory") {
+        for node in &parent.children {
+            result.extend(areas(parent, node)?);
+        }
+    }
+    Ok(result)
+}
+
+/// The regions the remote processor's `memory-region` property points at.
+fn cma_areas(dt: &Fdt, rproc: &fdt::Node) -> Result<Vec<Area>, String> {
+    let parent = match dt.find("/reserved-memory") {
+        Some(p) => p,
+        None => return Ok(Vec::new()),
+    };
+    let mut result = Vec::new();
+    for phandle in rproc.cells("memory-region") {
+        if let Some(node) = dt.by_phandle(phandle) {
+            result.extend(areas(parent, node)?);
+        }
+    }
+    Ok(result)
+}
+
+/// The regions any other node's `memory-region` property points at, less
+/// any it shares with the remote processor.
+fn other_cma_areas(dt: &Fdt, rproc: &fdt::Node) -> Result<Vec<Area>, String> {
+    fn search<'a>(node: &'a fdt::Node, rproc: &fdt::Node, found: &mut Vec<(&'a str, u32)>) {
+        if !::std::ptr::eq(node, rproc) {
+            for phandle in node.cells("memory-region") {
+                found.push((&node.name, phandle));
+            }
+        }
+        for child in &node.children {
+            search(child, rproc, found);
+        }
+    }
+    let parent = match dt.find("/reserved-memory") {
+        Some(p) => p,
+        None => return Ok(Vec::new()),
+    };
+    let ours = rproc.cells("memory-region");
+    let mut found = Vec::new();
+    search(&dt.root, rproc, &mut found);
+    let mut result = Vec::new();
+    for (owner, phandle) in found {
+        if ours.contains(&phandle) {
+            continue;
+        }
+        if let Some(node) = dt.by_phandle(phandle) {
+            for mut a in areas(parent, node)? {
+                a.name = format!("{} ({})", a.name, owner);
+                result.push(a);
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn areas(parent: &fdt::Node, node: &fdt::Node) -> Result<Vec<Area>, String> {
+    node.reg(parent)
+        .into_iter()
+        .map(|(address, size)| area(&node.name, address, size))
+        .collect()
+}
+
+fn area(name: &str, address: u64, size: u64) -> Result<Area, String> {
+    let end = address.checked_add(size).ok_or_else(|| {
+        format!(
+            "{}: 0x{:x} bytes at 0x{:x} runs past the end of memory",
+            name, size, address
+        )
+    })?;
+    Ok(Area {
+        name: name.to_owned(),
+        start: address,
+        end,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn a(name: &str, start: u64, len: u64) -> Area {
+        area(name, start, len).unwrap()
+    }
+
+    fn memory(kind: &str, name: &str, pa: u32, len: u32) -> rsc::Entry {
+        let m = rsc::Memory {
+            da: 0,
+            pa,
+            len,
+            flags: 0,
+            name: name.to_owned(),
+        };
+        rsc::Entry {
+            offset: 0,
+            resource: if kind == "carveout" {
+                Resource::Carveout(m)
+            } else {
+                Resource::Devmem(m)
+            },
+        }
+    }
+
+    /// 1 GiB of RAM, with our 1 MiB CMA region, another core's 1 MiB CMA
+    /// region, and a 1 MiB reserved region.
+    fn board() -> Areas {
+        Areas {
+            ram: vec![a("memory@80000000", 0x8000_0000, 0x4000_0000)],
+            reserved: vec![
+                a("ipu1_cma@9d000000", 0x9D00_0000, 0x10_0000),
+                a("dsp1_cma@99000000", 0x9900_0000, 0x10_0000),
+                a("spare@a0000000", 0xA000_0000, 0x10_0000),
+            ],
+            cma: vec![a("ipu1_cma@9d000000", 0x9D00_0000, 0x10_0000)],
+            other_cma: vec![a(
+                "dsp1_cma@99000000 (dsp@40800000)",
+                0x9900_0000,
+                0x10_0000,
+            )],
+        }
+    }
+
+    fn problems(entries: &[rsc::Entry]) -> usize {
+        check_entries(entries, &board()).1
+    }
+
+    #[test]
+    fn devmem_placement() {
+        // Not RAM at all
+        assert_eq!(
+            problems(&[memory("devmem", "l4cfg", 0x4A00_0000, 0x100_0000)]),
+            0
+        );
+        assert_eq!(problems(&[memory("devmem", "ipc", 0x9D00_0000, 0x1000)]), 0);
+        assert_eq!(
+            problems(&[memory("devmem", "spare", 0xA000_0000, 0x1000)]),
+            0
+        );
+        // Ordinary RAM the kernel could give away
+        assert_eq!(problems(&[memory("devmem", "ram", 0x8000_0000, 0x1000)]), 1);
+        // Running off the end of our CMA region
+        assert_eq!(problems(&[memory("devmem", "ipc", 0x9D0F_F000, 0x2000)]), 1);
+        // Reserved, but for the DSP
+        let (lines, count) =
+            check_entries(&[memory("devmem", "dsp", 0x9900_0000, 0x1000)], &board());
+        assert_eq!(count, 1);
+        assert!(lines[0].contains("another core"), "{}", lines[0]);
+        assert_eq!(problems(&[memory("devmem", "dsp", 0x98FF_F000, 0x2000)]), 1);
+    }
+
+    #[test]
+    fn carveout_size() {
+        assert_eq!(problems(&[memory("carveout", "text", 0, 0x10_0000)]), 0);
+        assert_eq!(problems(&[memory("carveout", "text", 0, 0x10_0001)]), 1);
+        // A devmem in the CMA region leaves less room for carveouts
+        let entries = [
+            memory("devmem", "ipc", 0x9D00_0000, 0x1000),
+            memory("carveout", "text", 0, 0xF_F000),
+        ];
+        assert_eq!(problems(&entries), 0);
+        let entries = [
+            memory("devmem", "ipc", 0x9D00_0000, 0x1000),
+            memory("carveout", "text", 0, 0x10_0000),
+        ];
+        assert_eq!(problems(&entries), 1);
+        // Only the part of a devmem inside the CMA region counts
+        let entries = [
+            memory("devmem", "ipc", 0x9D0F_F000, 0x2000),
+            memory("carveout", "text", 0, 0xF_F000),
+        ];
+        assert_eq!(problems(&entries), 1);
+    }
+
+    #[test]
+    fn area_overflow() {
+        assert!(area("top", 0xFFFF_FFFF_FFFF_F000, 0xFFF).is_ok());
+        assert!(area("bad", 0xFFFF_FFFF_FFFF_F000, 0x1000).is_err());
+    }
+
+    #[test]
+    fn overlaps() {
+        let cma = a("cma", 0x1000, 0x1000);
+        assert_eq!(overlap(&cma, 0, 0x1000), 0);
+        assert_eq!(overlap(&cma, 0x1800, 0x3000), 0x800);
+        assert_eq!(overlap(&cma, 0, 0x3000), 0x1000);
+        assert_eq!(overlap(&cma, 0x2000, 0x3000), 0);
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
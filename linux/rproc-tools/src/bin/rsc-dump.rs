@@ -0,0 +1,84 @@
+//! # Resource Table Dumper
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Prints the resource table and version string from a remoteproc firmware
+//! ELF. Exits with a non-zero status if the file can't be read, or if the
+//! resource table is missing or malformed.
+//!
+//! Usage: `rsc-dump <firmware.elf>`
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+extern crate rproc_tools;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use rproc_tools::elf::Elf;
+use rproc_tools::{rsc, RESOURCE_TABLE_SECTION, VERSION_SECTION};
+use std::process;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+fn main() {
+    let mut args = ::std::env::args().skip(1);
+    let path = match (args.next(), args.next()) {
+        (Some(path), None) => path,
+        _ => {
+            eprintln!("Usage: rsc-dump <firmware.elf>");
+            process::exit(2);
+        }
+    };
+    if let Err(e) = dump(&path) {
+        eprintln!("rsc-dump: {}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+fn dump(path: &str) -> Result<(), String> {
+    let data = ::std::fs::read(path).map_err(|e| e.to_string())?;
+    let elf = Elf::parse(&data).map_err(|e| e.to_string())?;
+
+    match elf.section(VERSION_SECTION) {
+        Some(section) => println!("Version: {}", rproc_tools::decode_version(section.data)),
+        None => println!("Version: no {} section", VERSION_SECTION),
+    }
+
+    let section = elf
+        .section(RESOURCE_TABLE_SECTION)
+        .ok_or_else(|| format!("no {} section", RESOURCE_TABLE_SECTION))?;
+    println!(
+        "Section {} at 0x{:08x}, {} bytes",
+        section.name,
+        section.addr,
+        section.data.len()
+    );
+    let table = rsc::parse(section.data).map_err(|e| e.to_string())?;
+    print!("{}", table);
+    Ok(())
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
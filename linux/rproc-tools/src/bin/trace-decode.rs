@@ -0,0 +1,103 @@
+//! # Deferred Log Decoder
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Prints the text in a remote processor's trace buffer, oldest first, like
+//! `trace-dump`, but with any binary log records (from the firmware's
+//! `dlog!` macros) turned back in to text using the format strings in the
+//! firmware ELF. The ELF must be the one which wrote the trace.
+//!
+//! Usage: `trace-decode <firmware.elf> [<trace file>]`
+//!
+//! The trace file defaults to IPU1's trace buffer in debugfs. A copy of the
+//! whole buffer, or the output of `trace-dump`, works too.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+extern crate rproc_tools;
+extern crate trace_ring;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use rproc_tools::deferred::Decoder;
+use rproc_tools::elf::Elf;
+use std::process;
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const DEFAULT_TRACE_FILE: &str = "/sys/kernel/debug/remoteproc/remoteproc0/trace0";
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+fn main() {
+    let mut args = ::std::env::args().skip(1);
+    let (elf_path, trace_path) = match (args.next(), args.next(), args.next()) {
+        (Some(elf), None, None) => (elf, DEFAULT_TRACE_FILE.to_owned()),
+        (Some(elf), Some(trace), None) => (elf, trace),
+        _ => {
+            eprintln!("Usage: trace-decode <firmware.elf> [<trace file>]");
+            process::exit(2);
+        }
+    };
+    let decoder = match load_decoder(&elf_path) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("trace-decode: {}: {}", elf_path, e);
+            process::exit(1);
+        }
+    };
+    let data = match ::std::fs::read(&trace_path) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("trace-decode: {}: {}", trace_path, e);
+            process::exit(1);
+        }
+    };
+    let text = match trace_ring::read(&data) {
+        Some(contents) => {
+            let mut text = contents.first.to_vec();
+            text.extend_from_slice(contents.second);
+            text
+        }
+        // Plain text, perhaps from `trace-dump`
+        None => data,
+    };
+    for line in String::from_utf8_lossy(&text).lines() {
+        println!("{}", decoder.decode_line(line));
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+fn load_decoder(path: &str) -> Result<Decoder, String> {
+    let data = ::std::fs::read(path).map_err(|e| e.to_string())?;
+    let elf = Elf::parse(&data).map_err(|e| e.to_string())?;
+    Decoder::from_elf(&elf).ok_or_else(|| "no deferred log strings".to_owned())
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,89 @@
+//! # Trace Buffer Dumper
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Prints the text in a remote processor's trace buffer, oldest first. The
+//! firmware writes the buffer as a ring (see the `trace-ring` crate), so
+//! once it has wrapped, `cat` shows the newest text before the oldest.
+//! The counters from the buffer's header go to stderr.
+//!
+//! Usage: `trace-dump [<trace file>]`
+//!
+//! The file defaults to IPU1's trace buffer in debugfs. A copy of the whole
+//! buffer, NULs and all, works too.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+extern crate trace_ring;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::io::Write;
+use std::process;
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const DEFAULT_TRACE_FILE: &str = "/sys/kernel/debug/remoteproc/remoteproc0/trace0";
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+fn main() {
+    let mut args = ::std::env::args().skip(1);
+    let path = match (args.next(), args.next()) {
+        (Some(path), None) => path,
+        (None, None) => DEFAULT_TRACE_FILE.to_owned(),
+        _ => {
+            eprintln!("Usage: trace-dump [<trace file>]");
+            process::exit(2);
+        }
+    };
+    if let Err(e) = dump(&path) {
+        eprintln!("trace-dump: {}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+fn dump(path: &str) -> Result<(), String> {
+    let data = ::std::fs::read(path).map_err(|e| e.to_string())?;
+    let contents = trace_ring::read(&data).ok_or("not a trace ring buffer")?;
+    eprintln!(
+        "{} bytes, wrapped {} times, {} bytes dropped",
+        contents.len(),
+        contents.header.wraps,
+        contents.header.dropped
+    );
+    let stdout = ::std::io::stdout();
+    let mut out = stdout.lock();
+    out.write_all(contents.first)
+        .and_then(|_| out.write_all(contents.second))
+        .map_err(|e| e.to_string())
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
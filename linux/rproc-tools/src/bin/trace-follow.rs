@@ -0,0 +1,185 @@
+//! # Trace Buffer Follower
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Like `tail -f` for a remote processor's trace buffer. Polls the trace
+//! file and prints each new line once, with the host's time (UTC) in
+//! front, even after the firmware's ring has wrapped. Keeps going if the
+//! file goes away, which it does while the remote processor is stopped.
+//!
+//! Usage: `trace-follow [-i <ms>] [-o <log file>] [-e <firmware.elf>]
+//! [<trace file>]`
+//!
+//! The file defaults to IPU1's trace buffer in debugfs and the poll interval
+//! to 500 ms. With `-o`, the lines are appended to the log file as well.
+//! With `-e`, binary log records are decoded using the format strings in the
+//! firmware ELF, as `trace-decode` does.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+extern crate rproc_tools;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use rproc_tools::deferred::Decoder;
+use rproc_tools::elf::Elf;
+use rproc_tools::follow::Follower;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const DEFAULT_TRACE_FILE: &str = "/sys/kernel/debug/remoteproc/remoteproc0/trace0";
+
+const DEFAULT_INTERVAL_MS: u64 = 500;
+
+const USAGE: &str =
+    "Usage: trace-follow [-i <ms>] [-o <log file>] [-e <firmware.elf>] [<trace file>]";
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+fn main() {
+    let mut path = DEFAULT_TRACE_FILE.to_owned();
+    let mut interval_ms = DEFAULT_INTERVAL_MS;
+    let mut log_path = None;
+    let mut elf_path = None;
+    let mut args = ::std::env::args().skip(1);
+    let mut have_path = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-i" => match args.next().and_then(|ms| ms.parse().ok()) {
+                Some(ms) => interval_ms = ms,
+                None => usage(),
+            },
+            "-o" => match args.next() {
+                Some(p) => log_path = Some(p),
+                None => usage(),
+            },
+            "-e" => match args.next() {
+                Some(p) => elf_path = Some(p),
+                None => usage(),
+            },
+            _ if !have_path && !arg.starts_with('-') => {
+                path = arg;
+                have_path = true;
+            }
+            _ => usage(),
+        }
+    }
+
+    let mut log = log_path.map(|p| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&p)
+            .unwrap_or_else(|e| {
+                eprintln!("trace-follow: {}: {}", p, e);
+                process::exit(1);
+            })
+    });
+    let decoder = elf_path.map(|p| {
+        load_decoder(&p).unwrap_or_else(|e| {
+            eprintln!("trace-follow: {}: {}", p, e);
+            process::exit(1);
+        })
+    });
+    follow(
+        &path,
+        Duration::from_millis(interval_ms),
+        &mut log,
+        decoder.as_ref(),
+    );
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn load_decoder(path: &str) -> Result<Decoder, String> {
+    let data = ::std::fs::read(path).map_err(|e| e.to_string())?;
+    let elf = Elf::parse(&data).map_err(|e| e.to_string())?;
+    Decoder::from_elf(&elf).ok_or_else(|| "no deferred log strings".to_owned())
+}
+
+/// Poll `path` for ever.
+fn follow(path: &str, interval: Duration, log: &mut Option<File>, decoder: Option<&Decoder>) -> ! {
+    let mut follower = Follower::new();
+    let mut missing = false;
+    loop {
+        match follower.poll(path) {
+            Ok(lines) => {
+                missing = false;
+                for line in lines {
+                    let line = match decoder {
+                        Some(d) => d.decode_line(&line),
+                        None => line,
+                    };
+                    let line = format!("[{}] {}", timestamp(), line);
+                    println!("{}", line);
+                    if let Some(ref mut file) = *log {
+                        if let Err(e) = writeln!(file, "{}", line) {
+                            eprintln!("trace-follow: log file: {}", e);
+                            process::exit(1);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                // Only say so once
+                if !missing {
+                    eprintln!("trace-follow: {}: {} (waiting)", path, e);
+                    missing = true;
+                }
+            }
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// The time of day, UTC, as `HH:MM:SS.mmm`.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    let secs = now.as_secs() % 86_400;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60,
+        now.subsec_millis()
+    )
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
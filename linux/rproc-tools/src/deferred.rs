@@ -0,0 +1,238 @@
+//! # Deferred Log Decoder
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Turns the binary log records written by the firmware's `dlog!` macros
+//! back in to text, using the format strings from the firmware ELF (see
+//! `trace_ring::deferred`). The text looks just like the firmware's own
+//! log lines.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use elf::Elf;
+use trace_ring::deferred::{self, Value, MAX_RECORD_LEN, STRINGS_SECTION};
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// The format strings from a firmware ELF.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    addr: u64,
+    strings: Vec<u8>,
+}
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const LEVELS: [&str; 6] = ["OFF", "ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl Decoder {
+    /// Returns `None` if the ELF has no format strings.
+    pub fn from_elf(elf: &Elf) -> Option<Decoder> {
+        elf.section(STRINGS_SECTION).map(|s| Decoder {
+            addr: s.addr,
+            strings: s.data.to_vec(),
+        })
+    }
+
+    /// Decode a line from the trace buffer, without its newline. Lines
+    /// which aren't records are returned as they are.
+    pub fn decode_line(&self, line: &str) -> String {
+        let mut scratch = [0u8; MAX_RECORD_LEN];
+        let record = match deferred::decode(line.as_bytes(), &mut scratch) {
+            Some(r) => r,
+            None => return line.to_owned(),
+        };
+        let level = LEVELS.get(record.level as usize).unwrap_or(&"?");
+        let (module, text) = match self.entry(record.id) {
+            Some((module, fmt)) => {
+                let args: Vec<Value> = record.args().collect();
+                (module, render(fmt, &args))
+            }
+            None => ("?", format!("<unknown string 0x{:08x}>", record.id)),
+        };
+        format!(
+            "[{:5}.{:06}] {:5} {}: {}",
+            record.seconds, record.micros, level, module, text
+        )
+    }
+
+    /// The module path and format string with the given ID.
+    fn entry(&self, id: u32) -> Option<(&str, &str)> {
+        let offset = u64::from(id).checked_sub(self.addr)? as usize;
+        let mut parts = self.strings.get(offset..)?.splitn(3, |&b| b == 0);
+        let module = ::std::str::from_utf8(parts.next()?).ok()?;
+        let fmt = ::std::str::from_utf8(parts.next()?).ok()?;
+        // The format string must have had its NUL
+        parts.next()?;
+        Some((module, fmt))
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Fill in the placeholders in `fmt`. Missing arguments show as `{?}`.
+fn render(fmt: &str, args: &[Value]) -> String {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let spec: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                match args.next() {
+                    Some(value) => out.push_str(&format_value(value, &spec)),
+                    None => out.push_str("{?}"),
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Format one value, given what was between the braces.
+fn format_value(value: &Value, spec: &str) -> String {
+    let spec = spec.trim_start_matches(':');
+    let alternate = spec.starts_with('#');
+    let spec = spec.trim_start_matches('#');
+    let zero = spec.starts_with('0');
+    let digits: String = spec.chars().take_while(|c| c.is_ascii_digit()).collect();
+    let width: usize = digits.parse().unwrap_or(0);
+    let kind = &spec[digits.len()..];
+    let radix = kind == "x" || kind == "X" || kind == "b";
+    let number = match *value {
+        // Hex and binary show negative numbers as the firmware would, with
+        // 32 bits unless they don't fit.
+        Value::Signed(n) if radix && n >= i64::from(i32::MIN) => {
+            Some((false, u64::from(n as i32 as u32)))
+        }
+        Value::Signed(n) if radix => Some((false, n as u64)),
+        Value::Signed(n) => Some((n < 0, n.wrapping_abs() as u64)),
+        Value::Unsigned(n) => Some((false, n)),
+        _ => None,
+    };
+    match number {
+        Some((negative, n)) => {
+            let (prefix, body) = match kind {
+                "x" => ("0x", format!("{:x}", n)),
+                "X" => ("0x", format!("{:X}", n)),
+                "b" => ("0b", format!("{:b}", n)),
+                _ => ("", n.to_string()),
+            };
+            let mut lead = String::new();
+            if negative {
+                lead.push('-');
+            }
+            if alternate {
+                lead.push_str(prefix);
+            }
+            if zero {
+                let pad = width.saturating_sub(lead.len() + body.len());
+                format!("{}{}{}", lead, "0".repeat(pad), body)
+            } else {
+                format!("{:>width$}", lead + &body, width = width)
+            }
+        }
+        None => {
+            let text = match (*value, kind) {
+                (Value::Str(s), "?") => format!("{:?}", s),
+                (Value::Char(c), "?") => format!("{:?}", c),
+                (Value::Str(s), _) => s.to_owned(),
+                (Value::Char(c), _) => c.to_string(),
+                (Value::Bool(b), _) => b.to_string(),
+                _ => String::new(),
+            };
+            format!("{:<width$}", text, width = width)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use trace_ring::deferred::Record;
+    use trace_ring::Ring;
+
+    #[test]
+    fn decode_records() {
+        let decoder = Decoder {
+            addr: 0x100,
+            strings: b"demo::main\0{}: got 0x{:08x} {:?}\0demo\0[{:5}] {:#06x} {} {{}}\0".to_vec(),
+        };
+        let mut buffer = [0u8; 512];
+        {
+            let mut ring = Ring::new(&mut buffer);
+            let mut record = Record::new(0x100, 3);
+            record.set_time(12, 345);
+            record.push(&7u32);
+            record.push(&0xBEEFu16);
+            record.push(&"hi");
+            record.write_to(&mut ring);
+            let mut record = Record::new(0x100 + 33, 2);
+            record.push(&-3i8);
+            record.push(&-1i32);
+            record.write_to(&mut ring);
+            ring.write(b"plain text\n");
+            let record = Record::new(0x99, 1);
+            record.write_to(&mut ring);
+        }
+
+        let contents = ::trace_ring::read(&buffer).unwrap();
+        let text = String::from_utf8(contents.first.to_vec()).unwrap();
+        let lines: Vec<String> = text.lines().map(|l| decoder.decode_line(l)).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "[   12.000345] INFO  demo::main: 7: got 0x0000beef \"hi\"",
+                "[    0.000000] WARN  demo: [   -3] 0xffffffff {?} {}",
+                "plain text",
+                "[    0.000000] ERROR ?: <unknown string 0x00000099>",
+            ]
+        );
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
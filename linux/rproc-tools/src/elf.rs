@@ -0,0 +1,359 @@
+//! # ELF Parser
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Just enough of an ELF parser to find sections by name. Both 32-bit and
+//! 64-bit files are supported, but only little-endian ones, as that's all
+//! the AM5728 cores produce.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::fmt;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// A parsed ELF file, borrowing from the bytes it was parsed from.
+#[derive(Debug)]
+pub struct Elf<'a> {
+    sections: Vec<Section<'a>>,
+}
+
+/// One section of an ELF file.
+#[derive(Debug, Clone, Copy)]
+pub struct Section<'a> {
+    /// The name, from the section header string table
+    pub name: &'a str,
+    /// The address the section is loaded at
+    pub addr: u64,
+    /// The contents of the section. Empty for `SHT_NOBITS` sections.
+    pub data: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The file doesn't start with the ELF magic number
+    NotElf,
+    /// The file is an ELF class (32-bit or 64-bit) we don't understand
+    UnsupportedClass(u8),
+    /// The file is big-endian
+    UnsupportedEndian(u8),
+    /// The file is too short to contain the header
+    TooShort,
+    /// The section header table runs off the end of the file
+    BadSectionTable,
+    /// The given section runs off the end of the file
+    BadSection(usize),
+    /// The given section has a name we can't find
+    BadSectionName(usize),
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+#[derive(Debug, Clone, Copy)]
+enum Class {
+    Elf32,
+    Elf64,
+}
+
+/// The bits of a section header we care about.
+#[derive(Debug, Clone, Copy)]
+struct SectionHeader {
+    name: usize,
+    kind: u32,
+    addr: u64,
+    offset: usize,
+    size: usize,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const SHT_NOBITS: u32 = 8;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl<'a> Elf<'a> {
+    /// Parse the section headers out of an ELF file.
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, Error> {
+        if data.len() < ELF_MAGIC.len() || data[0..4] != ELF_MAGIC {
+            return Err(Error::NotElf);
+        }
+        if data.len() <= EI_DATA {
+            return Err(Error::TooShort);
+        }
+        let class = match data[EI_CLASS] {
+            ELFCLASS32 => Class::Elf32,
+            ELFCLASS64 => Class::Elf64,
+            x => return Err(Error::UnsupportedClass(x)),
+        };
+        if data[EI_DATA] != ELFDATA2LSB {
+            return Err(Error::UnsupportedEndian(data[EI_DATA]));
+        }
+
+        // e_shoff, e_shentsize, e_shnum, e_shstrndx
+        let (shoff, shentsize, shnum, shstrndx) = match class {
+            Class::Elf32 => (
+                read_u32(data, 0x20).ok_or(Error::TooShort)? as usize,
+                read_u16(data, 0x2E).ok_or(Error::TooShort)? as usize,
+                read_u16(data, 0x30).ok_or(Error::TooShort)? as usize,
+                read_u16(data, 0x32).ok_or(Error::TooShort)? as usize,
+            ),
+            Class::Elf64 => (
+                read_u64(data, 0x28).ok_or(Error::TooShort)? as usize,
+                read_u16(data, 0x3A).ok_or(Error::TooShort)? as usize,
+                read_u16(data, 0x3C).ok_or(Error::TooShort)? as usize,
+                read_u16(data, 0x3E).ok_or(Error::TooShort)? as usize,
+            ),
+        };
+
+        let mut headers = Vec::with_capacity(shnum);
+        for idx in 0..shnum {
+            let offset = shentsize
+                .checked_mul(idx)
+                .and_then(|o| o.checked_add(shoff))
+                .ok_or(Error::BadSectionTable)?;
+            let header = read_section_header(data, offset, class).ok_or(Error::BadSectionTable)?;
+            headers.push(header);
+        }
+
+        let strings = match headers.get(shstrndx) {
+            Some(h) => section_data(data, h).ok_or(Error::BadSection(shstrndx))?,
+            None => return Err(Error::BadSectionTable),
+        };
+
+        let mut sections = Vec::with_capacity(shnum);
+        for (idx, header) in headers.iter().enumerate() {
+            let name = read_str(strings, header.name).ok_or(Error::BadSectionName(idx))?;
+            let contents = section_data(data, header).ok_or(Error::BadSection(idx))?;
+            sections.push(Section {
+                name,
+                addr: header.addr,
+                data: contents,
+            });
+        }
+
+        Ok(Elf { sections })
+    }
+
+    /// All the sections, in the order they appear in the section table.
+    pub fn sections(&self) -> &[Section<'a>] {
+        &self.sections
+    }
+
+    /// Find the first section with the given name.
+    pub fn section(&self, name: &str) -> Option<&Section<'a>> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotElf => write!(f, "not an ELF file"),
+            Error::UnsupportedClass(c) => write!(f, "unsupported ELF class {}", c),
+            Error::UnsupportedEndian(d) => write!(f, "unsupported ELF data encoding {}", d),
+            Error::TooShort => write!(f, "ELF header truncated"),
+            Error::BadSectionTable => write!(f, "section header table truncated"),
+            Error::BadSection(idx) => write!(f, "section {} truncated", idx),
+            Error::BadSectionName(idx) => write!(f, "section {} has a bad name", idx),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+fn read_section_header(data: &[u8], offset: usize, class: Class) -> Option<SectionHeader> {
+    // `offset` comes from the file, so adding to it can overflow
+    let field = |delta: usize| offset.checked_add(delta);
+    match class {
+        Class::Elf32 => Some(SectionHeader {
+            name: read_u32(data, offset)? as usize,
+            kind: read_u32(data, field(0x04)?)?,
+            addr: u64::from(read_u32(data, field(0x0C)?)?),
+            offset: read_u32(data, field(0x10)?)? as usize,
+            size: read_u32(data, field(0x14)?)? as usize,
+        }),
+        Class::Elf64 => Some(SectionHeader {
+            name: read_u32(data, offset)? as usize,
+            kind: read_u32(data, field(0x04)?)?,
+            addr: read_u64(data, field(0x10)?)?,
+            offset: read_u64(data, field(0x18)?)? as usize,
+            size: read_u64(data, field(0x20)?)? as usize,
+        }),
+    }
+}
+
+/// The bytes of the given section, or `None` if they're not all in the
+/// file.
+fn section_data<'a>(data: &'a [u8], header: &SectionHeader) -> Option<&'a [u8]> {
+    if header.kind == SHT_NOBITS {
+        return Some(&[]);
+    }
+    let end = header.offset.checked_add(header.size)?;
+    data.get(header.offset..end)
+}
+
+/// Read a NUL terminated string starting at `offset`.
+fn read_str(data: &[u8], offset: usize) -> Option<&str> {
+    let tail = data.get(offset..)?;
+    let len = tail.iter().position(|&b| b == 0)?;
+    ::std::str::from_utf8(&tail[..len]).ok()
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let b = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from(b[0]) | u16::from(b[1]) << 8)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let lo = read_u16(data, offset)?;
+    let hi = read_u16(data, offset.checked_add(2)?)?;
+    Some(u32::from(lo) | u32::from(hi) << 16)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let lo = read_u32(data, offset)?;
+    let hi = read_u32(data, offset.checked_add(4)?)?;
+    Some(u64::from(lo) | u64::from(hi) << 32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Build a 32-bit ELF with a single `.test` section holding `contents`.
+    fn make_elf32(contents: &[u8]) -> Vec<u8> {
+        let strings = b"\0.shstrtab\0.test\0";
+        let strings_off = 0x34;
+        let contents_off = strings_off + strings.len();
+        let shoff = contents_off + contents.len();
+        let mut elf = vec![0u8; 0x34];
+        elf[0..4].copy_from_slice(&ELF_MAGIC);
+        elf[EI_CLASS] = ELFCLASS32;
+        elf[EI_DATA] = ELFDATA2LSB;
+        put_u32(&mut elf, 0x20, shoff as u32);
+        put_u16(&mut elf, 0x2E, 0x28);
+        put_u16(&mut elf, 0x30, 3);
+        put_u16(&mut elf, 0x32, 1);
+        elf.extend_from_slice(strings);
+        elf.extend_from_slice(contents);
+        // Null section, then .shstrtab, then .test
+        let mut headers = vec![0u8; 0x28 * 3];
+        put_u32(&mut headers, 0x28, 1);
+        put_u32(&mut headers, 0x28 + 0x10, strings_off as u32);
+        put_u32(&mut headers, 0x28 + 0x14, strings.len() as u32);
+        put_u32(&mut headers, 0x50, 11);
+        put_u32(&mut headers, 0x50 + 0x0C, 0x1000);
+        put_u32(&mut headers, 0x50 + 0x10, contents_off as u32);
+        put_u32(&mut headers, 0x50 + 0x14, contents.len() as u32);
+        elf.extend_from_slice(&headers);
+        elf
+    }
+
+    fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+        buf[offset] = value as u8;
+        buf[offset + 1] = (value >> 8) as u8;
+    }
+
+    fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+        put_u16(buf, offset, value as u16);
+        put_u16(buf, offset + 2, (value >> 16) as u16);
+    }
+
+    #[test]
+    fn find_section() {
+        let data = make_elf32(b"hello");
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.sections().len(), 3);
+        let section = elf.section(".test").unwrap();
+        assert_eq!(section.addr, 0x1000);
+        assert_eq!(section.data, b"hello");
+        assert!(elf.section(".missing").is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        let data = make_elf32(b"hello");
+        assert_eq!(
+            Elf::parse(&data[..0x40]).unwrap_err(),
+            Error::BadSectionTable
+        );
+        assert_eq!(Elf::parse(b"\x7fELX").unwrap_err(), Error::NotElf);
+    }
+
+    #[test]
+    fn section_table_at_end_of_address_space() {
+        // The section header's fields would be past usize::MAX
+        let mut data = vec![0u8; 0x40];
+        data[0..4].copy_from_slice(&ELF_MAGIC);
+        data[EI_CLASS] = ELFCLASS64;
+        data[EI_DATA] = ELFDATA2LSB;
+        put_u32(&mut data, 0x28, 0xFFFF_FFF0);
+        put_u32(&mut data, 0x2C, 0xFFFF_FFFF);
+        put_u16(&mut data, 0x3A, 0x40);
+        put_u16(&mut data, 0x3C, 1);
+        assert_eq!(Elf::parse(&data).unwrap_err(), Error::BadSectionTable);
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
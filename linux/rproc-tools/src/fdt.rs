@@ -0,0 +1,497 @@
+//! # Flattened Device Tree Parser
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Reads a device tree blob (`.dtb`) into a tree of nodes, as described in
+//! chapter 5 of the Devicetree Specification. We only need to look things
+//! up, so the whole tree is copied out of the blob and nothing is ever
+//! written back.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::fmt;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// A parsed device tree.
+#[derive(Debug, Clone)]
+pub struct Fdt {
+    /// The root node, whose name is empty
+    pub root: Node,
+    /// The `/memreserve/` entries, as (address, size)
+    pub reservations: Vec<(u64, u64)>,
+}
+
+/// A node in the device tree.
+#[derive(Debug, Clone)]
+pub struct Node {
+    /// The name, including any unit address (e.g. `ipu@58820000`)
+    pub name: String,
+    pub props: Vec<Property>,
+    pub children: Vec<Node>,
+}
+
+/// A property of a node. The value is big-endian, and its meaning depends
+/// on the name.
+#[derive(Debug, Clone)]
+pub struct Property {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The blob doesn't start with `FDT_MAGIC`
+    NotFdt,
+    /// The blob uses a version of the format we don't understand
+    BadVersion(u32),
+    /// Something runs off the end of the blob
+    Truncated,
+    /// There's an unexpected token at the given offset in the structure
+    /// block
+    BadToken(usize),
+    /// A node or property name isn't valid UTF-8
+    BadName,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+/// Walks the structure block.
+struct Parser<'a> {
+    data: &'a [u8],
+    strings: &'a [u8],
+    offset: usize,
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+/// The oldest version with the fields we read.
+const FDT_MIN_VERSION: u32 = 16;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Parse a device tree blob.
+pub fn parse(data: &[u8]) -> Result<Fdt, Error> {
+    if read_u32(data, 0) != Some(FDT_MAGIC) {
+        return Err(Error::NotFdt);
+    }
+    let header = |n: usize| read_u32(data, 4 * n).ok_or(Error::Truncated);
+    let off_dt_struct = header(2)? as usize;
+    let off_dt_strings = header(3)? as usize;
+    let off_mem_rsvmap = header(4)? as usize;
+    let version = header(5)?;
+    if version < FDT_MIN_VERSION {
+        return Err(Error::BadVersion(version));
+    }
+    let strings = data.get(off_dt_strings..).ok_or(Error::Truncated)?;
+
+    let mut reservations = Vec::new();
+    let mut offset = off_mem_rsvmap;
+    loop {
+        let address = read_u64(data, offset).ok_or(Error::Truncated)?;
+        let size = read_u64(data, offset + 8).ok_or(Error::Truncated)?;
+        if address == 0 && size == 0 {
+            break;
+        }
+        reservations.push((address, size));
+        offset += 16;
+    }
+
+    let mut parser = Parser {
+        data,
+        strings,
+        offset: off_dt_struct,
+    };
+    parser.skip_nops()?;
+    if parser.token()? != FDT_BEGIN_NODE {
+        return Err(Error::BadToken(parser.offset - 4));
+    }
+    let root = parser.node()?;
+    parser.skip_nops()?;
+    if parser.token()? != FDT_END {
+        return Err(Error::BadToken(parser.offset - 4));
+    }
+    Ok(Fdt { root, reservations })
+}
+
+impl Fdt {
+    /// Find a node by its path, e.g. `/reserved-memory/ipu1_cma@9d000000`.
+    pub fn find(&self, path: &str) -> Option<&Node> {
+        let mut node = &self.root;
+        for name in path.split('/').filter(|p| !p.is_empty()) {
+            node = node.child(name)?;
+        }
+        Some(node)
+    }
+
+    /// Find the node with the given phandle.
+    pub fn by_phandle(&self, phandle: u32) -> Option<&Node> {
+        fn search(node: &Node, phandle: u32) -> Option<&Node> {
+            let found = node
+                .u32_prop("phandle")
+                .or_else(|| node.u32_prop("linux,phandle"));
+            if found == Some(phandle) {
+                return Some(node);
+            }
+            node.children
+                .iter()
+                .filter_map(|c| search(c, phandle))
+                .next()
+        }
+        search(&self.root, phandle)
+    }
+}
+
+impl Node {
+    /// The value of the named property.
+    pub fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props
+            .iter()
+            .find(|p| p.name == name)
+            .map(|p| p.value.as_slice())
+    }
+
+    /// The value of the named property, as a single cell.
+    pub fn u32_prop(&self, name: &str) -> Option<u32> {
+        self.prop(name).and_then(|v| read_u32(v, 0))
+    }
+
+    /// The value of the named property, as a list of cells.
+    pub fn cells(&self, name: &str) -> Vec<u32> {
+        let value = self.prop(name).unwrap_or(&[]);
+        (0..value.len() / 4)
+            .filter_map(|i| read_u32(value, 4 * i))
+            .collect()
+    }
+
+    /// The strings in a string list property, such as `compatible`.
+    pub fn strings(&self, name: &str) -> Vec<&str> {
+        self.prop(name)
+            .unwrap_or(&[])
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| ::std::str::from_utf8(s).ok())
+            .collect()
+    }
+
+    /// Is this node compatible with the given string?
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.strings("compatible").contains(&compatible)
+    }
+
+    /// The child with the given name. If `name` has no unit address, it
+    /// matches a child with any unit address.
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|c| c.name == name).or_else(|| {
+            if name.contains('@') {
+                None
+            } else {
+                self.children
+                    .iter()
+                    .find(|c| c.name.split('@').next() == Some(name))
+            }
+        })
+    }
+
+    /// Search this node and everything under it for a node with the given
+    /// name.
+    pub fn find_named(&self, name: &str) -> Option<&Node> {
+        if self.name == name {
+            return Some(self);
+        }
+        self.children
+            .iter()
+            .filter_map(|c| c.find_named(name))
+            .next()
+    }
+
+    /// Decode this node's `reg` property into (address, size) pairs, using
+    /// the parent's `#address-cells` and `#size-cells`.
+    pub fn reg(&self, parent: &Node) -> Vec<(u64, u64)> {
+        let address_cells = parent.u32_prop("#address-cells").unwrap_or(2) as usize;
+        let size_cells = parent.u32_prop("#size-cells").unwrap_or(1) as usize;
+        let cells = self.cells("reg");
+        let stride = address_cells + size_cells;
+        if stride == 0 {
+            return Vec::new();
+        }
+        cells
+            .chunks(stride)
+            .filter(|c| c.len() == stride)
+            .map(|c| {
+                (
+                    join_cells(&c[..address_cells]),
+                    join_cells(&c[address_cells..]),
+                )
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFdt => write!(f, "not a device tree blob"),
+            Error::BadVersion(v) => write!(f, "unsupported device tree version {}", v),
+            Error::Truncated => write!(f, "device tree truncated"),
+            Error::BadToken(offset) => write!(f, "bad token at structure offset 0x{:x}", offset),
+            Error::BadName => write!(f, "bad node or property name"),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+impl<'a> Parser<'a> {
+    fn token(&mut self) -> Result<u32, Error> {
+        let token = read_u32(self.data, self.offset).ok_or(Error::Truncated)?;
+        self.offset += 4;
+        Ok(token)
+    }
+
+    fn skip_nops(&mut self) -> Result<(), Error> {
+        while read_u32(self.data, self.offset).ok_or(Error::Truncated)? == FDT_NOP {
+            self.offset += 4;
+        }
+        Ok(())
+    }
+
+    /// Parse a node, just after its `FDT_BEGIN_NODE` token.
+    fn node(&mut self) -> Result<Node, Error> {
+        let name = read_str(self.data, self.offset)?;
+        self.offset = align4(self.offset + name.len() + 1);
+        let mut node = Node {
+            name: name.to_owned(),
+            props: Vec::new(),
+            children: Vec::new(),
+        };
+        loop {
+            let token_offset = self.offset;
+            match self.token()? {
+                FDT_PROP => {
+                    let len = self.token()? as usize;
+                    let name_offset = self.token()? as usize;
+                    let value = self
+                        .data
+                        .get(self.offset..self.offset + len)
+                        .ok_or(Error::Truncated)?;
+                    node.props.push(Property {
+                        name: read_str(self.strings, name_offset)?.to_owned(),
+                        value: value.to_vec(),
+                    });
+                    self.offset = align4(self.offset + len);
+                }
+                FDT_BEGIN_NODE => node.children.push(self.node()?),
+                FDT_END_NODE => return Ok(node),
+                FDT_NOP => {}
+                _ => return Err(Error::BadToken(token_offset)),
+            }
+        }
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn join_cells(cells: &[u32]) -> u64 {
+    cells
+        .iter()
+        .fold(0u64, |acc, &c| (acc << 32) | u64::from(c))
+}
+
+fn read_str(data: &[u8], offset: usize) -> Result<&str, Error> {
+    let tail = data.get(offset..).ok_or(Error::Truncated)?;
+    let len = tail.iter().position(|&b| b == 0).ok_or(Error::Truncated)?;
+    ::std::str::from_utf8(&tail[..len]).map_err(|_| Error::BadName)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let b = data.get(offset..offset + 4)?;
+    Some(u32::from(b[0]) << 24 | u32::from(b[1]) << 16 | u32::from(b[2]) << 8 | u32::from(b[3]))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let hi = read_u32(data, offset)?;
+    let lo = read_u32(data, offset + 4)?;
+    Some(u64::from(hi) << 32 | u64::from(lo))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Builds a blob, a token at a time.
+    struct Builder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn u32(&mut self, value: u32) {
+            self.structure.extend_from_slice(&[
+                (value >> 24) as u8,
+                (value >> 16) as u8,
+                (value >> 8) as u8,
+                value as u8,
+            ]);
+        }
+
+        fn begin(&mut self, name: &str) {
+            self.u32(FDT_BEGIN_NODE);
+            self.structure.extend_from_slice(name.as_bytes());
+            self.structure.push(0);
+            let padded = align4(self.structure.len());
+            self.structure.resize(padded, 0);
+        }
+
+        fn prop(&mut self, name: &str, cells: &[u32]) {
+            let name_offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.u32(FDT_PROP);
+            self.u32(4 * cells.len() as u32);
+            self.u32(name_offset);
+            for &c in cells {
+                self.u32(c);
+            }
+        }
+
+        fn finish(mut self) -> Vec<u8> {
+            self.u32(FDT_END);
+            let mut blob = Vec::new();
+            let rsvmap = 40;
+            let structure = rsvmap + 16;
+            let strings = structure + self.structure.len();
+            let total = strings + self.strings.len();
+            for &w in &[
+                FDT_MAGIC,
+                total as u32,
+                structure as u32,
+                strings as u32,
+                rsvmap as u32,
+                17,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structure.len() as u32,
+            ] {
+                blob.extend_from_slice(&[
+                    (w >> 24) as u8,
+                    (w >> 16) as u8,
+                    (w >> 8) as u8,
+                    w as u8,
+                ]);
+            }
+            blob.extend_from_slice(&[0; 16]);
+            blob.extend_from_slice(&self.structure);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    #[test]
+    fn reserved_memory() {
+        let mut b = Builder {
+            structure: Vec::new(),
+            strings: Vec::new(),
+        };
+        b.begin("");
+        b.begin("reserved-memory");
+        b.prop("#address-cells", &[2]);
+        b.prop("#size-cells", &[2]);
+        b.begin("ipu1_cma@9d000000");
+        b.prop("reg", &[0, 0x9D00_0000, 0, 0x0200_0000]);
+        b.prop("phandle", &[5]);
+        b.u32(FDT_END_NODE);
+        b.u32(FDT_END_NODE);
+        b.begin("ipu@58820000");
+        b.prop("memory-region", &[5]);
+        b.u32(FDT_END_NODE);
+        b.u32(FDT_END_NODE);
+
+        let fdt = parse(&b.finish()).unwrap();
+        assert!(fdt.reservations.is_empty());
+        let ipu = fdt.find("/ipu@58820000").unwrap();
+        let cma = fdt
+            .by_phandle(ipu.u32_prop("memory-region").unwrap())
+            .unwrap();
+        assert_eq!(cma.name, "ipu1_cma@9d000000");
+        let parent = fdt.find("/reserved-memory").unwrap();
+        assert_eq!(cma.reg(parent), vec![(0x9D00_0000, 0x0200_0000)]);
+        assert!(fdt.find("/reserved-memory/ipu1_cma").is_some());
+        assert!(fdt.find("/missing").is_none());
+    }
+
+    #[test]
+    fn not_a_blob() {
+        assert_eq!(parse(b"\x7fELF").unwrap_err(), Error::NotFdt);
+        assert_eq!(
+            parse(&[0xD0, 0x0D, 0xFE, 0xED]).unwrap_err(),
+            Error::Truncated
+        );
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,244 @@
+//! # Trace Follower
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Works out which lines in a trace buffer are new since we last looked.
+//! The firmware writes its trace buffer as a ring (see the `trace-ring`
+//! crate), and the header's write index and wrap count tell us how many
+//! bytes have been written in between, even if the ring has wrapped. If
+//! more was written than the ring holds, we say how much we missed.
+//!
+//! A file without a ring header is treated as plain text which only ever
+//! grows. If it shrinks, or changes, we assume the firmware restarted.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use trace_ring::{self, Contents};
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// Remembers where we got to in a trace buffer.
+#[derive(Debug, Default)]
+pub struct Follower {
+    /// The number of bytes written to the ring when we last looked.
+    position: Option<u64>,
+    /// The last snapshot, if it wasn't a ring.
+    plain: Vec<u8>,
+    /// Anything after the last newline, which we haven't returned yet.
+    partial: Vec<u8>,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// Returned when the firmware seems to have started again.
+pub const RESTARTED: &str = "--- trace restarted ---";
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl Follower {
+    pub fn new() -> Follower {
+        Follower::default()
+    }
+
+    /// Read the trace file at `path` and return any new lines.
+    pub fn poll<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Vec<String>> {
+        let snapshot = fs::read(path)?;
+        Ok(self.update(&snapshot))
+    }
+
+    /// Given the whole of the trace buffer as it is now, return any complete
+    /// lines we haven't returned before, without their newlines.
+    pub fn update(&mut self, snapshot: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        let new = match trace_ring::read(snapshot) {
+            Some(contents) => {
+                self.plain.clear();
+                self.update_ring(&contents, &mut lines)
+            }
+            None => {
+                self.position = None;
+                self.update_plain(snapshot, &mut lines)
+            }
+        };
+        self.partial.extend_from_slice(&new);
+        while let Some(idx) = self.partial.iter().position(|&b| b == b'\n') {
+            let rest = self.partial.split_off(idx + 1);
+            lines.push(String::from_utf8_lossy(&self.partial[..idx]).into_owned());
+            self.partial = rest;
+        }
+        lines
+    }
+
+    /// Returns the new text in a ring.
+    fn update_ring(&mut self, contents: &Contents, lines: &mut Vec<String>) -> Vec<u8> {
+        let mut text = contents.first.to_vec();
+        text.extend_from_slice(contents.second);
+        let header = contents.header;
+        let position = if header.wraps == 0 {
+            u64::from(header.write)
+        } else {
+            let ring_len = (text.len() + trace_ring::WRAP_MARKER.len()) as u64;
+            u64::from(header.wraps) * ring_len + u64::from(header.write)
+        };
+        let start = match self.position {
+            Some(old) if position >= old => {
+                let new = (position - old) as usize;
+                if new <= text.len() {
+                    Some(text.len() - new)
+                } else {
+                    self.break_line(lines);
+                    lines.push(format!("--- {} bytes lost ---", new - text.len()));
+                    None
+                }
+            }
+            Some(_) => {
+                self.break_line(lines);
+                lines.push(RESTARTED.to_owned());
+                None
+            }
+            None => None,
+        };
+        self.position = Some(position);
+        match start {
+            Some(start) => text.split_off(start),
+            None if header.wraps != 0 => {
+                // The oldest line has lost its start
+                match text.iter().position(|&b| b == b'\n') {
+                    Some(idx) => text.split_off(idx + 1),
+                    None => Vec::new(),
+                }
+            }
+            None => text,
+        }
+    }
+
+    /// Returns the new text in a plain file.
+    fn update_plain(&mut self, snapshot: &[u8], lines: &mut Vec<String>) -> Vec<u8> {
+        let end = snapshot
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(snapshot.len());
+        let snapshot = &snapshot[..end];
+        let new = if snapshot.starts_with(&self.plain) {
+            snapshot[self.plain.len()..].to_vec()
+        } else {
+            self.break_line(lines);
+            lines.push(RESTARTED.to_owned());
+            snapshot.to_vec()
+        };
+        self.plain = snapshot.to_vec();
+        new
+    }
+
+    /// We've lost track, so return whatever we had of the last line.
+    fn break_line(&mut self, lines: &mut Vec<String>) {
+        if !self.partial.is_empty() {
+            lines.push(String::from_utf8_lossy(&self.partial).into_owned());
+            self.partial.clear();
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use trace_ring::Ring;
+
+    #[test]
+    fn follow_ring_file() {
+        let path = ::std::env::temp_dir().join(format!("trace-follow-{}", ::std::process::id()));
+        let mut buffer = vec![0u8; 256];
+        let mut follower = Follower::new();
+        let mut next = 0;
+        let mut write_lines = |buffer: &mut Vec<u8>, count: usize| {
+            let mut ring = Ring::new(buffer);
+            for _ in 0..count {
+                ring.write(format!("Line {}\n", next).as_bytes());
+                next += 1;
+            }
+        };
+
+        write_lines(&mut buffer, 2);
+        assert!(trace_ring::read(&buffer).is_some());
+        fs::write(&path, &buffer).unwrap();
+        assert_eq!(follower.poll(&path).unwrap(), vec!["Line 0", "Line 1"]);
+        assert!(follower.poll(&path).unwrap().is_empty());
+
+        write_lines(&mut buffer, 15);
+        fs::write(&path, &buffer).unwrap();
+        let lines = follower.poll(&path).unwrap();
+        let expected: Vec<String> = (2..17).map(|i| format!("Line {}", i)).collect();
+        assert_eq!(lines, expected);
+
+        // Enough to wrap, but not enough to lose anything
+        write_lines(&mut buffer, 10);
+        fs::write(&path, &buffer).unwrap();
+        assert!(trace_ring::read(&buffer).unwrap().header.wraps > 0);
+        let lines = follower.poll(&path).unwrap();
+        let expected: Vec<String> = (17..27).map(|i| format!("Line {}", i)).collect();
+        assert_eq!(lines, expected);
+
+        // Far too much
+        write_lines(&mut buffer, 100);
+        fs::write(&path, &buffer).unwrap();
+        let lines = follower.poll(&path).unwrap();
+        assert!(lines[0].ends_with("bytes lost ---"));
+        assert_eq!(lines.last().unwrap(), "Line 126");
+
+        // Start again
+        for b in buffer.iter_mut() {
+            *b = 0;
+        }
+        write_lines(&mut buffer, 1);
+        fs::write(&path, &buffer).unwrap();
+        assert_eq!(
+            follower.poll(&path).unwrap(),
+            vec![RESTARTED.to_owned(), "Line 127".to_owned()]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn follow_plain_text() {
+        let mut follower = Follower::new();
+        assert_eq!(follower.update(b"One\nTw"), vec!["One"]);
+        assert_eq!(follower.update(b"One\nTwo\nThree\n"), vec!["Two", "Three"]);
+        assert!(follower.update(b"One\nTwo\nThree\n\0\0").is_empty());
+        assert_eq!(
+            follower.update(b"Four\n"),
+            vec![RESTARTED.to_owned(), "Four".to_owned()]
+        );
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,109 @@
+//! # remoteproc Tools
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Host-side code for picking apart remoteproc firmware images and
+//! following their trace buffers. The binaries in `src/bin` are thin
+//! wrappers around this library.
+
+// ****************************************************************************
+//
+// Crates
+//
+// ****************************************************************************
+
+#[cfg_attr(test, macro_use)]
+extern crate resource_table;
+extern crate trace_ring;
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+pub mod deferred;
+pub mod elf;
+pub mod fdt;
+pub mod follow;
+pub mod rsc;
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The section the kernel looks for the resource table in.
+pub const RESOURCE_TABLE_SECTION: &str = ".resource_table";
+
+/// The section our firmware puts its version string in.
+pub const VERSION_SECTION: &str = ".version";
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Decode the contents of a `.version` section. It holds text, perhaps
+/// padded with NULs.
+pub fn decode_version(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim_end().to_owned()
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
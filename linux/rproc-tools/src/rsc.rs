@@ -0,0 +1,542 @@
+//! # Resource Table Parser
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Decodes a remoteproc resource table, as found in the `.resource_table`
+//! section of a firmware ELF. The firmware builds its table with the
+//! `resource-table` crate, and we take the entry types and sizes from there
+//! too. We still decode the bytes one field at a time, rather than casting
+//! them to those structures, as a section in a file has no alignment
+//! guarantees.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use resource_table as rt;
+use std::fmt;
+use std::mem;
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+/// A decoded resource table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    /// The table format version. Only version 1 exists.
+    pub ver: u32,
+    /// The entries, in the order given in the offset array
+    pub entries: Vec<Entry>,
+}
+
+/// One entry from the resource table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Where the entry starts, relative to the start of the table
+    pub offset: usize,
+    /// What the entry contains
+    pub resource: Resource,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    /// Memory the host allocates for us
+    Carveout(Memory),
+    /// Memory-mapped peripherals (or other memory) the host maps for us
+    Devmem(Memory),
+    /// A buffer we write text into
+    Trace { da: u32, len: u32, name: String },
+    /// A VirtIO device
+    Vdev {
+        id: u32,
+        notifyid: u32,
+        dfeatures: u32,
+        gfeatures: u32,
+        status: u8,
+        vrings: Vec<Vring>,
+        config: Vec<u8>,
+    },
+    /// Internal memory the host can load sections into
+    Intmem {
+        version: u32,
+        da: u32,
+        pa: u32,
+        len: u32,
+        name: String,
+    },
+    /// Something for the platform driver to handle
+    Custom { sub_type: u32, data: Vec<u8> },
+    /// A vendor-specific entry. These don't say how long they are, so we
+    /// can't decode anything but the type.
+    Vendor(u32),
+    /// An entry type we don't know how to decode
+    Unknown(u32),
+}
+
+/// The body of a carveout or devmem entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Memory {
+    pub da: u32,
+    pub pa: u32,
+    pub len: u32,
+    pub flags: u32,
+    pub name: String,
+}
+
+/// One of the vrings following a vdev entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vring {
+    pub da: u32,
+    pub align: u32,
+    pub num: u32,
+    pub notifyid: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The table is too short for the header and offset array
+    TooShort,
+    /// The table is a version we don't understand
+    BadVersion(u32),
+    /// The entry with the given index has an offset outside the table
+    BadOffset(usize),
+    /// The entry with the given index runs off the end of the table
+    Truncated(usize),
+    /// The entry with the given index has a name which isn't NUL terminated
+    BadName(usize),
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+pub const RSC_CARVEOUT: u32 = rt::ResourceType::CARVEOUT.0;
+pub const RSC_DEVMEM: u32 = rt::ResourceType::DEVMEM.0;
+pub const RSC_TRACE: u32 = rt::ResourceType::TRACE.0;
+pub const RSC_VDEV: u32 = rt::ResourceType::VDEV.0;
+pub const RSC_INTMEM: u32 = rt::ResourceType::INTMEM.0;
+pub const RSC_CUSTOM: u32 = rt::ResourceType::CUSTOM.0;
+pub use resource_table::{RSC_VENDOR_END, RSC_VENDOR_START};
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+const HEADER_LEN: usize = mem::size_of::<rt::Header>();
+const NAME_LEN: usize = mem::size_of::<rt::String32>();
+/// A vdev entry, less the type word.
+const VDEV_LEN: usize = mem::size_of::<rt::Vdev>() - 4;
+const VRING_LEN: usize = mem::size_of::<rt::VdevVring>();
+const VIRTIO_ID_RPMSG: u32 = 7;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+/// Decode a resource table.
+pub fn parse(data: &[u8]) -> Result<Table, Error> {
+    let ver = read_u32(data, 0).ok_or(Error::TooShort)?;
+    if ver != 1 {
+        return Err(Error::BadVersion(ver));
+    }
+    let num = read_u32(data, 4).ok_or(Error::TooShort)? as usize;
+    let offsets_end = num
+        .checked_mul(4)
+        .and_then(|n| n.checked_add(HEADER_LEN))
+        .ok_or(Error::TooShort)?;
+    if offsets_end > data.len() {
+        return Err(Error::TooShort);
+    }
+    let mut entries = Vec::with_capacity(num);
+    for idx in 0..num {
+        let offset = read_u32(data, HEADER_LEN + 4 * idx).ok_or(Error::TooShort)? as usize;
+        if offset < offsets_end || offset >= data.len() {
+            return Err(Error::BadOffset(idx));
+        }
+        let resource = parse_entry(&data[offset..], idx)?;
+        entries.push(Entry { offset, resource });
+    }
+    Ok(Table { ver, entries })
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Resource table version {}, {} entries",
+            self.ver,
+            self.entries.len()
+        )?;
+        for (idx, entry) in self.entries.iter().enumerate() {
+            write!(f, "[{:2}] @0x{:04x} {}", idx, entry.offset, entry.resource)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Resource::Carveout(ref m) => writeln!(f, "CARVEOUT {}", m),
+            Resource::Devmem(ref m) => writeln!(f, "DEVMEM   {}", m),
+            Resource::Trace { da, len, ref name } => {
+                writeln!(f, "TRACE    {:24} da 0x{:08x} len 0x{:08x}", name, da, len)
+            }
+            Resource::Vdev {
+                id,
+                notifyid,
+                dfeatures,
+                gfeatures,
+                status,
+                ref vrings,
+                ref config,
+            } => {
+                writeln!(
+                    f,
+                    "VDEV     id {}{} notifyid {} dfeatures 0x{:x} gfeatures 0x{:x} status 0x{:02x} config {} bytes",
+                    id,
+                    if id == VIRTIO_ID_RPMSG { " (rpmsg)" } else { "" },
+                    notifyid,
+                    dfeatures,
+                    gfeatures,
+                    status,
+                    config.len()
+                )?;
+                for (idx, vring) in vrings.iter().enumerate() {
+                    writeln!(
+                        f,
+                        "                vring {}: da 0x{:08x} align {} num {} notifyid {}",
+                        idx, vring.da, vring.align, vring.num, vring.notifyid
+                    )?;
+                }
+                Ok(())
+            }
+            Resource::Intmem {
+                version,
+                da,
+                pa,
+                len,
+                ref name,
+            } => writeln!(
+                f,
+                "INTMEM   {:24} da 0x{:08x} pa 0x{:08x} len 0x{:08x} version {}",
+                name, da, pa, len, version
+            ),
+            Resource::Custom { sub_type, ref data } => {
+                write!(f, "CUSTOM   sub-type {}, {} bytes:", sub_type, data.len())?;
+                for b in data {
+                    write!(f, " {:02x}", b)?;
+                }
+                writeln!(f)
+            }
+            Resource::Vendor(rtype) => writeln!(f, "VENDOR   type {}", rtype),
+            Resource::Unknown(rtype) => writeln!(f, "unknown resource type {}", rtype),
+        }
+    }
+}
+
+impl fmt::Display for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:24} da 0x{:08x} pa 0x{:08x} len 0x{:08x} flags 0x{:x}",
+            self.name, self.da, self.pa, self.len, self.flags
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::TooShort => write!(f, "resource table header truncated"),
+            Error::BadVersion(v) => write!(f, "unsupported resource table version {}", v),
+            Error::BadOffset(idx) => write!(f, "entry {} has an offset outside the table", idx),
+            Error::Truncated(idx) => write!(f, "entry {} is truncated", idx),
+            Error::BadName(idx) => write!(f, "entry {} has an unterminated name", idx),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+/// Decode the entry at the start of `data`, which runs to the end of the
+/// table.
+fn parse_entry(data: &[u8], idx: usize) -> Result<Resource, Error> {
+    let word = |n: usize| read_u32(data, 4 + 4 * n).ok_or(Error::Truncated(idx));
+    let rtype = read_u32(data, 0).ok_or(Error::Truncated(idx))?;
+    match rtype {
+        RSC_CARVEOUT | RSC_DEVMEM => {
+            let memory = Memory {
+                da: word(0)?,
+                pa: word(1)?,
+                len: word(2)?,
+                flags: word(3)?,
+                name: read_name(data, 4 + 4 * 5, idx)?,
+            };
+            if rtype == RSC_CARVEOUT {
+                Ok(Resource::Carveout(memory))
+            } else {
+                Ok(Resource::Devmem(memory))
+            }
+        }
+        RSC_TRACE => Ok(Resource::Trace {
+            da: word(0)?,
+            len: word(1)?,
+            name: read_name(data, 4 + 4 * 3, idx)?,
+        }),
+        RSC_VDEV => {
+            let body = data.get(4..4 + VDEV_LEN).ok_or(Error::Truncated(idx))?;
+            let config_len = word(4)? as usize;
+            let num_of_vrings = body[21] as usize;
+            let mut vrings = Vec::with_capacity(num_of_vrings);
+            let mut offset = 4 + VDEV_LEN;
+            for _ in 0..num_of_vrings {
+                let field = |n: usize| read_u32(data, offset + 4 * n).ok_or(Error::Truncated(idx));
+                vrings.push(Vring {
+                    da: field(0)?,
+                    align: field(1)?,
+                    num: field(2)?,
+                    notifyid: field(3)?,
+                });
+                offset += VRING_LEN;
+            }
+            let config = data
+                .get(offset..offset + config_len)
+                .ok_or(Error::Truncated(idx))?;
+            Ok(Resource::Vdev {
+                id: word(0)?,
+                notifyid: word(1)?,
+                dfeatures: word(2)?,
+                gfeatures: word(3)?,
+                status: body[20],
+                vrings,
+                config: config.to_vec(),
+            })
+        }
+        RSC_INTMEM => Ok(Resource::Intmem {
+            version: word(0)?,
+            da: word(1)?,
+            pa: word(2)?,
+            len: word(3)?,
+            name: read_name(data, 4 + 4 * 5, idx)?,
+        }),
+        RSC_CUSTOM => {
+            let size = word(1)? as usize;
+            let contents = data.get(12..12 + size).ok_or(Error::Truncated(idx))?;
+            Ok(Resource::Custom {
+                sub_type: word(0)?,
+                data: contents.to_vec(),
+            })
+        }
+        x if (RSC_VENDOR_START..=RSC_VENDOR_END).contains(&x) => Ok(Resource::Vendor(x)),
+        x => Ok(Resource::Unknown(x)),
+    }
+}
+
+/// Read a 32 byte, NUL terminated, name.
+fn read_name(data: &[u8], offset: usize, idx: usize) -> Result<String, Error> {
+    let raw = data
+        .get(offset..offset + NAME_LEN)
+        .ok_or(Error::Truncated(idx))?;
+    let len = raw
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(Error::BadName(idx))?;
+    Ok(String::from_utf8_lossy(&raw[..len]).into_owned())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let b = data.get(offset..offset + 4)?;
+    Some(u32::from(b[0]) | u32::from(b[1]) << 8 | u32::from(b[2]) << 16 | u32::from(b[3]) << 24)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn push_u32(buf: &mut Vec<u8>, value: u32) {
+        for i in 0..4 {
+            buf.push((value >> (8 * i)) as u8);
+        }
+    }
+
+    fn push_name(buf: &mut Vec<u8>, name: &str) {
+        let mut raw = [0u8; NAME_LEN];
+        raw[..name.len()].copy_from_slice(name.as_bytes());
+        buf.extend_from_slice(&raw);
+    }
+
+    /// A table with a vdev (and two vrings), a trace buffer and a custom
+    /// entry, laid out as the firmware would on the Cortex-M4.
+    fn make_table() -> Vec<u8> {
+        let mut buf = Vec::new();
+        for &w in &[1, 3, 0, 0, 28, 28 + 68, 28 + 68 + 48] {
+            push_u32(&mut buf, w);
+        }
+        for &w in &[RSC_VDEV, VIRTIO_ID_RPMSG, 0, 1, 0, 0] {
+            push_u32(&mut buf, w);
+        }
+        buf.extend_from_slice(&[0, 2, 0, 0]);
+        for &w in &[0x6000_0000, 4096, 256, 1, 0, 0x6000_4000, 4096, 256, 2, 0] {
+            push_u32(&mut buf, w);
+        }
+        for &w in &[RSC_TRACE, 0x9F00_0000, 16384, 0] {
+            push_u32(&mut buf, w);
+        }
+        push_name(&mut buf, "trace:sysm3");
+        for &w in &[RSC_CUSTOM, 7, 4, 5000] {
+            push_u32(&mut buf, w);
+        }
+        buf
+    }
+
+    #[test]
+    fn parse_table() {
+        let table = parse(&make_table()).unwrap();
+        assert_eq!(table.entries.len(), 3);
+        assert_eq!(table.entries[1].offset, 96);
+        match table.entries[0].resource {
+            Resource::Vdev { ref vrings, .. } => {
+                assert_eq!(vrings.len(), 2);
+                assert_eq!(vrings[1].da, 0x6000_4000);
+                assert_eq!(vrings[1].notifyid, 2);
+            }
+            ref r => panic!("Expected vdev, got {:?}", r),
+        }
+        assert_eq!(
+            table.entries[1].resource,
+            Resource::Trace {
+                da: 0x9F00_0000,
+                len: 16384,
+                name: "trace:sysm3".into(),
+            }
+        );
+        assert_eq!(
+            table.entries[2].resource,
+            Resource::Custom {
+                sub_type: 7,
+                data: vec![0x88, 0x13, 0, 0],
+            }
+        );
+    }
+
+    resource_table! {
+        pub struct FirmwareTable;
+
+        pub static FIRMWARE_TABLE = {
+            vdev: rt::Vdev = rt::Vdev {
+                rtype: rt::ResourceType::VDEV,
+                id: VIRTIO_ID_RPMSG,
+                notifyid: 0,
+                dfeatures: 1,
+                gfeatures: 0,
+                config_len: 0,
+                status: 0,
+                num_of_vrings: 1,
+                reserved: [0, 0],
+            }
+                => vring0: rt::VdevVring = rt::VdevVring {
+                    da: 0x6000_0000,
+                    align: 4096,
+                    num: 256,
+                    notifyid: 1,
+                    reserved: 0,
+                };
+            carveout: rt::Carveout = rt::Carveout {
+                rtype: rt::ResourceType::CARVEOUT,
+                da: 0x8000_0000,
+                pa: 0,
+                len: rt::SZ_1M,
+                flags: 0,
+                reserved: 0,
+                name: rt::String32 { buffer: *b"IPU_MEM_DATA\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0" },
+            };
+        };
+    }
+
+    /// The firmware's own definitions, built for the host, give a table we
+    /// can read.
+    #[test]
+    fn firmware_layout() {
+        let bytes = unsafe {
+            ::std::slice::from_raw_parts(
+                &FIRMWARE_TABLE as *const FirmwareTable as *const u8,
+                mem::size_of::<FirmwareTable>(),
+            )
+        };
+        let table = parse(bytes).unwrap();
+        assert_eq!(table.entries.len(), 2);
+        assert_eq!(table.entries[1].offset, FIRMWARE_TABLE.offsets[1] as usize);
+        assert_eq!(
+            table.entries[1].resource,
+            Resource::Carveout(Memory {
+                da: 0x8000_0000,
+                pa: 0,
+                len: rt::SZ_1M,
+                flags: 0,
+                name: "IPU_MEM_DATA".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let table = make_table();
+        assert_eq!(parse(&table[..20]), Err(Error::TooShort));
+        assert_eq!(parse(&table[..table.len() - 1]), Err(Error::Truncated(2)));
+        let mut bad = table.clone();
+        bad[0] = 2;
+        assert_eq!(parse(&bad), Err(Error::BadVersion(2)));
+        let mut bad = table.clone();
+        bad[20] = 250;
+        assert_eq!(parse(&bad), Err(Error::BadOffset(1)));
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -13,8 +13,13 @@
 //
 // ****************************************************************************
 
+extern crate demo_messages;
+extern crate le_bytes;
 extern crate libc;
+extern crate rpmsg_fragment;
+extern crate rpmsg_rpc;
 extern crate socket2;
+extern crate ti_messageq;
 
 // ****************************************************************************
 //
@@ -22,7 +27,12 @@ extern crate socket2;
 //
 // ****************************************************************************
 
+use demo_messages::{Message, Service, TestRequest};
+use le_bytes::u32_bytes;
 use libc::{sa_family_t, socklen_t};
+use rpmsg_fragment::{Fragmenter, Reassembler};
+use std::io;
+use std::time::Duration;
 
 // ****************************************************************************
 //
@@ -30,7 +40,7 @@ use libc::{sa_family_t, socklen_t};
 //
 // ****************************************************************************
 
-// None
+mod messageq;
 
 // ****************************************************************************
 //
@@ -69,12 +79,9 @@ pub struct SockAddrRpMsg {
     pub addr: u32,
 }
 
-#[repr(C)]
-pub struct TestMessage {
-    pub test1: u32,
-    pub test2: u32,
-    pub test3: u32,
-}
+/// Carries RPC calls and replies over a connected rpmsg socket, tagging
+/// each call with `Service::Rpc`.
+struct SocketTransport(socket2::Socket);
 
 // ****************************************************************************
 //
@@ -85,9 +92,34 @@ pub struct TestMessage {
 const AF_RPMSG: i32 = 43;
 const CORE_ID: u32 = 0;
 const HOST_ID: u32 = 100;
-const REMOTE_ID: u32 = 61;
+/// TI's rpmsg socket driver connects every socket to the IPU's
+/// "rpmsg-proto" endpoint, so each message for the demo's services carries a
+/// `Service` tag.
+const REMOTE_ID: u32 = ti_messageq::MESSAGEQ_PORT;
 const CONNECT_TIMEOUT_SECONDS: u64 = 3;
 const READ_TIMEOUT_SECONDS: u64 = 2;
+/// An rpmsg buffer, less the 16 byte rpmsg header and the service tag
+const MAX_FRAGMENT_LEN: usize = 512 - 16 - demo_messages::SERVICE_TAG_LEN;
+const FRAGMENT_TEST_LEN: usize = 2000;
+const FRAGMENT_TEST_COUNT: usize = 100;
+const FRAGMENT_TIMEOUT_MS: u32 = 1000;
+const RPC_TIMEOUT_MS: u64 = 500;
+const RPC_TEST_BATCHES: u32 = 100;
+const RPC_IN_FLIGHT: u32 = 8;
+/// Our MultiProc ID, and the IPU's. These must match the MultiProc
+/// configuration in the IPU firmware.
+const HOST_PROC_ID: u16 = 0;
+const IPU_PROC_ID: u16 = 2;
+/// Our MessageQ index. TI's IPC stack allocates from the bottom, so we use a
+/// high number to avoid it.
+const MESSAGEQ_INDEX: u16 = 0x7F;
+const MESSAGEQ_NAME: &str = "IPU1_DEMO";
+const MESSAGEQ_TEST_COUNT: u16 = 100;
+const TEST_REQUEST: TestRequest = TestRequest {
+    test1: 0xAAAAAAAA,
+    test2: 0xBBBBBBBB,
+    test3: 0xCCCCCCCC,
+};
 
 // ****************************************************************************
 //
@@ -113,6 +145,9 @@ fn main() {
         }
     }
     println!("Final Total: Sent: {}, Recv: {}", sent_count, recv_count);
+    fragment_test();
+    rpc_test();
+    messageq_test();
     println!("Test complete.");
 }
 
@@ -123,43 +158,209 @@ fn main() {
 // ****************************************************************************
 
 fn sockets() -> (socket2::Socket, socket2::Socket) {
-    let tx = socket2::Socket::new(AF_RPMSG.into(), socket2::Type::seqpacket(), None)
+    let tx = connect(REMOTE_ID);
+    let rx = bind(HOST_ID);
+    (tx, rx)
+}
+
+/// Open a socket which receives anything the IPU sends to the given local
+/// address.
+fn bind(local_addr: u32) -> socket2::Socket {
+    let socket = socket2::Socket::new(AF_RPMSG.into(), socket2::Type::seqpacket(), None)
         .expect("socket() failed");
     let addr = SockAddrRpMsg {
         sa_family: AF_RPMSG as u16,
         vproc_id: CORE_ID,
-        addr: REMOTE_ID,
+        addr: local_addr,
     };
-    tx.connect_timeout(
-        &addr.into(),
-        ::std::time::Duration::new(CONNECT_TIMEOUT_SECONDS, 0),
-    )
-    .expect("connect_timeout() failed");
-    tx.set_nonblocking(false).expect("set_nonblocking() failed");
-
-    let rx = socket2::Socket::new(AF_RPMSG.into(), socket2::Type::seqpacket(), None)
+    socket.bind(&addr.into()).expect("bind() failed");
+    socket
+        .set_nonblocking(false)
+        .expect("set_nonblocking() failed");
+    socket
+        .set_read_timeout(Some(::std::time::Duration::new(READ_TIMEOUT_SECONDS, 0)))
+        .expect("read_timeout() failed");
+    socket
+}
+
+/// Open a socket connected to the given address on the IPU. Replies to
+/// messages sent on this socket come back on it.
+fn connect(remote_addr: u32) -> socket2::Socket {
+    let socket = socket2::Socket::new(AF_RPMSG.into(), socket2::Type::seqpacket(), None)
         .expect("socket() failed");
     let addr = SockAddrRpMsg {
         sa_family: AF_RPMSG as u16,
         vproc_id: CORE_ID,
-        addr: HOST_ID,
+        addr: remote_addr,
     };
-    rx.bind(&addr.into()).expect("bind() failed");
-    rx.set_nonblocking(false).expect("set_nonblocking() failed");
-    rx.set_read_timeout(Some(::std::time::Duration::new(READ_TIMEOUT_SECONDS, 0)))
+    socket
+        .connect_timeout(
+            &addr.into(),
+            ::std::time::Duration::new(CONNECT_TIMEOUT_SECONDS, 0),
+        )
+        .expect("connect_timeout() failed");
+    socket
+        .set_nonblocking(false)
+        .expect("set_nonblocking() failed");
+    socket
+}
+
+/// Send messages too large for a single rpmsg buffer to the IPU, which
+/// reassembles them and echoes them back.
+fn fragment_test() {
+    let mut socket = connect(REMOTE_ID);
+    socket
+        .set_read_timeout(Some(::std::time::Duration::new(READ_TIMEOUT_SECONDS, 0)))
         .expect("read_timeout() failed");
+    let mut storage = vec![0u8; 65536];
+    let mut reassembler = Reassembler::new(&mut storage, FRAGMENT_TIMEOUT_MS);
+    let start = ::std::time::Instant::now();
+    let mut good_count = 0;
+    for i in 0..FRAGMENT_TEST_COUNT {
+        let payload: Vec<u8> = (0..FRAGMENT_TEST_LEN).map(|x| (x + i) as u8).collect();
+        if !send_fragmented(&mut socket, i as u16, &payload) {
+            continue;
+        }
+        match recv_fragmented(&mut socket, &mut reassembler, &start) {
+            Some(ref echo) if *echo == payload => good_count += 1,
+            Some(_) => eprintln!("Echoed message {} did not match", i),
+            None => {}
+        }
+    }
+    println!(
+        "Fragmented: Sent: {}, Echoed: {}, {:?}",
+        FRAGMENT_TEST_COUNT,
+        good_count,
+        reassembler.stats()
+    );
+}
 
-    (tx, rx)
+/// Make remote procedure calls to the IPU, with several in flight at once.
+fn rpc_test() {
+    let mut client = rpmsg_rpc::Client::new(SocketTransport(connect(REMOTE_ID)));
+    let timeout = Duration::from_millis(RPC_TIMEOUT_MS);
+    match client.call(demo_messages::METHOD_VERSION, &[], timeout) {
+        Ok(version) => println!("IPU version: {}", String::from_utf8_lossy(&version)),
+        Err(e) => eprintln!("Version call failed: {:?}", e),
+    }
+    let mut good_count = 0;
+    for batch in 0..RPC_TEST_BATCHES {
+        let mut calls = Vec::new();
+        for i in 0..RPC_IN_FLIGHT {
+            let a = batch * 1000;
+            let b = i;
+            let mut request = Vec::new();
+            request.extend_from_slice(&u32_bytes(a));
+            request.extend_from_slice(&u32_bytes(b));
+            match client.start(demo_messages::METHOD_ADD, &request, timeout) {
+                Ok(id) => calls.push((id, a + b)),
+                Err(e) => eprintln!("Failed to start call: {:?}", e),
+            }
+        }
+        for (id, expected) in calls {
+            match client.wait(id) {
+                Ok(ref reply) if *reply == u32_bytes(expected) => good_count += 1,
+                Ok(reply) => eprintln!("Bad sum {:?}, expected {}", reply, expected),
+                Err(e) => eprintln!("Call failed: {:?}", e),
+            }
+        }
+    }
+    println!(
+        "RPC: Calls: {}, Good: {}, Unmatched: {}",
+        RPC_TEST_BATCHES * RPC_IN_FLIGHT,
+        good_count,
+        client.unmatched()
+    );
 }
 
-fn send(tx: &mut socket2::Socket) -> bool {
-    let msg = TestMessage {
-        test1: 0xAAAAAAAA,
-        test2: 0xBBBBBBBB,
-        test3: 0xCCCCCCCC,
+/// Exchange messages with the IPU's MessageQ, using the same framing as TI's
+/// IPC stack.
+fn messageq_test() {
+    let mut client = messageq::Client::new(HOST_PROC_ID, IPU_PROC_ID, MESSAGEQ_INDEX);
+    let queue = match client.open(MESSAGEQ_NAME) {
+        Ok(Some(queue)) => queue,
+        Ok(None) => {
+            eprintln!("IPU has no MessageQ called {:?}", MESSAGEQ_NAME);
+            return;
+        }
+        Err(e) => {
+            eprintln!("MessageQ open failed: {:?}", e);
+            return;
+        }
     };
-    let slice = msg.as_bytes();
-    match tx.send(&slice) {
+    let mut good_count = 0;
+    for msg_id in 0..MESSAGEQ_TEST_COUNT {
+        let body = format!("MessageQ message {}", msg_id);
+        if let Err(e) = client.put(queue, msg_id, body.as_bytes()) {
+            eprintln!("MessageQ put failed: {:?}", e);
+            continue;
+        }
+        match client.get() {
+            Ok((header, reply)) => {
+                if (header.msg_id == msg_id) && (reply == body.as_bytes()) {
+                    good_count += 1;
+                } else {
+                    eprintln!("Bad MessageQ reply {:?}", header);
+                }
+            }
+            Err(e) => eprintln!("MessageQ get failed: {:?}", e),
+        }
+    }
+    println!(
+        "MessageQ: Local: 0x{:08x}, Remote: 0x{:08x}, Sent: {}, Echoed: {}",
+        client.queue_id().0,
+        queue.0,
+        MESSAGEQ_TEST_COUNT,
+        good_count
+    );
+}
+
+fn send_fragmented(tx: &mut socket2::Socket, message_id: u16, payload: &[u8]) -> bool {
+    let mut fragmenter =
+        Fragmenter::new(message_id, payload, MAX_FRAGMENT_LEN).expect("Message too large");
+    let mut buffer = [0u8; MAX_FRAGMENT_LEN];
+    while let Some(res) = fragmenter.next_fragment(&mut buffer) {
+        let len = res.expect("Fragment buffer too small");
+        if let Err(e) = send_tagged(tx, Service::Fragment, &buffer[0..len]) {
+            eprintln!("Got send error: {:?}", e);
+            return false;
+        }
+    }
+    true
+}
+
+fn recv_fragmented(
+    rx: &mut socket2::Socket,
+    reassembler: &mut Reassembler,
+    start: &::std::time::Instant,
+) -> Option<Vec<u8>> {
+    let mut buffer = [0u8; 512];
+    loop {
+        let len = match rx.recv(&mut buffer) {
+            Ok(len) => len,
+            Err(e) => {
+                eprintln!("Error reading: {:?}", e);
+                return None;
+            }
+        };
+        let elapsed = start.elapsed();
+        let now = (elapsed.as_secs() * 1000) as u32 + elapsed.subsec_millis();
+        match reassembler.push(&buffer[0..len], now) {
+            Ok(Some(message)) => return Some(message.to_vec()),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Error reassembling: {:?}", e);
+                return None;
+            }
+        }
+    }
+}
+
+fn send(tx: &mut socket2::Socket) -> bool {
+    let msg = Message::TestRequest(TEST_REQUEST);
+    let mut buffer = [0u8; demo_messages::MAX_MESSAGE_LEN];
+    let len = msg.encode(&mut buffer).expect("Failed to encode");
+    match send_tagged(tx, Service::Demo, &buffer[0..len]) {
         Ok(_) => true,
         Err(e) => {
             eprintln!("Got send error: {:?}", e);
@@ -168,6 +369,14 @@ fn send(tx: &mut socket2::Socket) -> bool {
     }
 }
 
+/// Send one message for the given service on a socket connected to the
+/// IPU.
+fn send_tagged(tx: &mut socket2::Socket, service: Service, payload: &[u8]) -> io::Result<()> {
+    let mut message = service.tag().to_vec();
+    message.extend_from_slice(payload);
+    tx.send(&message).map(|_| ())
+}
+
 fn recv(rx: &mut socket2::Socket) -> bool {
     let mut buffer = [0u8; 512];
     loop {
@@ -181,17 +390,45 @@ fn recv(rx: &mut socket2::Socket) -> bool {
                 }
             }
             Ok((len, _addr)) => {
-                let valid = &buffer[0..len];
-                let _message = std::str::from_utf8(valid).expect("Invalid UTF-8 received");
-                return len == 64;
+                return match Message::decode(&buffer[0..len]) {
+                    Ok(Message::TestResponse(rsp)) => rsp.request == TEST_REQUEST,
+                    Ok(m) => {
+                        eprintln!("Unexpected message: {:?}", m);
+                        false
+                    }
+                    Err(e) => {
+                        eprintln!("Bad message: {}", e);
+                        false
+                    }
+                };
             }
         }
     }
 }
 
-impl<'a> TestMessage {
-    fn as_bytes(self) -> [u8; 12] {
-        unsafe { std::mem::transmute::<TestMessage, [u8; 12]>(self) }
+impl rpmsg_rpc::Transport for SocketTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        send_tagged(&mut self.0, Service::Rpc, message)
+    }
+
+    fn recv(&mut self, buffer: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        // A zero timeout would mean wait forever
+        let timeout = timeout.max(Duration::from_millis(1));
+        self.0.set_read_timeout(Some(timeout))?;
+        match self.0.recv(buffer) {
+            Ok(len) => Ok(Some(len)),
+            Err(ref e)
+                if (e.kind() == io::ErrorKind::WouldBlock)
+                    || (e.kind() == io::ErrorKind::TimedOut) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn max_message_len(&self) -> usize {
+        rpmsg_rpc::MAX_MESSAGE_LEN - demo_messages::SERVICE_TAG_LEN
     }
 }
 
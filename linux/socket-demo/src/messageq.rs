@@ -0,0 +1,177 @@
+//! # MessageQ Client
+//!
+//! Copyright (c) 2018, Cambridge Consultants Ltd.
+//! See the top-level README.md for licence details.
+//!
+//! Talks to MessageQs on the IPU using the same framing as TI's IPC 3.x
+//! stack, but without needing the LAD daemon. We own one queue, which is
+//! bound to an rpmsg port as IPC 3.x would, so replies sent to it come back
+//! to us.
+//!
+//! NameServer requests go to the IPU's MessageQ port, like everything else,
+//! and the responses come back to `NAME_SERVER_ADDR`. We bind that address
+//! ourselves, so LAD must not be running.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use std::io;
+use ti_messageq::{self, MsgHeader, NameServerMsg, QueueId};
+
+// ****************************************************************************
+//
+// Sub-modules
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Macros
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Types / Traits
+//
+// ****************************************************************************
+
+pub struct Client {
+    proc_id: u16,
+    remote_proc_id: u16,
+    queue: QueueId,
+    /// Bound to our queue's port
+    rx: ::socket2::Socket,
+    /// Connected to the remote MessageQ port, for messages and NameServer
+    /// requests
+    tx: ::socket2::Socket,
+    /// Bound to the address NameServer responses are sent to
+    name_server: ::socket2::Socket,
+    seq_num: u16,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types / Traits
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// An rpmsg buffer, less the rpmsg header
+const MAX_MESSAGE_LEN: usize = 512 - 16;
+
+// ****************************************************************************
+//
+// Public Functions
+//
+// ****************************************************************************
+
+impl Client {
+    /// Create our queue. `proc_id` is our MultiProc ID and `remote_proc_id`
+    /// is the IPU's.
+    pub fn new(proc_id: u16, remote_proc_id: u16, index: u16) -> Client {
+        let port = ti_messageq::PORT_OFFSET + index;
+        Client {
+            proc_id,
+            remote_proc_id,
+            queue: QueueId::new(proc_id, port),
+            rx: super::bind(port as u32),
+            tx: super::connect(ti_messageq::MESSAGEQ_PORT),
+            name_server: super::bind(ti_messageq::NAME_SERVER_ADDR),
+            seq_num: 0,
+        }
+    }
+
+    /// Our queue.
+    pub fn queue_id(&self) -> QueueId {
+        self.queue
+    }
+
+    /// Find a queue on the remote processor by name, as per
+    /// `MessageQ_open`.
+    pub fn open(&mut self, name: &str) -> io::Result<Option<QueueId>> {
+        let request = NameServerMsg::request(ti_messageq::MESSAGEQ_INSTANCE, name);
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        let len = request.encode(&mut buffer).map_err(invalid)?;
+        self.tx.send(&buffer[0..len])?;
+        loop {
+            let len = self.name_server.recv(&mut buffer)?;
+            let response = NameServerMsg::decode(&buffer[0..len]).map_err(invalid)?;
+            // Ignore stale responses to earlier requests
+            if response.name == name {
+                return Ok(response.found().map(QueueId));
+            }
+        }
+    }
+
+    /// Send a message to a remote queue, with our queue as the reply queue,
+    /// as per `MessageQ_put`.
+    pub fn put(&mut self, dst: QueueId, msg_id: u16, body: &[u8]) -> io::Result<()> {
+        let len = ti_messageq::HEADER_LEN + body.len();
+        if (len > MAX_MESSAGE_LEN) || (dst.proc_id() != self.remote_proc_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message too long or bad queue",
+            ));
+        }
+        let mut header = MsgHeader::new(msg_id, self.proc_id, dst, body.len());
+        header.set_reply_queue(self.queue);
+        header.seq_num = self.seq_num;
+        self.seq_num = self.seq_num.wrapping_add(1);
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        header.encode(&mut buffer).map_err(invalid)?;
+        buffer[ti_messageq::HEADER_LEN..len].copy_from_slice(body);
+        self.tx.send(&buffer[0..len])?;
+        Ok(())
+    }
+
+    /// Wait for a message on our queue, as per `MessageQ_get`.
+    pub fn get(&mut self) -> io::Result<(MsgHeader, Vec<u8>)> {
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        let len = self.rx.recv(&mut buffer)?;
+        let header = MsgHeader::decode(&buffer[0..len]).map_err(invalid)?;
+        let end = ti_messageq::HEADER_LEN + header.body_len();
+        if end > len {
+            return Err(invalid(ti_messageq::Error::TooShort));
+        }
+        Ok((header, buffer[ti_messageq::HEADER_LEN..end].to_vec()))
+    }
+}
+
+// ****************************************************************************
+//
+// Private Functions
+//
+// ****************************************************************************
+
+fn invalid(e: ti_messageq::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************